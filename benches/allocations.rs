@@ -0,0 +1,86 @@
+//! Counts heap allocations of reads and replay, run with `cargo bench --bench allocations`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use kvs_project::{KvStore, Result};
+
+const KEYS: u64 = 10_000;
+
+/// The system allocator, counting every allocation and reallocation.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Runs `f` and prints its allocations and time per key.
+fn measure<T>(name: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let result = f()?;
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{:<10} {:>6.2} allocations/key {:>8.2?}/key",
+        name,
+        allocations as f64 / KEYS as f64,
+        elapsed / KEYS as u32
+    );
+    Ok(result)
+}
+
+fn main() -> Result<()> {
+    let mut store = KvStore::open_temporary()?;
+    for i in 0..KEYS {
+        store.set_v2(format!("key{}", i), format!("value{}", i).repeat(10))?;
+    }
+    let path = store.path().to_owned();
+
+    measure("get", || {
+        for i in 0..KEYS {
+            store.get_v2(format!("key{}", i))?;
+        }
+        Ok(())
+    })?;
+    measure("get_bytes", || {
+        for i in 0..KEYS {
+            store.get_bytes(format!("key{}", i))?;
+        }
+        Ok(())
+    })?;
+    measure("scan", || store.scan(..).try_for_each(|pair| pair.map(drop)))?;
+
+    // the temporary directory goes away with the store, replay a copy of it.
+    let copy = std::env::temp_dir().join(format!("kvs-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&copy)?;
+    store.flush()?;
+    for entry in std::fs::read_dir(&path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name() != "LOCK" {
+            std::fs::copy(entry.path(), copy.join(entry.file_name()))?;
+        }
+    }
+    drop(store);
+    measure("replay", || KvStore::open(&copy, None, None))?;
+    std::fs::remove_dir_all(&copy)?;
+    Ok(())
+}
@@ -0,0 +1,241 @@
+//! Compares kvs configurations and sled on writes, reads, scans, open and compaction, run
+//! with `cargo bench --bench engines`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs_project::{KvStore, NeverCompact, Options};
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+const VALUE_SIZE: usize = 100;
+const BUFFER_SIZES: [usize; 3] = [4 * 1024, 8 * 1024, 64 * 1024];
+
+/// When a benchmarked engine makes writes durable.
+#[derive(Clone, Copy, Debug)]
+enum SyncPolicy {
+    /// Writes reach the OS, nothing is fsynced.
+    Never,
+    /// Every write is fsynced before the next one.
+    EveryWrite,
+}
+
+/// An engine and the settings it is opened with.
+#[derive(Clone, Copy, Debug)]
+enum Engine {
+    Kvs { buffer_size: usize, sync: SyncPolicy },
+    Sled { sync: SyncPolicy },
+}
+
+impl Engine {
+    /// The engines every workload runs against.
+    fn all() -> Vec<Engine> {
+        let mut engines: Vec<Engine> = BUFFER_SIZES
+            .iter()
+            .map(|&buffer_size| Engine::Kvs { buffer_size, sync: SyncPolicy::Never })
+            .collect();
+        engines.push(Engine::Kvs { buffer_size: 8 * 1024, sync: SyncPolicy::EveryWrite });
+        engines.push(Engine::Sled { sync: SyncPolicy::Never });
+        engines.push(Engine::Sled { sync: SyncPolicy::EveryWrite });
+        engines
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Engine::Kvs { buffer_size, sync } => format!("kvs/{}k/{:?}", buffer_size / 1024, sync),
+            Engine::Sled { sync } => format!("sled/{:?}", sync),
+        }
+    }
+
+    fn open(&self, path: &Path) -> Handle {
+        match *self {
+            Engine::Kvs { buffer_size, sync } => {
+                let options = Options::new()
+                    .reader_buffer_size(buffer_size)
+                    .writer_buffer_size(buffer_size)
+                    // compaction has its own benchmark, keep it out of the write numbers.
+                    .compaction_policy(Arc::new(NeverCompact));
+                Handle::Kvs(Box::new(KvStore::open_with(path, options).unwrap()), sync)
+            }
+            Engine::Sled { sync } => Handle::Sled(sled::open(path).unwrap(), sync),
+        }
+    }
+}
+
+/// An open engine.
+enum Handle {
+    Kvs(Box<KvStore>, SyncPolicy),
+    Sled(sled::Db, SyncPolicy),
+}
+
+impl Handle {
+    fn set(&mut self, key: String, value: String) {
+        match self {
+            Handle::Kvs(store, sync) => {
+                store.set_v2(key, value).unwrap();
+                if let SyncPolicy::EveryWrite = sync {
+                    store.sync().unwrap();
+                }
+            }
+            Handle::Sled(db, sync) => {
+                db.insert(key, value.into_bytes()).unwrap();
+                if let SyncPolicy::EveryWrite = sync {
+                    db.flush().unwrap();
+                }
+            }
+        }
+    }
+
+    fn get(&mut self, key: String) -> Option<String> {
+        match self {
+            Handle::Kvs(store, _) => store.get_v2(key).unwrap(),
+            Handle::Sled(db, _) => db
+                .get(key)
+                .unwrap()
+                .map(|value| String::from_utf8(value.to_vec()).unwrap()),
+        }
+    }
+
+    /// Reads every pair in key order, returns how many there were.
+    fn scan(&mut self) -> usize {
+        match self {
+            Handle::Kvs(store, _) => store.scan(..).fold(0, |count, pair| {
+                pair.unwrap();
+                count + 1
+            }),
+            Handle::Sled(db, _) => db.iter().fold(0, |count, pair| {
+                pair.unwrap();
+                count + 1
+            }),
+        }
+    }
+
+    /// Pushes everything written so far to disk, so a reopen sees it.
+    fn flush(&mut self) {
+        match self {
+            Handle::Kvs(store, _) => store.sync().unwrap(),
+            Handle::Sled(db, _) => {
+                db.flush().unwrap();
+            }
+        }
+    }
+}
+
+fn key(i: usize) -> String {
+    format!("key{:08}", i)
+}
+
+fn value(i: usize) -> String {
+    format!("{:0>width$}", i, width = VALUE_SIZE)
+}
+
+/// `0..KEYS` in a fixed pseudo-random order, the same on every run.
+fn shuffled() -> Vec<usize> {
+    let mut order: Vec<usize> = (0..KEYS).collect();
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    for i in (1..order.len()).rev() {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        order.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+    order
+}
+
+/// Opens `engine` in a new directory holding `0..KEYS`.
+fn filled(engine: &Engine) -> (TempDir, Handle) {
+    let dir = TempDir::new().unwrap();
+    let mut handle = engine.open(dir.path());
+    for i in 0..KEYS {
+        handle.set(key(i), value(i));
+    }
+    handle.flush();
+    (dir, handle)
+}
+
+fn writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for (order_name, order) in [("sequential", (0..KEYS).collect()), ("random", shuffled())] {
+        for engine in Engine::all() {
+            group.bench_with_input(BenchmarkId::new(order_name, engine.name()), &order, |b, order| {
+                b.iter_batched(
+                    || {
+                        let dir = TempDir::new().unwrap();
+                        let handle = engine.open(dir.path());
+                        (dir, handle)
+                    },
+                    // hand the store back, so removing its directory is not timed.
+                    |(dir, mut handle)| {
+                        for &i in order {
+                            handle.set(key(i), value(i));
+                        }
+                        (dir, handle)
+                    },
+                    BatchSize::PerIteration,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+fn reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let order = shuffled();
+    for engine in Engine::all() {
+        let (_dir, mut handle) = filled(&engine);
+        group.bench_function(BenchmarkId::new("point", engine.name()), |b| {
+            b.iter(|| {
+                for &i in &order {
+                    assert!(handle.get(key(i)).is_some());
+                }
+            });
+        });
+        group.bench_function(BenchmarkId::new("scan", engine.name()), |b| {
+            b.iter(|| assert_eq!(handle.scan(), KEYS));
+        });
+    }
+    group.finish();
+}
+
+fn open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    for engine in Engine::all() {
+        let (dir, handle) = filled(&engine);
+        drop(handle);
+        group.bench_function(engine.name(), |b| b.iter(|| engine.open(dir.path())));
+    }
+    group.finish();
+}
+
+fn compaction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact");
+    for buffer_size in BUFFER_SIZES {
+        let engine = Engine::Kvs { buffer_size, sync: SyncPolicy::Never };
+        group.bench_function(engine.name(), |b| {
+            b.iter_batched(
+                || {
+                    // every key written twice, so half the log is stale.
+                    let (dir, mut handle) = filled(&engine);
+                    for i in 0..KEYS {
+                        handle.set(key(i), value(i + 1));
+                    }
+                    let Handle::Kvs(store, _) = handle else { unreachable!() };
+                    (dir, store)
+                },
+                |(dir, mut store)| {
+                    store.compact().unwrap();
+                    (dir, store)
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, writes, reads, open, compaction);
+criterion_main!(benches);
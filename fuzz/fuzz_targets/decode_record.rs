@@ -0,0 +1,27 @@
+//! Opens a store whose only log is the fuzz input, run with `cargo fuzz run decode_record`.
+
+#![no_main]
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use kvs_project::{KvStore, MemoryStorage, Options, Storage};
+use libfuzzer_sys::fuzz_target;
+
+// Open fails with an error or indexes records that all read back, it never panics.
+fuzz_target!(|data: &[u8]| {
+    let storage = MemoryStorage::new();
+    storage.create_dir_all(Path::new("/fuzz")).unwrap();
+    storage.open_writer(Path::new("/fuzz/1.log")).unwrap().write_all(data).unwrap();
+
+    let Ok(mut store) = KvStore::open_with("/fuzz", Options::new().storage(Arc::new(storage))) else {
+        return;
+    };
+    let keys: Vec<String> = store.keys(..).collect();
+    for key in keys {
+        let _ = store.get_v2(key.clone());
+        let _ = store.get_bytes(key);
+    }
+    let _ = store.scan(..).count();
+});
@@ -0,0 +1,64 @@
+//! Replays a valid store with its logs corrupted by the fuzz input, run with
+//! `cargo fuzz run replay`.
+
+#![no_main]
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use kvs_project::{KvStore, MemoryStorage, Options, Storage};
+use libfuzzer_sys::fuzz_target;
+
+/// Files of a store with plain, removed, renamed and compacted records, built once.
+fn base_files() -> &'static [(PathBuf, Vec<u8>)] {
+    static FILES: OnceLock<Vec<(PathBuf, Vec<u8>)>> = OnceLock::new();
+    FILES.get_or_init(|| {
+        let storage = MemoryStorage::new();
+        let options = Options::new().storage(Arc::new(storage.clone()));
+        let mut store = KvStore::open_with("/fuzz", options).unwrap();
+        for i in 0..20 {
+            store.set_v2(format!("key{}", i % 8), format!("value{}", i)).unwrap();
+        }
+        store.compact().unwrap();
+        store.remove_v2("key1".to_owned()).unwrap();
+        store.rename("key2".to_owned(), "key9".to_owned()).unwrap();
+        drop(store);
+
+        let mut files = Vec::new();
+        for path in storage.list_files(Path::new("/fuzz")).unwrap() {
+            let mut contents = Vec::new();
+            storage.open_reader(&path).unwrap().read_to_end(&mut contents).unwrap();
+            files.push((path, contents));
+        }
+        files
+    })
+}
+
+// The input is a list of 4 byte edits: a file, a little endian offset into it and a byte to
+// XOR there. An offset past the end truncates the file to the offset instead.
+fuzz_target!(|data: &[u8]| {
+    let mut files = base_files().to_vec();
+    for edit in data.chunks_exact(4) {
+        let (_, contents) = &mut files[edit[0] as usize % files.len()];
+        let offset = u16::from_le_bytes([edit[1], edit[2]]) as usize;
+        match contents.get_mut(offset) {
+            Some(byte) => *byte ^= edit[3],
+            None => contents.truncate(offset % (contents.len() + 1)),
+        }
+    }
+
+    let storage = MemoryStorage::new();
+    storage.create_dir_all(Path::new("/fuzz")).unwrap();
+    for (path, contents) in &files {
+        storage.open_writer(path).unwrap().write_all(contents).unwrap();
+    }
+    let Ok(mut store) = KvStore::open_with("/fuzz", Options::new().storage(Arc::new(storage))) else {
+        return;
+    };
+    let keys: Vec<String> = store.keys(..).collect();
+    for key in keys {
+        let _ = store.get_v2(key);
+    }
+    let _ = store.scan(..).count();
+});
@@ -0,0 +1,40 @@
+/// A group of writes applied with a single log flush, see `KvStore::apply_batch`.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Queues setting the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    /// Queues removing a key.
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Returns the number of queued writes.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether no writes are queued.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,213 @@
+use clap::{App, Arg, ArgMatches};
+use kvs_project::{KvStore, Result};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Quantiles printed for each operation.
+const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+/// The workload, read from the command line.
+struct Workload {
+    keys: u64,
+    value_size: usize,
+    operations: u64,
+    read_ratio: f64,
+    threads: u64,
+}
+
+/// A phase of the benchmark.
+#[derive(Clone, Copy)]
+enum Phase {
+    /// Sets every key once, in order.
+    Load,
+    /// Runs the operations on random keys, gets in the read ratio.
+    Mixed,
+}
+
+/// Durations of the operations one thread ran.
+#[derive(Default)]
+struct Timings {
+    gets: Vec<Duration>,
+    sets: Vec<Duration>,
+}
+
+fn main() {
+    let matches = App::new("kvs-bench")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Generates load on a store and reports throughput and latency percentiles")
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("PATH")
+                .help("Directory of the store, created if missing [default: a temporary directory]")
+                .takes_value(true),
+        )
+        .arg(number_arg("keys", "Number of distinct keys", "10000"))
+        .arg(number_arg("value-size", "Size of each value in bytes", "100"))
+        .arg(number_arg("operations", "Operations to run after loading the keys", "100000"))
+        .arg(number_arg("threads", "Threads sharing the store", "1"))
+        .arg(
+            Arg::with_name("read-ratio")
+                .long("read-ratio")
+                .value_name("RATIO")
+                .help("Fraction of operations that are gets, the rest are sets")
+                .default_value("0.9")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn number_arg<'a>(name: &'a str, help: &'a str, default: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name("N")
+        .help(help)
+        .default_value(default)
+        .takes_value(true)
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let workload = Workload {
+        keys: parse(matches, "keys"),
+        value_size: parse(matches, "value-size"),
+        operations: parse(matches, "operations"),
+        read_ratio: parse(matches, "read-ratio"),
+        threads: parse(matches, "threads"),
+    };
+    if workload.keys == 0 || workload.threads == 0 || !(0.0..=1.0).contains(&workload.read_ratio) {
+        eprintln!("--keys and --threads must be positive, --read-ratio between 0 and 1");
+        exit(1);
+    }
+
+    let store = match matches.value_of("data-dir") {
+        Some(dir) => KvStore::open(PathBuf::from(dir), None, None)?,
+        None => KvStore::open_temporary()?,
+    };
+    let store = Mutex::new(store);
+
+    let started = Instant::now();
+    let loaded = run_threads(&store, &workload, Phase::Load)?;
+    report("load", &loaded, started.elapsed());
+
+    let started = Instant::now();
+    let mixed = run_threads(&store, &workload, Phase::Mixed)?;
+    report("mixed", &mixed, started.elapsed());
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> T {
+    match matches.value_of(name).unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("--{} must be a number", name);
+            exit(1);
+        }
+    }
+}
+
+/// Splits the operations of `phase` over the threads and collects their timings.
+fn run_threads(store: &Mutex<KvStore>, workload: &Workload, phase: Phase) -> Result<Timings> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workload.threads)
+            .map(|thread| scope.spawn(move || run_thread(store, workload, thread, phase)))
+            .collect();
+
+        let mut timings = Timings::default();
+        for handle in handles {
+            let thread_timings = handle.join().expect("benchmark thread panicked")?;
+            timings.gets.extend(thread_timings.gets);
+            timings.sets.extend(thread_timings.sets);
+        }
+        Ok(timings)
+    })
+}
+
+fn run_thread(
+    store: &Mutex<KvStore>,
+    workload: &Workload,
+    thread: u64,
+    phase: Phase,
+) -> Result<Timings> {
+    let value = "v".repeat(workload.value_size);
+    let mut timings = Timings::default();
+    let mut random = 0x9e37_79b9_7f4a_7c15 ^ (thread + 1);
+    let operations = match phase {
+        Phase::Load => workload.keys,
+        Phase::Mixed => workload.operations,
+    };
+
+    // thread `t` runs operations t, t + threads, t + 2 * threads and so on.
+    for op in (thread..operations).step_by(workload.threads as usize) {
+        random = xorshift(random);
+        let (key, read) = match phase {
+            Phase::Load => (op, false),
+            Phase::Mixed => {
+                let draw = (xorshift(random) % 1_000_000) as f64 / 1_000_000.0;
+                (random % workload.keys, draw < workload.read_ratio)
+            }
+        };
+        let key = format!("key{:010}", key);
+
+        // waiting for the lock counts, as it would for a client of a shared store.
+        let started = Instant::now();
+        let mut store = store.lock().unwrap();
+        if read {
+            store.get_v2(key)?;
+            drop(store);
+            timings.gets.push(started.elapsed());
+        } else {
+            store.set_v2(key, value.clone())?;
+            drop(store);
+            timings.sets.push(started.elapsed());
+        }
+    }
+    Ok(timings)
+}
+
+fn xorshift(mut state: u64) -> u64 {
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    state
+}
+
+/// Prints the throughput of a phase and the latency percentiles of each operation.
+fn report(phase: &str, timings: &Timings, elapsed: Duration) {
+    let total = timings.gets.len() + timings.sets.len();
+    println!(
+        "{}: {} operations in {:.2?}, {:.0} ops/s",
+        phase,
+        total,
+        elapsed,
+        total as f64 / elapsed.as_secs_f64()
+    );
+    for (name, durations) in [("get", &timings.gets), ("set", &timings.sets)] {
+        if durations.is_empty() {
+            continue;
+        }
+        let mut sorted = durations.clone();
+        sorted.sort_unstable();
+        let percentiles: Vec<String> = QUANTILES
+            .iter()
+            .map(|q| {
+                let rank = ((q * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+                format!("p{} {:.2?}", q * 100.0, sorted[rank - 1])
+            })
+            .collect();
+        println!(
+            "  {} x{}: {}, max {:.2?}",
+            name,
+            sorted.len(),
+            percentiles.join(", "),
+            sorted[sorted.len() - 1]
+        );
+    }
+}
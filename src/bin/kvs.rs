@@ -1,9 +1,74 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use std::env::current_dir;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env::{self, current_dir};
+use std::io::{self, BufRead};
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use kvs_project::{KvStore, KvsError, Result};
+use kvs_project::{
+    KvStore, KvsError, LocalStorage, Options, Result, SizeThreshold, Storage, WriteBatch,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Arc;
 
-fn main() -> Result<()> {
+/// Exit code for errors without a more specific code below, including invalid arguments.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code of `rm` when the key does not exist.
+const EXIT_KEY_NOT_FOUND: i32 = 2;
+/// Exit code when the log files are corrupted.
+const EXIT_CORRUPTED: i32 = 3;
+/// Exit code when another process has the store open.
+const EXIT_STORE_LOCKED: i32 = 4;
+
+const CONFIG_HELP: &str = "CONFIG FILE:
+    --config reads a TOML file, flags override its values:
+
+    data_dir = \"store\"            # relative to the file, like --data-dir
+    reader_buffer_size = 65536
+    writer_buffer_size = 65536
+
+    [compaction]
+    threshold = 1048576            # stale bytes that trigger compaction
+    rate_limit = 10485760          # bytes per second, unlimited if unset
+    on_open = 0.5                  # compact on open above this garbage ratio";
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    Success, including `get` of a missing key
+    1    Invalid arguments or any other error
+    2    `rm` of a missing key
+    3    Corrupted log files
+    4    Store locked by another process";
+
+/// Settings read from `--config`, every one optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    data_dir: Option<PathBuf>,
+    reader_buffer_size: Option<usize>,
+    writer_buffer_size: Option<usize>,
+    compaction: CompactionConfig,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CompactionConfig {
+    threshold: Option<u64>,
+    rate_limit: Option<u64>,
+    on_open: Option<f64>,
+}
+
+/// How `get`, `scan`, `stats` and `doctor` print their results.
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Text,
+    Json,
+}
+
+fn main() {
+    let after_help = format!("{}\n\n{}", CONFIG_HELP, EXIT_CODES_HELP);
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -11,6 +76,34 @@ fn main() -> Result<()> {
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
+        .after_help(after_help.as_str())
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FORMAT")
+                .help("Output format of get, scan, stats and doctor [default: text]")
+                .possible_values(&["text", "json"])
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("PATH")
+                .help("TOML file with store settings, see CONFIG FILE below")
+                .env("KVS_CONFIG")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("PATH")
+                .help("Directory of the store, created if missing [default: current directory]")
+                .env("KVS_DATA_DIR")
+                .global(true)
+                .takes_value(true),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the value of a string key to a string")
@@ -31,40 +124,721 @@ fn main() -> Result<()> {
                 .about("Remove a given key")
                 .arg(Arg::with_name("KEY").help("A string key").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .alias("list")
+                .about("List keys in order, optionally only those starting with a prefix")
+                .arg(Arg::with_name("PREFIX").help("Only list keys starting with this prefix"))
+                .arg(
+                    Arg::with_name("start-after")
+                        .long("start-after")
+                        .value_name("KEY")
+                        .help("Only list keys after this one, for pagination")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("List at most N keys")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("values")
+                        .long("values")
+                        .help("Print the value next to each key, separated by a tab"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print key, byte and segment counts of the store")
+                .arg(Arg::with_name("json").long("json").help("Same as `--output json`"))
+                .arg(
+                    Arg::with_name("segments")
+                        .long("segments")
+                        .help("Print the size, live data and records of each log file instead"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("Rewrite the live values into a new log and delete stale logs"),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Apply newline-delimited `set KEY VALUE` / `rm KEY` commands from stdin")
+                .after_help(
+                    "Blank lines and lines starting with `#` are ignored. Commands are applied in \
+                     batches with one flush each. The first invalid line stops the batch, \
+                     commands from earlier batches stay applied.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check the store and its directory and print what needs attention")
+                .after_help(
+                    "Opens the store like the other commands, which moves leftovers of \
+                     interrupted operations to `quarantine/`. Exits with 1 if a check failed.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Open the store once and run commands interactively"),
+        )
         .get_matches();
 
+    if let Err(e) = run(&matches) {
+        eprintln!("error: {}", e);
+        exit(exit_code(&e));
+    }
+}
+
+/// Maps an error to the documented exit code for it.
+fn exit_code(e: &KvsError) -> i32 {
+    match e {
+        KvsError::KeyNotFound => EXIT_KEY_NOT_FOUND,
+        KvsError::CorruptedData
+        | KvsError::Deserialize(_)
+        | KvsError::UnexpectedCommandType
+        | KvsError::InconsistentLog(_) => EXIT_CORRUPTED,
+        KvsError::StoreLocked => EXIT_STORE_LOCKED,
+        _ => EXIT_FAILURE,
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let config = config(matches)?;
+    let data_dir = data_dir(matches, &config)?;
+    let options = options(&config);
+    let output = match global_value(matches, "output") {
+        Some("json") => Output::Json,
+        _ => Output::Text,
+    };
+
     match matches.subcommand() {
         ("set", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
 
-            let mut store = KvStore::open(current_dir()?, None, None)?;
+            let mut store = KvStore::open_with(&data_dir, options)?;
             store.set_v2(key.to_string(), value.to_string())?;
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let mut store = KvStore::open(current_dir()?, None, None)?;
-            if let Some(value) = store.get_v2(key.to_string())? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+            let mut store = KvStore::open_with(&data_dir, options)?;
+            let value = store.get_v2(key.to_string())?;
+            match (output, value) {
+                (Output::Json, value) => {
+                    println!("{}", serde_json::json!({ "key": key, "value": value }))
+                }
+                (Output::Text, Some(value)) => println!("{}", value),
+                (Output::Text, None) => println!("Key not found"),
             }
         }
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let mut store = KvStore::open(current_dir()?, None, None)?;
+            let mut store = KvStore::open_with(&data_dir, options)?;
             match store.remove_v2(key.to_string()) {
                 Ok(()) => {}
                 Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
-                    exit(1);
+                    exit(EXIT_KEY_NOT_FOUND);
                 }
                 Err(e) => return Err(e),
             }
         }
+        ("scan", Some(matches)) => {
+            let prefix = matches.value_of("PREFIX").unwrap_or("");
+            let start_after = matches.value_of("start-after");
+            let limit = match matches.value_of("limit").map(str::parse::<usize>) {
+                Some(Ok(limit)) => Some(limit),
+                Some(Err(_)) => {
+                    eprintln!("--limit must be a non-negative integer");
+                    exit(EXIT_FAILURE);
+                }
+                None => None,
+            };
+
+            let mut store = KvStore::open_with(&data_dir, options)?;
+            let values = matches.is_present("values");
+            print_scan(&mut store, prefix, start_after, limit, values, output)?;
+        }
+        ("stats", Some(matches)) => {
+            let output = if matches.is_present("json") { Output::Json } else { output };
+            let store = KvStore::open_with(&data_dir, options)?;
+            if matches.is_present("segments") {
+                print_segment_stats(&store, output)?;
+            } else {
+                print_stats(&store, output)?;
+            }
+        }
+        ("compact", Some(_)) => {
+            let mut store = KvStore::open_with(&data_dir, options)?;
+            let before = store.stats()?.disk_bytes;
+            store.compact()?;
+            let after = store.stats()?.disk_bytes;
+
+            println!("reclaimed {} bytes", before.saturating_sub(after));
+            for segment in store.segment_stats()? {
+                println!("generation {}: {} bytes", segment.generation, segment.disk_bytes);
+            }
+        }
+        ("batch", Some(_)) => {
+            let mut store = KvStore::open_with(&data_dir, options)?;
+            batch(&mut store, io::stdin().lock())?;
+        }
+        ("doctor", Some(_)) => {
+            let findings = doctor(&data_dir, options);
+            print_findings(&findings, output)?;
+            if findings.iter().any(|finding| finding.level == Level::Error) {
+                exit(EXIT_FAILURE);
+            }
+        }
+        ("repl", Some(_)) => repl(&data_dir, options)?,
         _ => unreachable!(),
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Prints the keys starting with `prefix` that sort after `start_after`, at most `limit` of them.
+///
+/// JSON output is a single array of `{"key": ..}` objects, with a `"value"` field if `values`.
+fn print_scan(
+    store: &mut KvStore,
+    prefix: &str,
+    start_after: Option<&str>,
+    limit: Option<usize>,
+    values: bool,
+    output: Output,
+) -> Result<()> {
+    let start = match start_after {
+        Some(after) if after >= prefix => Bound::Excluded(after.to_owned()),
+        _ => Bound::Included(prefix.to_owned()),
+    };
+    // the index alone tells which keys are on the page.
+    let keys: Vec<String> = store
+        .keys((start, Bound::Unbounded))
+        .take_while(|key| key.starts_with(prefix))
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+
+    let entries: Vec<(String, Option<String>)> = match (keys.first(), keys.last()) {
+        (Some(first), Some(last)) if values => store
+            .scan(first.clone()..=last.clone())
+            .map(|entry| entry.map(|(key, value)| (key, Some(value))))
+            .collect::<Result<_>>()?,
+        _ => keys.into_iter().map(|key| (key, None)).collect(),
+    };
+
+    if output == Output::Json {
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => serde_json::json!({ "key": key, "value": value }),
+                None => serde_json::json!({ "key": key }),
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(entries));
+        return Ok(());
+    }
+    for (key, value) in entries {
+        match value {
+            Some(value) => println!("{}\t{}", key, value),
+            None => println!("{}", key),
+        }
+    }
+    Ok(())
+}
+
+/// Prints the store stats, one per line or as a JSON object.
+fn print_stats(store: &KvStore, output: Output) -> Result<()> {
+    let stats = store.stats()?;
+    if output == Output::Json {
+        println!("{}", serde_json::to_string(&stats)?);
+    } else {
+        println!("id:               {}", stats.id);
+        println!("keys:             {}", stats.keys);
+        println!("live bytes:       {}", stats.live_bytes);
+        println!("disk bytes:       {}", stats.disk_bytes);
+        println!("garbage ratio:    {:.2}", stats.garbage_ratio);
+        println!("segments:         {}", stats.segments);
+        println!("highest sequence: {}", stats.highest_sequence);
+        let latencies = &stats.latencies;
+        for (name, histogram) in [
+            ("get", &latencies.get),
+            ("set", &latencies.set),
+            ("remove", &latencies.remove),
+            ("compaction", &latencies.compaction),
+        ] {
+            println!(
+                "{:<18}{} ops, p50 {:?}, p99 {:?}, max {:?}",
+                format!("{} latency:", name),
+                histogram.count(),
+                histogram.quantile(0.5),
+                histogram.quantile(0.99),
+                histogram.max()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints the stats of each log file, as a table or a JSON array.
+fn print_segment_stats(store: &KvStore, output: Output) -> Result<()> {
+    let segments = store.segment_stats()?;
+    if output == Output::Json {
+        println!("{}", serde_json::to_string(&segments)?);
+        return Ok(());
+    }
+    println!(
+        "{:>10} {:>12} {:>12} {:>12} {:>10} {:>10}",
+        "generation", "disk bytes", "live bytes", "garbage", "records", "live keys"
+    );
+    for segment in segments {
+        println!(
+            "{:>10} {:>12} {:>12} {:>12} {:>10} {:>10}",
+            segment.generation,
+            segment.disk_bytes,
+            segment.live_bytes,
+            segment.garbage_bytes,
+            segment.records,
+            segment.live_keys
+        );
+    }
+    Ok(())
+}
+
+/// How bad a `kvs doctor` finding is.
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Level {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// The outcome of one `kvs doctor` check.
+#[derive(Serialize)]
+struct Finding {
+    level: Level,
+    check: &'static str,
+    message: String,
+}
+
+impl Finding {
+    fn new(level: Level, check: &'static str, message: impl Into<String>) -> Finding {
+        Finding {
+            level,
+            check,
+            message: message.into(),
+        }
+    }
+}
+
+/// Stale fraction of the logs above which `kvs doctor` suggests compacting.
+const DOCTOR_GARBAGE_RATIO: f64 = 0.5;
+
+/// Free space on the store's filesystem below which `kvs doctor` warns, writes and
+/// compaction fail once the disk is full.
+const DOCTOR_MIN_FREE_BYTES: u64 = 1 << 30;
+
+/// Runs the `kvs doctor` checks in order, stopping at the first one the later ones depend on.
+///
+/// The store is opened with `KvStore::open_dry_run`, so the checks only report what an open
+/// would quarantine and leave the directory as it is.
+fn doctor(data_dir: &Path, options: Options) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    if !data_dir.is_dir() {
+        findings.push(Finding::new(
+            Level::Warning,
+            "directory",
+            format!(
+                "{} does not exist, the first command creates it",
+                data_dir.display()
+            ),
+        ));
+        return findings;
+    }
+    let probe = data_dir.join("DOCTOR");
+    match fs::write(&probe, b"").and_then(|()| fs::remove_file(&probe)) {
+        Ok(()) => findings.push(Finding::new(Level::Ok, "directory", "writable")),
+        Err(e) => {
+            findings.push(Finding::new(
+                Level::Error,
+                "directory",
+                format!(
+                    "cannot write to {}: {}, fix its permissions",
+                    data_dir.display(),
+                    e
+                ),
+            ));
+            return findings;
+        }
+    }
+
+    match fs2::available_space(data_dir) {
+        Ok(free) if free < DOCTOR_MIN_FREE_BYTES => findings.push(Finding::new(
+            Level::Warning,
+            "disk",
+            format!(
+                "{} bytes free, writes and compaction fail once the disk is full",
+                free
+            ),
+        )),
+        Ok(free) => findings.push(Finding::new(
+            Level::Ok,
+            "disk",
+            format!("{} bytes free", free),
+        )),
+        Err(e) => findings.push(Finding::new(
+            Level::Warning,
+            "disk",
+            format!("cannot read the free space: {}", e),
+        )),
+    }
+
+    match LocalStorage.lock_dir(data_dir) {
+        Ok(_lock) => findings.push(Finding::new(Level::Ok, "lock", "not locked")),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            findings.push(Finding::new(
+                Level::Error,
+                "lock",
+                "locked by another process, stop it to use the store from the command line",
+            ));
+            return findings;
+        }
+        Err(e) => {
+            findings.push(Finding::new(
+                Level::Error,
+                "lock",
+                format!("cannot take the lock: {}", e),
+            ));
+            return findings;
+        }
+    }
+
+    let store = match KvStore::open_dry_run(data_dir, options) {
+        Ok(store) => store,
+        Err(e) => {
+            let advice = match &e {
+                KvsError::IncompatibleFormat(_) => "open it with the kvs version that wrote it",
+                KvsError::CorruptedData
+                | KvsError::Deserialize(_)
+                | KvsError::UnexpectedCommandType => {
+                    "back up the directory, then open it with `Options::skip_corrupted`"
+                }
+                KvsError::InconsistentLog(_) => {
+                    "the logs were mixed up or lost writes, restore a backup"
+                }
+                _ => "see the error for the cause",
+            };
+            findings.push(Finding::new(
+                Level::Error,
+                "open",
+                format!("{}, {}", e, advice),
+            ));
+            return findings;
+        }
+    };
+    findings.push(Finding::new(Level::Ok, "open", "logs replayed"));
+
+    let report = store.recovery_report();
+    if report.gaps.is_empty() && report.duplicates.is_empty() {
+        findings.push(Finding::new(
+            Level::Ok,
+            "sequences",
+            "no gaps or duplicates",
+        ));
+    } else {
+        findings.push(Finding::new(
+            Level::Warning,
+            "sequences",
+            format!(
+                "{} gaps and {} duplicate sequence numbers, writes may be lost or logs mixed up",
+                report.gaps.len(),
+                report.duplicates.len()
+            ),
+        ));
+    }
+    for orphan in &report.orphans {
+        findings.push(Finding::new(
+            Level::Warning,
+            "orphans",
+            format!(
+                "{} left by an interrupted operation, opening moves it to quarantine/",
+                orphan.display()
+            ),
+        ));
+    }
+
+    for record in &report.corrupted {
+        findings.push(Finding::new(
+            Level::Warning,
+            "corruption",
+            format!(
+                "{} corrupted bytes at {} of generation {}, opening copies them to {}",
+                record.len,
+                record.offset,
+                record.generation,
+                record.path.display()
+            ),
+        ));
+    }
+
+    let (stats, segments) = match store
+        .stats()
+        .and_then(|stats| Ok((stats, store.segment_stats()?)))
+    {
+        Ok(stats) => stats,
+        Err(e) => {
+            findings.push(Finding::new(
+                Level::Error,
+                "segments",
+                format!("cannot read the logs: {}", e),
+            ));
+            return findings;
+        }
+    };
+    let empty = segments
+        .iter()
+        .filter(|segment| segment.disk_bytes > 0 && segment.live_bytes == 0)
+        .count();
+    if stats.garbage_ratio > DOCTOR_GARBAGE_RATIO || empty > 0 {
+        findings.push(Finding::new(
+            Level::Warning,
+            "segments",
+            format!(
+                "{:.0}% of {} bytes stale, {} of {} segments without live data, run `kvs compact`",
+                stats.garbage_ratio * 100.0,
+                stats.disk_bytes,
+                empty,
+                segments.len()
+            ),
+        ));
+    } else {
+        findings.push(Finding::new(
+            Level::Ok,
+            "segments",
+            format!(
+                "{} segments, {:.0}% stale",
+                segments.len(),
+                stats.garbage_ratio * 100.0
+            ),
+        ));
+    }
+    findings
+}
+
+/// Prints the `kvs doctor` findings, one per line or as a JSON array.
+fn print_findings(findings: &[Finding], output: Output) -> Result<()> {
+    if output == Output::Json {
+        println!("{}", serde_json::to_string(findings)?);
+        return Ok(());
+    }
+    for finding in findings {
+        let level = match finding.level {
+            Level::Ok => "ok",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        };
+        println!("{:<8}{:<11}{}", level, finding.check, finding.message);
+    }
+    Ok(())
+}
+
+/// Number of stdin commands applied with a single flush by `kvs batch`.
+const BATCH_SIZE: usize = 1024;
+
+/// Applies `set`/`rm` commands read line by line, exiting at the first invalid line.
+fn batch(store: &mut KvStore, input: impl BufRead) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    for (line_number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim_start();
+        match (command, args.split_whitespace().count()) {
+            ("set", n) if n >= 2 => {
+                let (key, value) = args.split_once(char::is_whitespace).unwrap();
+                batch.set(key.to_string(), value.trim_start().to_string());
+            }
+            ("rm", 1) => {
+                batch.remove(args.to_string());
+            }
+            _ => {
+                eprintln!("line {}: invalid command `{}`", line_number + 1, line);
+                exit(EXIT_FAILURE);
+            }
+        }
+
+        if batch.len() >= BATCH_SIZE {
+            store.apply_batch(mem::take(&mut batch))?;
+        }
+    }
+    store.apply_batch(batch)
+}
+
+const REPL_HELP: &str = "commands:
+  set <KEY> <VALUE>   set the value of a key, the value is the rest of the line
+  get <KEY>           print the value of a key
+  rm <KEY>            remove a key
+  scan [PREFIX]       print the keys and values starting with a prefix
+  stats               print key, byte and segment counts
+  help                print this help
+  exit                leave the repl";
+
+/// Runs commands read from the terminal against one open store, with line editing and
+/// history kept in `~/.kvs_history`.
+fn repl(data_dir: &Path, options: Options) -> Result<()> {
+    let mut store = KvStore::open_with(data_dir, options)?;
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"));
+    if let Some(history) = &history {
+        // a missing history file just means a fresh history.
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim_start();
+        let result = match (command, args.split_whitespace().count()) {
+            ("set", n) if n >= 2 => {
+                let (key, value) = args.split_once(char::is_whitespace).unwrap();
+                store.set_v2(key.to_string(), value.trim_start().to_string())
+            }
+            ("get", 1) => store.get_v2(args.to_string()).map(|value| match value {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            }),
+            ("rm", 1) => match store.remove_v2(args.to_string()) {
+                Err(KvsError::KeyNotFound) => {
+                    println!("Key not found");
+                    Ok(())
+                }
+                result => result,
+            },
+            ("scan", 0) | ("scan", 1) => print_scan(&mut store, args, None, None, true, Output::Text),
+            ("stats", 0) => print_stats(&store, Output::Text),
+            ("help", 0) => {
+                println!("{}", REPL_HELP);
+                Ok(())
+            }
+            ("exit", 0) | ("quit", 0) => break,
+            _ => {
+                println!("invalid command, try `help`");
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            println!("error: {}", e);
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+fn readline_error(e: ReadlineError) -> KvsError {
+    match e {
+        ReadlineError::Io(e) => KvsError::IoError(e),
+        e => KvsError::IoError(io::Error::other(e.to_string())),
+    }
+}
+
+/// Reads the `--config`/`KVS_CONFIG` file, empty if none is given.
+///
+/// Exits with an error if the file is not valid. A relative `data_dir` in it is resolved
+/// against the directory of the file.
+fn config(matches: &ArgMatches) -> Result<Config> {
+    let Some(path) = global_value(matches, "config").map(Path::new) else {
+        return Ok(Config::default());
+    };
+    let mut config: Config = match toml::from_str(&fs::read_to_string(path)?) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid config file {}: {}", path.display(), e);
+            exit(EXIT_FAILURE);
+        }
+    };
+    if config.compaction.rate_limit == Some(0) {
+        eprintln!("invalid config file {}: compaction.rate_limit must be positive", path.display());
+        exit(EXIT_FAILURE);
+    }
+    if config.compaction.on_open.is_some_and(|ratio| !(0.0..=1.0).contains(&ratio)) {
+        eprintln!("invalid config file {}: compaction.on_open must be between 0 and 1", path.display());
+        exit(EXIT_FAILURE);
+    }
+    if let (Some(dir), Some(base)) = (&config.data_dir, path.parent()) {
+        config.data_dir = Some(base.join(dir));
+    }
+    Ok(config)
+}
+
+/// Returns the store options set in the config file.
+fn options(config: &Config) -> Options {
+    let mut options = Options::new();
+    if let Some(size) = config.reader_buffer_size {
+        options = options.reader_buffer_size(size);
+    }
+    if let Some(size) = config.writer_buffer_size {
+        options = options.writer_buffer_size(size);
+    }
+    if let Some(threshold) = config.compaction.threshold {
+        options = options.compaction_policy(Arc::new(SizeThreshold(threshold)));
+    }
+    if let Some(rate_limit) = config.compaction.rate_limit {
+        options = options.compaction_rate_limit(rate_limit);
+    }
+    if let Some(ratio) = config.compaction.on_open {
+        options = options.compact_on_open(ratio);
+    }
+    options
+}
+
+/// Resolves the store directory from `--data-dir`/`KVS_DATA_DIR`, then the config file,
+/// defaulting to the current one.
+///
+/// Exits with an error if the path exists but is not a directory.
+fn data_dir(matches: &ArgMatches, config: &Config) -> Result<PathBuf> {
+    let dir = match (global_value(matches, "data-dir"), &config.data_dir) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(dir)) => dir.clone(),
+        (None, None) => return Ok(current_dir()?),
+    };
+
+    if dir.exists() && !dir.is_dir() {
+        eprintln!("{} is not a directory", dir.display());
+        exit(EXIT_FAILURE);
+    }
+    Ok(dir)
+}
+
+/// Returns the value of a global arg, given before or after the subcommand.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    // global args given after the subcommand only show up in the subcommand's matches.
+    matches
+        .subcommand()
+        .1
+        .and_then(|sub| sub.value_of(name))
+        .or_else(|| matches.value_of(name))
+}
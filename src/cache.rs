@@ -0,0 +1,64 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Recency of every live key for cache mode, see `Options::cache_budget`.
+#[derive(Debug)]
+pub(crate) struct Lru {
+    budget: u64,
+    live_bytes: u64,
+    clock: u64,
+    // last access tick and record length of every key.
+    entries: HashMap<String, (u64, u64)>,
+    // keys by last access tick, oldest first.
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    pub(crate) fn new(budget: u64) -> Lru {
+        Lru {
+            budget,
+            live_bytes: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    pub(crate) fn set_budget(&mut self, budget: u64) {
+        self.budget = budget;
+    }
+
+    /// Records a new value of `len` bytes for `key`, as its most recent access.
+    pub(crate) fn insert(&mut self, key: &str, len: u64) {
+        self.remove(key);
+        self.clock += 1;
+        self.live_bytes += len;
+        self.entries.insert(key.to_owned(), (self.clock, len));
+        self.order.insert(self.clock, key.to_owned());
+    }
+
+    /// Marks `key` as the most recently accessed.
+    pub(crate) fn touch(&mut self, key: &str) {
+        if let Some(&(_, len)) = self.entries.get(key) {
+            self.insert(key, len);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some((tick, len)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.live_bytes -= len;
+        }
+    }
+
+    /// Returns the least recently used key while the live data is over budget.
+    pub(crate) fn victim(&self) -> Option<&String> {
+        if self.live_bytes <= self.budget {
+            return None;
+        }
+        self.order.values().next()
+    }
+}
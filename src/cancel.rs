@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{KvsError, Result};
+
+/// Stops a long operation once cancelled or past its deadline, see `Scan::cancel_on` and
+/// `KvStore::bulk_ingest_with`.
+///
+/// Clones share the cancellation, so a server can keep one clone per request and cancel it
+/// when the client goes away. Operations check the token between records and return
+/// `KvsError::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// Creates a token that only stops operations once cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Creates a token that also stops operations once `deadline` passed.
+    pub fn with_deadline(deadline: Instant) -> CancellationToken {
+        CancellationToken {
+            cancelled: Arc::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Creates a token that also stops operations `timeout` from now.
+    pub fn with_timeout(timeout: Duration) -> CancellationToken {
+        CancellationToken::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Stops the operations using this token or a clone of it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether `cancel` was called or the deadline passed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Returns `KvsError::Cancelled` once the token was cancelled or its deadline passed.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(KvsError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{KvsError, Result};
+
+/// Stale bytes the default policy lets pile up before compacting.
+const DEFAULT_THRESHOLD: u64 = 1024 * 1024;
+
+/// The figures a `CompactionPolicy` decides on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionState {
+    /// Bytes of log records overwritten or removed since the last compaction.
+    pub stale_bytes: u64,
+    /// Bytes of all log files on disk.
+    pub disk_bytes: u64,
+}
+
+/// Decides when a store compacts after a write, see `Options::compaction_policy`.
+///
+/// Compaction always rewrites every live record, a policy only picks the moment.
+/// `KvStore::compact` compacts whatever the policy says, and `Options::max_disk_bytes`
+/// compacts when a write would not fit otherwise.
+pub trait CompactionPolicy: Debug + Send + Sync {
+    /// Returns whether to compact now, called after every write.
+    fn should_compact(&self, state: &CompactionState) -> bool;
+}
+
+/// Compacts once more than the given number of stale bytes piled up.
+///
+/// The default policy, with a threshold of 1 MiB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeThreshold(pub u64);
+
+impl Default for SizeThreshold {
+    fn default() -> SizeThreshold {
+        SizeThreshold(DEFAULT_THRESHOLD)
+    }
+}
+
+impl CompactionPolicy for SizeThreshold {
+    fn should_compact(&self, state: &CompactionState) -> bool {
+        state.stale_bytes > self.0
+    }
+}
+
+/// Compacts once stale bytes make up more than `ratio` of the disk bytes.
+///
+/// `min_stale_bytes` keeps small stores from compacting on every other write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GarbageRatio {
+    /// Fraction of the disk bytes, between 0 and 1.
+    pub ratio: f64,
+    /// Stale bytes below which it never compacts.
+    pub min_stale_bytes: u64,
+}
+
+impl CompactionPolicy for GarbageRatio {
+    fn should_compact(&self, state: &CompactionState) -> bool {
+        state.stale_bytes >= self.min_stale_bytes
+            && state.stale_bytes as f64 > self.ratio * state.disk_bytes as f64
+    }
+}
+
+/// Defers to another policy during a window of the day and never compacts outside it.
+///
+/// The window runs from `start_hour` up to `end_hour` in UTC and wraps past midnight if
+/// `start_hour` is the later one, so `TimeWindow::new(22, 4, ..)` covers the night.
+#[derive(Clone, Debug)]
+pub struct TimeWindow {
+    start_hour: u32,
+    end_hour: u32,
+    policy: Arc<dyn CompactionPolicy>,
+}
+
+impl TimeWindow {
+    /// Creates a window from `start_hour` to `end_hour`, both between 0 and 24.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an hour is above 24.
+    pub fn new(start_hour: u32, end_hour: u32, policy: Arc<dyn CompactionPolicy>) -> TimeWindow {
+        assert!(start_hour <= 24 && end_hour <= 24, "hours of the day go up to 24");
+        TimeWindow { start_hour, end_hour, policy }
+    }
+
+    fn contains(&self, hour: u32) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+impl CompactionPolicy for TimeWindow {
+    fn should_compact(&self, state: &CompactionState) -> bool {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.contains((secs / 3600 % 24) as u32) && self.policy.should_compact(state)
+    }
+}
+
+/// Never compacts after writes, leaving it to `KvStore::compact`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NeverCompact;
+
+impl CompactionPolicy for NeverCompact {
+    fn should_compact(&self, _state: &CompactionState) -> bool {
+        false
+    }
+}
+
+/// Compacts after the next write once `request` was called, and never otherwise.
+///
+/// Lets tests compact at an exact point of a write sequence, through the same path as a
+/// compaction the default policy triggers. Clones share the request, so keep one and pass
+/// another to `Options::compaction_policy`.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionTrigger(Arc<AtomicBool>);
+
+impl CompactionTrigger {
+    /// Creates a trigger with no request pending.
+    pub fn new() -> CompactionTrigger {
+        CompactionTrigger::default()
+    }
+
+    /// Makes the next write compact.
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl CompactionPolicy for CompactionTrigger {
+    fn should_compact(&self, _state: &CompactionState) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// A handle reporting how far a `KvStore::compact_with` has copied the live records, and
+/// cancelling it.
+///
+/// Clones share the same state, so one clone can be passed to `compact_with` while another
+/// is polled or cancelled from a different thread. Use a new handle for each compaction.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionHandle {
+    inner: Arc<CompactionProgress>,
+}
+
+#[derive(Debug, Default)]
+struct CompactionProgress {
+    bytes_copied: AtomicU64,
+    total_bytes: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl CompactionHandle {
+    /// Creates a new compaction handle.
+    pub fn new() -> CompactionHandle {
+        CompactionHandle::default()
+    }
+
+    /// Returns the number of record bytes copied so far.
+    pub fn bytes_copied(&self) -> u64 {
+        self.inner.bytes_copied.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of record bytes to copy, known once the compaction started.
+    pub fn total_bytes(&self) -> u64 {
+        self.inner.total_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Asks the compaction using this handle to stop.
+    ///
+    /// It returns `KvsError::Cancelled` and leaves every log in place. The records it
+    /// copied so far stay in use, the next compaction picks up from scratch.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether `cancel` was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_total_bytes(&self, total: u64) {
+        self.inner.total_bytes.store(total, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes(&self, bytes: u64) {
+        self.inner.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns `KvsError::Cancelled` once the handle was cancelled.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(KvsError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+/// Maps keys to the form they are stored in, which sets the key order of the store.
+///
+/// Keys are stored and sorted as their encoded strings, so an encoding lets composite or
+/// numeric keys sort by meaning in range scans, for example by zero-padding numbers. Every
+/// method taking or returning keys goes through the encoding, including listeners.
+///
+/// The name is recorded in the store metadata when the store is created, and the store
+/// can only be reopened with an encoding of the same name.
+pub trait KeyEncoding: Debug + Send + Sync {
+    /// Returns the name recorded in the store metadata.
+    fn name(&self) -> &str;
+
+    /// Encodes a key, distinct keys must have distinct encodings.
+    fn encode(&self, key: &str) -> String;
+
+    /// Turns an encoded key back into the key.
+    fn decode(&self, encoded: &str) -> String;
+}
@@ -0,0 +1,139 @@
+use std::mem;
+
+use crate::{KvStore, Result};
+
+/// A view into a single key of a store, see `KvStore::entry`.
+///
+/// Each method writes at most one log record, so `and_modify` followed by `or_insert`
+/// costs a single write whichever way the key turns out.
+pub enum Entry<'a> {
+    /// The key holds a value.
+    Occupied(OccupiedEntry<'a>),
+    /// The key does not exist.
+    Vacant(VacantEntry<'a>),
+}
+
+/// An entry for a key that holds a value.
+pub struct OccupiedEntry<'a> {
+    pub(crate) store: &'a mut KvStore,
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+/// An entry for a key that does not exist.
+pub struct VacantEntry<'a> {
+    pub(crate) store: &'a mut KvStore,
+    pub(crate) key: String,
+}
+
+impl<'a> Entry<'a> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns the value of the key, first setting it to `default` if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors during writing the log.
+    pub fn or_insert(self, default: String) -> Result<String> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value of the key, first setting it to `default()` if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors during writing the log.
+    pub fn or_insert_with(self, default: impl FnOnce() -> String) -> Result<String> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.value),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Updates the value in place if the key exists.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors during writing the log.
+    pub fn and_modify(self, modify: impl FnOnce(&mut String)) -> Result<Entry<'a>> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.value.clone();
+                modify(&mut value);
+                entry.insert(value)?;
+                Ok(Entry::Occupied(entry))
+            }
+            Entry::Vacant(entry) => Ok(Entry::Vacant(entry)),
+        }
+    }
+
+    /// Removes the key, returning its value if it existed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors during writing the log.
+    pub fn remove(self) -> Result<Option<String>> {
+        match self {
+            Entry::Occupied(entry) => entry.remove().map(Some),
+            Entry::Vacant(_) => Ok(None),
+        }
+    }
+}
+
+impl OccupiedEntry<'_> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the value of the key.
+    pub fn get(&self) -> &str {
+        &self.value
+    }
+
+    /// Sets the value of the key, returning the previous one.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors during writing the log.
+    pub fn insert(&mut self, value: String) -> Result<String> {
+        if value == self.value {
+            return Ok(value);
+        }
+        self.store.set_v2(self.key.clone(), value.clone())?;
+        Ok(mem::replace(&mut self.value, value))
+    }
+
+    /// Removes the key, returning its value.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors during writing the log.
+    pub fn remove(self) -> Result<String> {
+        self.store.remove_v2(self.key)?;
+        Ok(self.value)
+    }
+}
+
+impl VacantEntry<'_> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Sets the value of the key, returning it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors during writing the log.
+    pub fn insert(self, value: String) -> Result<String> {
+        self.store.set_v2(self.key, value.clone())?;
+        Ok(value)
+    }
+}
@@ -1,4 +1,6 @@
-use std::io;
+use std::{error, fmt, io};
+
+use crate::RecoveryReport;
 
 #[derive(Debug)]
 
@@ -21,6 +23,88 @@ pub enum KvsError {
 
     /// Corrupted data
     CorruptedData,
+
+    /// An operation was cancelled through its `Progress`, `CompactionHandle` or
+    /// `CancellationToken`
+    Cancelled,
+
+    /// The store directory is locked by another open store
+    StoreLocked,
+
+    /// The write would grow the logs past `Options::max_disk_bytes`
+    QuotaExceeded,
+
+    /// The disk filled up during a write, which was rolled back
+    DiskFull,
+
+    /// Replay found sequence number anomalies with `Options::strict_recovery` set
+    InconsistentLog(RecoveryReport),
+
+    /// The store id differs from `Options::expected_id`, holds the id found
+    StoreMismatch(String),
+
+    /// The store was created with another `Options::key_encoding`, holds its name if any
+    KeyEncodingMismatch(Option<String>),
+
+    /// No secondary index of the given name was given in `Options`
+    UnknownIndex(String),
+
+    /// A bulk ingest got a key not above the one before it, holds the key
+    UnsortedInput(String),
+
+    /// The store to restore into already holds keys
+    StoreNotEmpty,
+
+    /// The store directory is in an on-disk format this version can't read, holds what was found
+    IncompatibleFormat(String),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::IoError(e) => write!(f, "IO error: {}", e),
+            KvsError::Serde(e) => write!(f, "serialization error: {}", e),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::UnexpectedCommandType => write!(f, "unexpected command type"),
+            KvsError::Deserialize(e) => write!(f, "cannot decode log record: {}", e),
+            KvsError::CorruptedData => write!(f, "corrupted data"),
+            KvsError::Cancelled => write!(f, "operation cancelled"),
+            KvsError::StoreLocked => write!(f, "store is locked by another process"),
+            KvsError::QuotaExceeded => write!(f, "store size quota exceeded"),
+            KvsError::DiskFull => write!(f, "disk full, the write was rolled back"),
+            KvsError::InconsistentLog(report) => write!(
+                f,
+                "inconsistent log: {} sequence gaps, {} duplicate sequences",
+                report.gaps.len(),
+                report.duplicates.len()
+            ),
+            KvsError::StoreMismatch(id) => write!(f, "unexpected store id {}", id),
+            KvsError::KeyEncodingMismatch(Some(name)) => {
+                write!(f, "store was created with key encoding {}", name)
+            }
+            KvsError::KeyEncodingMismatch(None) => write!(f, "store was created without a key encoding"),
+            KvsError::UnknownIndex(name) => write!(f, "unknown secondary index {}", name),
+            KvsError::UnsortedInput(key) => write!(f, "bulk ingest input not sorted at key {}", key),
+            KvsError::StoreNotEmpty => write!(f, "store to restore into is not empty"),
+            KvsError::IncompatibleFormat(found) => write!(
+                f,
+                "incompatible store format ({}): open it with the kvs version that wrote it, \
+                 or upgrade kvs",
+                found
+            ),
+        }
+    }
+}
+
+impl error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KvsError::IoError(e) => Some(e),
+            KvsError::Serde(e) => Some(e),
+            KvsError::Deserialize(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -41,5 +125,13 @@ impl From<prost::DecodeError> for KvsError {
     }
 }
 
+#[cfg(feature = "failpoints")]
+impl KvsError {
+    /// The error of a failpoint configured to `return`.
+    pub(crate) fn injected(failpoint: &str) -> KvsError {
+        KvsError::IoError(io::Error::other(format!("failpoint {} triggered", failpoint)))
+    }
+}
+
 /// Result type
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,67 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Length prefix that ends the records of a sealed log, the footer follows it.
+pub(crate) const FOOTER_MARKER: u32 = u32::MAX;
+
+/// Last bytes of a log with a footer.
+pub(crate) const FOOTER_MAGIC: &[u8; 8] = b"KVSFOOT1";
+
+/// Bytes of records between two entries of the sparse index.
+const SPARSE_INDEX_INTERVAL: u64 = 4096;
+
+/// Summary appended to a key-sorted log when it is sealed.
+///
+/// Lays out as the marker, the footer as JSON, its length as a little endian u64 and the
+/// magic, so it can be found from the end of the file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SegmentFooter {
+    /// Number of records in the log.
+    pub(crate) records: u64,
+    /// Bytes of records, where the marker starts.
+    pub(crate) records_len: u64,
+    /// Smallest and largest key in the log.
+    pub(crate) key_range: Option<(String, String)>,
+    /// Seal time in seconds since the Unix epoch.
+    pub(crate) sealed_at: u64,
+    /// Key and position of the first record of every `SPARSE_INDEX_INTERVAL` bytes, in key order.
+    pub(crate) sparse_index: Vec<(String, u64)>,
+}
+
+impl SegmentFooter {
+    /// Builds the footer of a log from the key, position and length of its records, in order.
+    pub(crate) fn new<'a>(records: impl IntoIterator<Item = (&'a str, u64, u64)>) -> SegmentFooter {
+        let mut footer = SegmentFooter {
+            sealed_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            ..SegmentFooter::default()
+        };
+        let mut next_sample = 0;
+        for (key, pos, len) in records {
+            if pos >= next_sample {
+                footer.sparse_index.push((key.to_owned(), pos));
+                next_sample = pos + SPARSE_INDEX_INTERVAL;
+            }
+            footer.key_range = match footer.key_range.take() {
+                Some((first, _)) => Some((first, key.to_owned())),
+                None => Some((key.to_owned(), key.to_owned())),
+            };
+            footer.records += 1;
+            footer.records_len = pos + len;
+        }
+        footer
+    }
+
+    /// Appends the footer to a log after its last record.
+    pub(crate) fn write(&self, writer: &mut impl Write) -> Result<()> {
+        let bytes = serde_json::to_vec(self)?;
+        writer.write_all(&FOOTER_MARKER.to_le_bytes())?;
+        writer.write_all(&bytes)?;
+        writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        writer.write_all(FOOTER_MAGIC)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::meta;
+use crate::{Result, Storage};
+
+/// Extension of the hint file next to a log.
+const HINT_EXTENSION: &str = "hint";
+
+/// First bytes of a hint file.
+const HINT_MAGIC: &[u8; 8] = b"KVSHINT1";
+
+/// The index of a sealed log kept in a file next to it, so a reopen can index the log
+/// without reading its records.
+///
+/// Lays out as the magic, the length of the log, then for every record the length of its
+/// key, the key, its position, length and sequence number, and a CRC32 of everything before.
+/// Lengths are little endian, the key length a u32 and the rest u64s.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Hint {
+    /// Length of the log when the hint was written, a log of another length is replayed.
+    pub(crate) log_len: u64,
+    /// Every record of the log, in log order.
+    pub(crate) records: Vec<HintRecord>,
+}
+
+/// Where a record of a hinted log is, with its stored key and sequence number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HintRecord {
+    pub(crate) key: String,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+    pub(crate) sequence: u64,
+}
+
+impl Hint {
+    /// Writes the hint of `log` next to it, replacing any previous one.
+    pub(crate) fn write(&self, storage: &dyn Storage, log: &Path) -> Result<()> {
+        let mut bytes = HINT_MAGIC.to_vec();
+        bytes.extend_from_slice(&self.log_len.to_le_bytes());
+        for record in &self.records {
+            bytes.extend_from_slice(&(record.key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(record.key.as_bytes());
+            bytes.extend_from_slice(&record.pos.to_le_bytes());
+            bytes.extend_from_slice(&record.len.to_le_bytes());
+            bytes.extend_from_slice(&record.sequence.to_le_bytes());
+        }
+        let checksum = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        meta::write_atomically(storage, &path(log), &bytes)
+    }
+
+    /// Reads the hint of `log`, `None` if there is none or it is torn or corrupted.
+    pub(crate) fn read(storage: &dyn Storage, log: &Path) -> Result<Option<Hint>> {
+        let mut bytes = Vec::new();
+        match storage.open_reader(&path(log)) {
+            Ok(mut reader) => reader.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(decode(&bytes))
+    }
+}
+
+/// Deletes the hint of `log`, if it has one.
+pub(crate) fn remove(storage: &dyn Storage, log: &Path) -> Result<()> {
+    match storage.remove_file(&path(log)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the path of the hint of `log`.
+pub(crate) fn path(log: &Path) -> PathBuf {
+    log.with_extension(HINT_EXTENSION)
+}
+
+fn decode(bytes: &[u8]) -> Option<Hint> {
+    let (body, checksum) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+    if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().ok()?) {
+        return None;
+    }
+    let mut rest = body.strip_prefix(HINT_MAGIC)?;
+    let mut hint = Hint { log_len: take_u64(&mut rest)?, records: Vec::new() };
+    while !rest.is_empty() {
+        let key_len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().ok()?) as usize;
+        let key = String::from_utf8(take(&mut rest, key_len)?.to_vec()).ok()?;
+        hint.records.push(HintRecord {
+            key,
+            pos: take_u64(&mut rest)?,
+            len: take_u64(&mut rest)?,
+            sequence: take_u64(&mut rest)?,
+        });
+    }
+    Some(hint)
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (taken, remaining) = rest.split_at_checked(len)?;
+    *rest = remaining;
+    Some(taken)
+}
+
+fn take_u64(rest: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(take(rest, 8)?.try_into().ok()?))
+}
@@ -1,21 +1,56 @@
 use std::cmp::max;
-use std::collections::{BTreeMap, HashMap};
-use std::fs::{self, File, OpenOptions};
+use std::borrow::Cow;
+use std::collections::{btree_map, hash_map, BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::iter::Rev;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, iter, mem, process, thread, vec};
 
 use serde::{Deserialize, Serialize};
 
-use crate::kvs_command::{kvs_command, KvsCommand, KvsRemove, KvsSet};
-use crate::{KvsError, Result};
+use crate::batch::BatchOp;
+use crate::cache::Lru;
+use crate::compaction::{
+    CompactionHandle, CompactionPolicy, CompactionState, GarbageRatio, SizeThreshold,
+};
+use crate::entry::{Entry, OccupiedEntry, VacantEntry};
+use crate::footer::{SegmentFooter, FOOTER_MAGIC, FOOTER_MARKER};
+use crate::hint::{self, Hint, HintRecord};
+use crate::latency::{Latencies, Operation};
+use crate::listener::Listeners;
+use crate::meta::{self, StoreMeta, TMP_EXTENSION};
+use crate::namespace::{self, Namespace};
+use crate::placement::Placement;
+use crate::secondary::Indexes;
+use crate::snapshot::{self, ChecksumWriter, SnapshotHeader};
+use crate::kvs_command::{kvs_command, KvsCommand, KvsRemove, KvsRename, KvsSet};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
+use crate::storage::{LocalStorage, OverlayStorage, Storage, StorageReader, StorageWriter};
+use crate::syncer::Syncer;
+use crate::throttle::Throttle;
+use crate::{
+    CancellationToken, ChecksumVerification, Config, CorruptedRecord, KeyEncoding, KvsError,
+    Options, PrefixStats, DurabilityWatcher, Progress, RecoveryReport, Result, SegmentStats,
+    SequenceGap, Stats, WriteBatch,
+};
+use bytes::{Bytes, BytesMut};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use fail::fail_point;
 use crc32fast::Hasher;
 use prost::Message;
-use std::ffi::OsStr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 const CURRENT_SCHEMA_VERSION: u64 = 1;
+/// Subdirectory of the store that records skipped by replay are copied to.
+const CORRUPT_DIR: &str = "corrupt";
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const COMPACTION_BATCH_SIZE: usize = 1024;
 
 /// For example, this sequence:
 /// store.set("key1", "value1")
@@ -31,7 +66,7 @@ const CURRENT_SCHEMA_VERSION: u64 = 1;
 /// Then update to point to position 41
 /// Finally remove the "key1" entry completely
 ///
-/// When the amount of stale data (40 + 41 = 81 bytes in this example) exceeds the COMPACTION_THRESHOLD (1MB), the store performs compaction by:
+/// When the amount of stale data (40 + 41 = 81 bytes in this example) exceeds the threshold of the compaction policy (1MB by default), the store performs compaction by:
 //
 /// Creating a new log file
 /// Only copying the latest valid entries
@@ -42,18 +77,57 @@ const CURRENT_SCHEMA_VERSION: u64 = 1;
 pub struct KvStore {
     // directory for the log and other data.
     path: PathBuf,
+    // where the log files live.
+    storage: Arc<dyn Storage>,
     // map generation number to the file reader.
-    readers: HashMap<u64, BufReaderWithPos<File>>,
+    readers: HashMap<u64, LogReader>,
     // writer of the current log.
-    writer: BufWriterWithPos<File>,
+    writer: LogWriter,
     current_gen: u64,
     index: BTreeMap<String, CommandPos>,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction.
     uncompacted: u64,
     current_sequence: Option<u64>,
+    // highest sequence number known to be on durable storage.
+    synced_sequence: u64,
+    // set with `Options::background_sync`.
+    syncer: Option<Syncer>,
+    // total size of the log files, including buffered writes.
+    disk_bytes: u64,
+    max_disk_bytes: Option<u64>,
+    // set in cache mode.
+    lru: Option<Lru>,
+    // set while there are unflushed writes.
+    checkpoint: Option<Checkpoint>,
+    compaction_rate_limit: Option<u64>,
+    // decides when writes compact.
+    compaction_policy: Arc<dyn CompactionPolicy>,
+    // how long compaction keeps removes.
+    tombstone_retention: Option<Duration>,
+    // durations of the timed operations since open.
+    latencies: Latencies,
+    slow_op_threshold: Option<Duration>,
+    sync_on_drop: bool,
+    checksum_verification: ChecksumVerification,
+    // gets since the last one that verified its checksum, for sampling.
+    unverified_gets: u32,
+    recovery_report: RecoveryReport,
+    meta: StoreMeta,
     reader_buffer_size: usize,
     writer_buffer_size: usize,
+    listeners: Listeners,
+    key_encoding: Option<Arc<dyn KeyEncoding>>,
+    // not persisted, rebuilt from the logs on open.
+    indexes: Indexes,
+    // directory of every log file.
+    placement: Placement,
+    // exclusive lock on the directory, released on drop.
+    _lock: Box<dyn Send>,
+    // set by `kill`, drop then leaves the files as they are.
+    killed: bool,
+    // removes the directory of a temporary store, declared last so the logs are closed first.
+    temp_dir: Option<TempDirGuard>,
 }
 
 impl KvStore {
@@ -65,82 +139,443 @@ impl KvStore {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>, reader_buffer_size: Option<usize>, writer_buffer_size: Option<usize>) -> Result<KvStore> {
-        let reader_buffer_size = reader_buffer_size.unwrap_or(8 * 1024); // 8kb
-        let writer_buffer_size = writer_buffer_size.unwrap_or(8 * 1024);
+        KvStore::open_with(
+            path,
+            Options {
+                reader_buffer_size,
+                writer_buffer_size,
+                ..Options::default()
+            },
+        )
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StoreLocked` if another store has the directory open.
+    ///
+    /// It returns `KvsError::Cancelled` if the replay was cancelled through `Options::progress`.
+    ///
+    /// It returns `KvsError::IncompatibleFormat` if the store was written in a format this
+    /// version can't read.
+    ///
+    /// It returns `KvsError::StoreMismatch` if `Options::expected_id` is set to another id.
+    ///
+    /// It returns `KvsError::KeyEncodingMismatch` if `Options::key_encoding` differs from the
+    /// encoding the store was created with.
+    ///
+    /// It returns `KvsError::InconsistentLog` if `Options::strict_recovery` is set and the
+    /// replay found sequence number gaps or duplicates.
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<KvStore> {
+        let reader_buffer_size = options.reader_buffer_size.unwrap_or(8 * 1024); // 8kb
+        let writer_buffer_size = options.writer_buffer_size.unwrap_or(8 * 1024);
+        let progress = options.progress.unwrap_or_default();
+        let storage = options.storage.unwrap_or_else(|| Arc::new(LocalStorage));
+        let listeners = options.listeners;
+        let max_disk_bytes = options.max_disk_bytes;
+        let cache_budget = options.cache_budget;
+        let compaction_rate_limit = options.compaction_rate_limit;
+        let tombstone_retention = options.tombstone_retention;
+        let slow_op_threshold = options.slow_op_threshold;
+        let compaction_policy =
+            options.compaction_policy.unwrap_or_else(|| Arc::new(SizeThreshold::default()));
+        let compact_on_open = options.compact_on_open;
+        let sync_on_drop = options.sync_on_drop;
+        let background_sync = options.background_sync;
+        let strict_recovery = options.strict_recovery;
+        let skip_corrupted = options.skip_corrupted;
+        let checksum_verification = options.checksum_verification;
+        let expected_id = options.expected_id;
+        let delete_orphans = options.delete_orphans;
+        let key_encoding = options.key_encoding;
+        let secondary_indexes = options.secondary_indexes;
+        let data_dirs = options.data_dirs;
+        let segment_shards = options.segment_shards;
+        let descriptive_names = options.descriptive_names;
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        progress.check_cancelled()?;
+        storage.create_dir_all(&path)?;
+        let lock = storage.lock_dir(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => KvsError::StoreLocked,
+            _ => KvsError::IoError(e),
+        })?;
+
+        meta::check_format(storage.as_ref(), &path)?;
+        let (mut meta, created) = match StoreMeta::load(storage.as_ref(), &path)? {
+            Some(meta) => (meta, false),
+            None => (StoreMeta::new(CURRENT_SCHEMA_VERSION), true),
+        };
+        meta.check_compatible(CURRENT_SCHEMA_VERSION)?;
+        if expected_id.is_some_and(|expected_id| expected_id != meta.id) {
+            return Err(KvsError::StoreMismatch(meta.id));
+        }
+        let encoding_name = key_encoding.as_ref().map(|encoding| encoding.name().to_owned());
+        if created {
+            meta.key_encoding = encoding_name;
+        } else if meta.key_encoding != encoding_name {
+            return Err(KvsError::KeyEncodingMismatch(meta.key_encoding));
+        }
+        // directories are only ever added, their logs would be lost otherwise.
+        let new_dirs: Vec<PathBuf> =
+            data_dirs.into_iter().filter(|dir| !meta.data_dirs.contains(dir)).collect();
+        meta.data_dirs.extend(new_dirs.iter().cloned());
+        // likewise shards, and older versions can't find descriptive names.
+        let layout_changed = segment_shards > meta.segment_shards
+            || (descriptive_names && !meta.descriptive_names);
+        meta.segment_shards = max(meta.segment_shards, segment_shards);
+        meta.descriptive_names |= descriptive_names;
+        let mut placement = Placement::new(
+            iter::once(path.clone()).chain(meta.data_dirs.iter().cloned()).collect(),
+            meta.segment_shards,
+            meta.descriptive_names,
+        );
+        placement.create_dirs(storage.as_ref())?;
+
+        placement.discover(storage.as_ref())?;
+        let unfinished_compaction = meta.compacting.map(|gen| placement.log_path(gen));
+        let orphans =
+            clean_orphans(storage.as_ref(), &placement.dirs(), unfinished_compaction, delete_orphans)?;
+        if created || meta.compacting.is_some() || !new_dirs.is_empty() || layout_changed {
+            meta.compacting = None;
+            meta.save(storage.as_ref(), &path)?;
+        }
 
         let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
-
-        let mut highest_seq = 0;
-
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
+        let mut total_bytes = 0;
 
+        let gen_list = placement.discover(storage.as_ref())?;
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?, reader_buffer_size)?;
+            let log = placement.log_path(gen);
+            total_bytes += storage.file_len(&log)?;
+            readers.insert(gen, BufReaderWithPos::new(storage.open_reader(&log)?, reader_buffer_size)?);
+        }
+        progress.set_total_bytes(total_bytes);
+
+        // All existing generations are sealed, so they can be replayed independently. Those
+        // with a hint file are indexed from it instead.
+        let mut partials = load_hints(storage.as_ref(), &placement, &gen_list, &progress)?;
+        let hinted: HashSet<u64> = partials.iter().map(|partial| partial.gen).collect();
+        partials.extend(replay_all(&mut readers, &hinted, &progress, &listeners, skip_corrupted)?);
+        let mut recovery_report = check_sequences(&partials);
+        recovery_report.orphans = orphans;
+        recovery_report.corrupted = quarantine_corrupted(storage.as_ref(), &path, &placement, &partials)?;
+        if strict_recovery && !recovery_report.is_clean() {
+            return Err(KvsError::InconsistentLog(recovery_report));
+        }
+        let (index, uncompacted, highest_seq) = merge_partial_indexes(partials);
 
-            let (uncompat, seq) = load_v2(gen, &mut reader, &mut index)?;
+        // compaction may have dropped the records with the highest sequences.
+        let highest_seq = max(highest_seq, meta.sequence);
 
-            uncompacted += uncompat;
-            readers.insert(gen, reader);
-            highest_seq = max(highest_seq, seq);
-        }
+        let lru = cache_budget.map(|budget| new_lru(&index, budget));
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers, reader_buffer_size, writer_buffer_size)?;
+        let writer = new_log_file(
+            storage.as_ref(),
+            &placement.place(current_gen),
+            current_gen,
+            &mut readers,
+            reader_buffer_size,
+            writer_buffer_size,
+        )?;
+
+        let syncer = match background_sync {
+            Some(interval) => {
+                let log = storage.open_writer(&placement.log_path(current_gen))?;
+                Some(Syncer::start(log, highest_seq, interval, listeners.clone())?)
+            }
+            None => None,
+        };
 
-        Ok(KvStore {
+        let mut store = KvStore {
             path,
+            storage,
             readers,
             writer,
             current_gen,
             index,
             uncompacted,
             current_sequence: Some(highest_seq),
+            synced_sequence: highest_seq,
+            syncer,
+            disk_bytes: total_bytes,
+            max_disk_bytes,
+            lru,
+            checkpoint: None,
+            compaction_rate_limit,
+            compaction_policy,
+            tombstone_retention,
+            latencies: Latencies::default(),
+            slow_op_threshold,
+            sync_on_drop,
+            checksum_verification,
+            unverified_gets: 0,
+            recovery_report,
+            meta,
             reader_buffer_size,
             writer_buffer_size,
-        })
+            listeners,
+            key_encoding,
+            indexes: Indexes::new(secondary_indexes),
+            placement,
+            _lock: lock,
+            killed: false,
+            temp_dir: None,
+        };
+        store.rebuild_indexes()?;
+        // the budget may have shrunk since the last open.
+        store.write_and_flush(KvStore::evict)?;
+        if let Some(ratio) = compact_on_open {
+            let state = CompactionState { stale_bytes: store.uncompacted, disk_bytes: store.disk_bytes };
+            if (GarbageRatio { ratio, min_stale_bytes: 0 }).should_compact(&state) {
+                store.compact()?;
+            }
+        }
+        Ok(store)
     }
 
-    /// Sets the value of a string key to a string.
+    /// Opens a `KvStore` like `open_with` but leaves its directory as it is, to see what an
+    /// open would do.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// Every change the open makes (moving orphans and corrupted records to quarantine, the
+    /// metadata, the new generation) and later writes are kept in memory and dropped with
+    /// the store, see `recovery_report` for what they were. `Options::compact_on_open` is
+    /// ignored, compacting would copy the live data into memory.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    pub fn set_v2(&mut self, key: String, value: String) -> Result<()> {
-        let sequence = self.current_sequence.unwrap_or(0) + 1;
-        self.current_sequence = Some(sequence);
+    /// Like `open_with`.
+    pub fn open_dry_run(path: impl Into<PathBuf>, mut options: Options) -> Result<KvStore> {
+        let base = options.storage.take().unwrap_or_else(|| Arc::new(LocalStorage));
+        options.storage = Some(Arc::new(OverlayStorage::new(base)));
+        options.compact_on_open = None;
+        KvStore::open_with(path, options)
+    }
 
-        let cmd = KvsCommand::set(key, value, sequence);
-        let pos = self.writer.pos;
+    /// Opens a `KvStore` in a new directory under the system temp directory.
+    ///
+    /// The directory and every file in it are removed when the store is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors creating the directory.
+    pub fn open_temporary() -> Result<KvStore> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let path = env::temp_dir().join(format!(
+            "kvs-{}-{}-{}",
+            process::id(),
+            nanos,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let guard = TempDirGuard(path.clone());
+
+        let mut store = KvStore::open_with(path, Options::default())?;
+        store.temp_dir = Some(guard);
+        Ok(store)
+    }
 
-        let cmd_bytes = cmd.encode_to_vec();
+    /// Returns the directory of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-        // Write length prefix (4 bytes, little endian)
-        self.writer.write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+    /// Returns the persistent UUID of the store, assigned when it was created.
+    ///
+    /// Lets backups, replicas and monitoring tell store directories apart.
+    pub fn id(&self) -> &str {
+        &self.meta.id
+    }
 
-        // Write actual message
-        self.writer.write_all(&cmd_bytes)?;
-        self.writer.flush()?;
+    /// Returns the sequence number anomalies found when the store was opened.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
 
-        // Update index and track uncompacted bytes
-        if let Some(kvs_command::Command::Set(set)) = cmd.command {
-            if let Some(old_cmd) = self
-                .index
-                .insert(set.key, CommandPos { gen: self.current_gen, pos, len: self.writer.pos - pos })
-            {
-                self.uncompacted += old_cmd.len;
-            }
+    /// Pushes buffered writes of the active log to the OS.
+    ///
+    /// Every write method flushes before returning, so this only matters for writes that
+    /// failed halfway.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, the unflushed writes are rolled back.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_and_flush(|_| Ok(()))
+    }
+
+    /// Flushes the active log and forces it down to durable storage.
+    ///
+    /// Writes returned before the call survive a crash of the machine once it returns.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors from flushing or syncing the log.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.mark_synced();
+        Ok(())
+    }
+
+    /// Makes sure every write up to sequence number `sequence` is on durable storage,
+    /// syncing the active log only if one of them may not be yet.
+    ///
+    /// Lets writers that don't sync each write wait for durability where it matters, for
+    /// example at the end of a job, without syncing more often than needed. A sequence
+    /// number not written yet syncs every write so far. Writes replayed on open count as
+    /// synced, see `Options::sync_on_drop` for making sure they are.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors from flushing or syncing the log.
+    pub fn sync_up_to(&mut self, sequence: u64) -> Result<()> {
+        if sequence <= self.synced_sequence() {
+            return Ok(());
         }
+        self.sync()
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Returns the sequence number of the last write, 0 for an empty store.
+    pub fn last_sequence(&self) -> u64 {
+        self.current_sequence.unwrap_or(0)
+    }
+
+    /// Returns the highest sequence number known to be on durable storage, see `sync_up_to`.
+    pub fn synced_sequence(&self) -> u64 {
+        match &self.syncer {
+            Some(syncer) => max(self.synced_sequence, syncer.durable()),
+            None => self.synced_sequence,
+        }
+    }
+
+    /// Returns a handle to wait for writes to become durable from any thread, `None` without
+    /// `Options::background_sync`.
+    pub fn durability_watcher(&self) -> Option<DurabilityWatcher> {
+        self.syncer.as_ref().map(Syncer::watcher)
+    }
+
+    /// Records that every write so far is on durable storage.
+    fn mark_synced(&mut self) {
+        self.synced_sequence = self.last_sequence();
+        if let Some(syncer) = &self.syncer {
+            syncer.synced(self.synced_sequence);
+        }
+        self.listeners.on_durable(self.synced_sequence);
+    }
+
+    /// Hands the background syncer the active log after switching to a new one.
+    fn track_active_log(&mut self) -> Result<()> {
+        if let Some(syncer) = &self.syncer {
+            syncer.track(self.storage.open_writer(&self.placement.log_path(self.current_gen))?);
+        }
+        Ok(())
+    }
+
+    /// Closes the store the way a killed process would, for crash tests.
+    ///
+    /// Buffered writes are thrown away and the metadata is not saved, only what reached the
+    /// storage stays. The directory lock is released.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors reopening the active log.
+    #[cfg(feature = "failpoints")]
+    pub fn kill(mut self) -> Result<()> {
+        // a fresh writer, the old one must not flush its buffer on drop.
+        let log = self.placement.log_path(self.current_gen);
+        let writer = open_log_writer(self.storage.as_ref(), &log, self.writer_buffer_size)?;
+        mem::replace(&mut self.writer, writer).discard();
+        self.killed = true;
+        Ok(())
+    }
+
+    /// Changes the compaction rate limit, see `Options::compaction_rate_limit`.
+    ///
+    /// `None` lifts the limit. Takes effect from the next compaction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the limit is zero.
+    pub fn set_compaction_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        assert!(bytes_per_sec != Some(0), "compaction rate limit must be positive");
+        self.compaction_rate_limit = bytes_per_sec;
+    }
+
+    /// Changes the compaction policy, see `Options::compaction_policy`.
+    ///
+    /// Takes effect from the next write.
+    pub fn set_compaction_policy(&mut self, policy: Arc<dyn CompactionPolicy>) {
+        self.compaction_policy = policy;
+    }
+
+    /// Returns the settings that can be changed on the open store, see `set_config`.
+    pub fn config(&self) -> Config {
+        Config {
+            compaction_policy: self.compaction_policy.clone(),
+            compaction_rate_limit: self.compaction_rate_limit,
+            cache_budget: self.lru.as_ref().map(Lru::budget),
+            max_disk_bytes: self.max_disk_bytes,
+            sync_on_drop: self.sync_on_drop,
+        }
+    }
+
+    /// Applies settings without reopening the store, usually ones from `config` with some
+    /// fields changed.
+    ///
+    /// A lower cache budget evicts right away. Turning cache mode on ranks the keys as a
+    /// reopen would.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up during eviction, the store is
+    /// left unchanged then but for the new settings.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the compaction rate limit is zero.
+    pub fn set_config(&mut self, config: Config) -> Result<()> {
+        self.set_compaction_rate_limit(config.compaction_rate_limit);
+        self.compaction_policy = config.compaction_policy;
+        self.max_disk_bytes = config.max_disk_bytes;
+        self.sync_on_drop = config.sync_on_drop;
+        match (&mut self.lru, config.cache_budget) {
+            (Some(lru), Some(budget)) => lru.set_budget(budget),
+            (lru @ None, Some(budget)) => *lru = Some(new_lru(&self.index, budget)),
+            (lru, None) => *lru = None,
         }
+        self.write_and_flush(KvStore::evict)
+    }
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, the store is left unchanged.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_v2(&mut self, key: String, value: String) -> Result<()> {
+        let started = Instant::now();
+        let reported = self.slow_op_threshold.map(|_| key.clone());
+        let key = self.encode_key(key);
+        self.write_and_flush(|store| {
+            store.write_set(key, value)?;
+            store.evict()
+        })?;
 
+        self.maybe_compact()?;
+
+        self.record_latency(Operation::Set, reported.as_deref(), started);
         Ok(())
     }
 
@@ -152,202 +587,2743 @@ impl KvStore {
     ///
     /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
     pub fn get_v2(&mut self, key: String) -> Result<Option<String>>{
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self.readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let started = Instant::now();
+        let reported = self.slow_op_threshold.map(|_| key.clone());
+        let key = self.encode_key(key);
+        let verify = self.verify_get();
+        let value = if let Some(cmd_pos) = self.index.get(&key) {
+            let value = if verify {
+                read_value(&mut self.readers, &self.listeners, cmd_pos)?
+            } else {
+                read_value_unverified(&mut self.readers, &self.listeners, cmd_pos)?
+            };
+            if let Some(lru) = &mut self.lru {
+                lru.touch(&key);
+            }
+            Some(value)
+        } else {
+            None
+        };
+
+        self.record_latency(Operation::Get, reported.as_deref(), started);
+        Ok(value)
+    }
+
+    /// Returns whether the next get verifies the checksum of its record, see
+    /// `Options::checksum_verification`.
+    fn verify_get(&mut self) -> bool {
+        match self.checksum_verification {
+            ChecksumVerification::Always => true,
+            ChecksumVerification::OnReplay => false,
+            ChecksumVerification::Sample(n) => {
+                self.unverified_gets += 1;
+                if self.unverified_gets < n {
+                    return false;
+                }
+                self.unverified_gets = 0;
+                true
+            }
+        }
+    }
+
+    /// Gets the value of a given key as raw bytes, `None` if the key does not exist.
+    ///
+    /// Works like `get_v2`, but the value is a slice of the buffer the record was read
+    /// into rather than a `String` decoded out of it, so it is neither copied nor checked
+    /// for UTF-8 again. Meant for hot read paths handing values on as bytes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Bytes>> {
+        let started = Instant::now();
+        let reported = self.slow_op_threshold.map(|_| key.clone());
+        let key = self.encode_key(key);
+        let verify = self.verify_get();
+        let value = if let Some(cmd_pos) = self.index.get(&key) {
+            let value = read_value_bytes(&mut self.readers, &self.listeners, cmd_pos, verify)?;
+            if let Some(lru) = &mut self.lru {
+                lru.touch(&key);
+            }
+            Some(value)
+        } else {
+            None
+        };
+
+        self.record_latency(Operation::Get, reported.as_deref(), started);
+        Ok(value)
+    }
+
+    /// Gets the value of a given key, first setting it to `default()` if it does not exist.
+    ///
+    /// `default` is only called when the key is missing.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, the store is left unchanged.
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    pub fn get_or_insert_with(&mut self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        if let Some(value) = self.get_v2(key.clone())? {
+            return Ok(value);
+        }
+        let value = default();
+        self.set_v2(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// Gets the entry of a given key for in-place updates, reading its value if it exists.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log.
+    pub fn entry(&mut self, key: String) -> Result<Entry<'_>> {
+        match self.get_v2(key.clone())? {
+            Some(value) => Ok(Entry::Occupied(OccupiedEntry { store: self, key, value })),
+            None => Ok(Entry::Vacant(VacantEntry { store: self, key })),
+        }
+    }
+
+    /// Returns a view of the keys of namespace `name`, kept apart from the keys of every
+    /// other namespace.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` contains a NUL character.
+    pub fn namespace(&mut self, name: &str) -> Namespace<'_> {
+        Namespace {
+            prefix: namespace::prefix(name),
+            store: self,
+        }
+    }
+
+    /// Returns the keys within `range` in ascending order, without reading any values.
+    pub fn keys<R: RangeBounds<String>>(&self, range: R) -> impl DoubleEndedIterator<Item = String> + '_ {
+        self.index
+            .range(self.encode_range(range))
+            .map(|(key, _)| self.decode_key(key).into_owned())
+    }
+
+    /// Iterates over the key/value pairs within `range` in ascending key order.
+    ///
+    /// Values are read lazily from the log as the iterator advances.
+    pub fn scan<R: RangeBounds<String>>(&mut self, range: R) -> Scan<'_> {
+        let range = self.encode_range(range);
+        self.scan_encoded(range)
+    }
+
+    /// Returns the key/value pairs filed under `index_key` in the given secondary index, in
+    /// ascending key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownIndex` if no index of that name was given in `Options`.
+    ///
+    /// It propagates I/O or serialization errors during reading the log.
+    pub fn get_by_index(&mut self, index: &str, index_key: &str) -> Result<Vec<(String, String)>> {
+        let Some(stored_keys) = self.indexes.get(index, index_key) else {
+            return Err(KvsError::UnknownIndex(index.to_owned()));
+        };
+        stored_keys
+            .into_iter()
+            .map(|stored_key| {
+                let cmd_pos = self.index.get(&stored_key).expect("secondary index out of sync");
+                let value = read_value(&mut self.readers, &self.listeners, cmd_pos)?;
+                Ok((self.decode_key(&stored_key).into_owned(), value))
+            })
+            .collect()
+    }
+
+    /// Iterates over the key/value pairs within `range` in descending key order.
+    pub fn scan_rev<R: RangeBounds<String>>(&mut self, range: R) -> Rev<Scan<'_>> {
+        self.scan(range).rev()
+    }
+
+    /// Iterates over the key/value pairs whose key starts with `prefix` in ascending key order.
+    ///
+    /// The iterator can be reversed to get the last keys under the prefix first.
+    ///
+    /// With a key encoding, keys match if their encoding starts with the encoded prefix.
+    pub fn scan_prefix(&mut self, prefix: &str) -> Scan<'_> {
+        let range = prefix_range(self.encode_key(prefix.to_owned()));
+        self.scan_encoded(range)
+    }
+
+    /// Returns up to `limit` key/value pairs whose key sorts strictly after `after`, in
+    /// ascending key order.
+    ///
+    /// `None` starts from the first key. Passing the last key of a page gets the next one, so
+    /// the key works as a pagination cursor.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log.
+    pub fn scan_after(&mut self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_owned()),
+            None => Bound::Unbounded,
+        };
+        self.scan((start, Bound::Unbounded)).take(limit).collect()
+    }
+
+    /// Iterates over the writes with a sequence number above `sequence`, oldest first.
+    ///
+    /// Only the records still in the logs are returned: compaction drops overwritten and
+    /// removed values, so after one the changes collapse to the latest write of each key,
+    /// and removes may be missing. A rename shows up as a remove of the old key followed by a
+    /// set of the new one, with the same sequence number.
+    ///
+    /// The logs are scanned for record positions up front, values are read as the iterator
+    /// advances.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the logs.
+    pub fn changes_since(&mut self, sequence: u64) -> Result<Changes<'_>> {
+        let mut ops = self.record_ops()?;
+        ops.retain(|op| op.sequence > sequence);
+
+        Ok(Changes {
+            ops: ops.into_iter(),
+            readers: &mut self.readers,
+            listeners: &self.listeners,
+            key_encoding: self.key_encoding.as_deref(),
+        })
+    }
+
+    /// Iterates over the keys that differ between the store at sequence `from` and at
+    /// sequence `to`, in ascending key order.
+    ///
+    /// A key is added if it didn't exist at `from`, removed if it doesn't exist at `to`, and
+    /// modified if it was written in between. The diff is worked out from the records still
+    /// in the logs: once a compaction dropped the records of a key written before `to`, it
+    /// may show up as added instead of modified, or be missing instead of removed.
+    ///
+    /// The logs are scanned for record positions up front, values are read as the iterator
+    /// advances.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the logs.
+    pub fn diff(&mut self, from: u64, to: u64) -> Result<Diff<'_>> {
+        // latest operation on each key at or below each sequence point.
+        let mut before: HashMap<String, RecordOp> = HashMap::new();
+        let mut after: HashMap<String, RecordOp> = HashMap::new();
+        for op in self.record_ops()? {
+            if op.sequence <= from {
+                before.insert(op.key.clone(), op.clone());
+            }
+            if op.sequence <= to {
+                after.insert(op.key.clone(), op);
+            }
+        }
+
+        let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+        let mut entries = Vec::new();
+        for key in keys {
+            let existed = before.get(key).filter(|op| op.set);
+            let exists = after.get(key).filter(|op| op.set);
+            let entry = match (existed, exists) {
+                (None, Some(op)) => (DiffKind::Added, Some(op.cmd_pos.clone())),
+                (Some(_), None) => (DiffKind::Removed, None),
+                (Some(old), Some(op)) if old.sequence != op.sequence => {
+                    (DiffKind::Modified, Some(op.cmd_pos.clone()))
+                }
+                _ => continue,
+            };
+            entries.push((key.clone(), entry));
+        }
+
+        Ok(Diff {
+            entries: entries.into_iter(),
+            readers: &mut self.readers,
+            listeners: &self.listeners,
+            key_encoding: self.key_encoding.as_deref(),
+        })
+    }
+
+    /// Returns the key/value pair with the smallest key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log.
+    pub fn first(&mut self) -> Result<Option<(String, String)>> {
+        self.scan(..).next().transpose()
+    }
+
+    /// Returns the key/value pair with the largest key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log.
+    pub fn last(&mut self) -> Result<Option<(String, String)>> {
+        self.scan(..).next_back().transpose()
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, the store is left unchanged.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn remove_v2(&mut self, key: String) -> Result<()> {
+        let started = Instant::now();
+        let reported = self.slow_op_threshold.map(|_| key.clone());
+        let key = self.encode_key(key);
+        if self.index.contains_key(&key) {
+            self.write_and_flush(|store| store.write_remove(key))?;
+
+            self.maybe_compact()?;
+
+            self.record_latency(Operation::Remove, reported.as_deref(), started);
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Removes a given key and compacts, so no value it ever held is left in the log files.
+    ///
+    /// A plain remove only appends a tombstone and old values stay on disk until compaction
+    /// drops them. This runs a full compaction right away, which copies the live records
+    /// only and deletes every older log, their `TieredStorage` objects included. It is meant
+    /// for compliance deletes and costs as much as `compact`.
+    ///
+    /// With `Options::tombstone_retention` the remove record, which holds the key but no
+    /// value, is kept so followers drop the key as well. Copies made before, such as exports
+    /// and snapshot archives, are not reached.
+    ///
+    /// Returns whether the key existed. Missing keys are still compacted away, as removed
+    /// keys can leave old values behind.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up during the remove, the store is
+    /// left unchanged.
+    ///
+    /// It propagates I/O or serialization errors during writing the log or compacting. If
+    /// compacting fails the key stays removed, and the next compaction drops its values.
+    pub fn purge(&mut self, key: String) -> Result<bool> {
+        let existed = match self.remove_v2(key) {
+            Ok(()) => true,
+            Err(KvsError::KeyNotFound) => false,
+            Err(e) => return Err(e),
+        };
+        self.compact()?;
+        Ok(existed)
+    }
+
+    /// Removes a given key only if its current value is `expected`.
+    ///
+    /// Returns whether the key was removed, `false` if it is missing or holds another value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, the store is left unchanged.
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    pub fn remove_if(&mut self, key: String, expected: &str) -> Result<bool> {
+        let Some(cmd_pos) = self.index.get(&self.encode_key(key.clone())) else {
+            return Ok(false);
+        };
+        if read_value(&mut self.readers, &self.listeners, cmd_pos)? != expected {
+            return Ok(false);
+        }
+        self.remove_v2(key)?;
+        Ok(true)
+    }
+
+    /// Moves the value of `old_key` to `new_key`, overwriting any value `new_key` had.
+    ///
+    /// Both keys change in a single log record, so a crash can't leave the value under
+    /// both keys or under neither.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if `old_key` is not found.
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, the store is left unchanged.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn rename(&mut self, old_key: String, new_key: String) -> Result<()> {
+        let old_key = self.encode_key(old_key);
+        let new_key = self.encode_key(new_key);
+        let Some(cmd_pos) = self.index.get(&old_key) else {
+            return Err(KvsError::KeyNotFound);
+        };
+        if old_key == new_key {
+            return Ok(());
+        }
+        let value = read_value(&mut self.readers, &self.listeners, cmd_pos)?;
+        self.write_and_flush(|store| {
+            store.write_rename(old_key, new_key, value)?;
+            store.evict()
+        })?;
+
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    /// Applies every write of the batch in order, flushing the log once at the end.
+    ///
+    /// Removes of keys that don't exist at that point of the batch are skipped. The batch
+    /// is not atomic: a crash can leave only a prefix of it applied.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, none of the batch is applied.
+    ///
+    /// It returns `KvsError::QuotaExceeded` if the batch doesn't fit under `max_disk_bytes`,
+    /// none of the batch is applied.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.reserve_sets(batch.ops.iter().filter_map(|op| match op {
+            BatchOp::Set { key, value } => Some((key, value)),
+            BatchOp::Remove { .. } => None,
+        }))?;
+        self.write_and_flush(|store| {
+            for op in batch.ops {
+                match op {
+                    BatchOp::Set { key, value } => store.write_set(store.encode_key(key), value)?,
+                    BatchOp::Remove { key } => {
+                        let key = store.encode_key(key);
+                        if store.index.contains_key(&key) {
+                            store.write_remove(key)?;
+                        }
+                    }
+                }
+            }
+            store.evict()
+        })?;
+
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    /// Applies the writes of another store, for example those of `changes_since` on a
+    /// leader, skipping the ones at or below the last sequence number of this store.
+    ///
+    /// The writes keep their sequence numbers, so a follower can retry a failed call or
+    /// resume after a restart from its `last_sequence` without applying anything twice. A
+    /// remove and a set with the same sequence number are the halves of a rename and are
+    /// applied as one, they must come in the same call. Sequence numbers missing from the
+    /// changes, for example after a compaction of the other store, show up as gaps in the
+    /// `recovery_report` of this one. The log is flushed once at the end.
+    ///
+    /// Returns the number of records written, a rename counting once.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DiskFull` if the disk fills up, none of the changes are applied.
+    ///
+    /// It returns `KvsError::QuotaExceeded` if the changes don't fit under `max_disk_bytes`,
+    /// none of them are applied.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn apply_if_newer(&mut self, changes: impl IntoIterator<Item = Change>) -> Result<u64> {
+        let changes: Vec<Change> = changes.into_iter().collect();
+        let last_sequence = self.last_sequence();
+        let newer = changes.iter().filter(|change| change.sequence > last_sequence);
+        self.reserve_sets(newer.filter_map(|change| Some((&change.key, change.value.as_ref()?))))?;
+        let mut changes = changes.into_iter().peekable();
+        let mut applied = 0;
+        self.write_and_flush(|store| {
+            // before the sequence number moves, a roll back restores it.
+            store.checkpoint();
+            while let Some(change) = changes.next() {
+                if change.sequence <= store.last_sequence() {
+                    continue;
+                }
+                store.current_sequence = Some(change.sequence - 1);
+                let key = store.encode_key(change.key);
+                match change.value {
+                    Some(value) => store.write_set(key, value)?,
+                    None => match changes
+                        .next_if(|next| next.sequence == change.sequence && next.value.is_some())
+                    {
+                        Some(Change { key: new_key, value: Some(value), .. }) => {
+                            let new_key = store.encode_key(new_key);
+                            store.write_rename(key, new_key, value)?
+                        }
+                        _ => store.write_remove(key)?,
+                    },
+                }
+                applied += 1;
+            }
+            store.evict()
+        })?;
+
+        self.maybe_compact()?;
+
+        Ok(applied)
+    }
+
+    /// Returns the writes of every record in the logs, in sequence order.
+    ///
+    /// In a rename the remove of the old key comes first, copies of a record left behind by
+    /// compaction are dropped.
+    fn record_ops(&mut self) -> Result<Vec<RecordOp>> {
+        let mut ops = Vec::new();
+        for (&gen, reader) in self.readers.iter_mut() {
+            ops.extend(record_ops(gen, reader)?);
+        }
+        ops.sort_by_key(|op| (op.sequence, op.set));
+        ops.dedup_by(|op, previous| op.sequence == previous.sequence && op.key == previous.key);
+        Ok(ops)
+    }
+
+    /// Files every live record in the secondary indexes.
+    fn rebuild_indexes(&mut self) -> Result<()> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+        for (stored_key, cmd_pos) in &self.index {
+            let value = read_value(&mut self.readers, &self.listeners, cmd_pos)?;
+            let key = decode_key(self.key_encoding.as_deref(), stored_key);
+            self.indexes.insert(stored_key, &key, &value);
+        }
+        Ok(())
+    }
+
+    /// Iterates over the key/value pairs within a range of encoded keys, see `scan`.
+    fn scan_encoded<R: RangeBounds<String>>(&mut self, range: R) -> Scan<'_> {
+        Scan {
+            entries: self.index.range(range),
+            readers: &mut self.readers,
+            listeners: &self.listeners,
+            key_encoding: self.key_encoding.as_deref(),
+            read_ahead: ReadAhead::default(),
+            token: None,
+            cancelled: false,
+        }
+    }
+
+    /// Turns a key into the form it is stored in, see `Options::key_encoding`.
+    fn encode_key(&self, key: String) -> String {
+        match &self.key_encoding {
+            Some(encoding) => encoding.encode(&key),
+            None => key,
+        }
+    }
+
+    fn decode_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        decode_key(self.key_encoding.as_deref(), key)
+    }
+
+    fn encode_range<R: RangeBounds<String>>(&self, range: R) -> (Bound<String>, Bound<String>) {
+        let encode = |key: &String| self.encode_key(key.clone());
+        (range.start_bound().map(encode), range.end_bound().map(encode))
+    }
+
+    /// Appends a set command to the log and points the index at it, without flushing.
+    ///
+    /// It returns `KvsError::QuotaExceeded` if the record doesn't fit under `max_disk_bytes`.
+    fn write_set(&mut self, key: String, value: String) -> Result<()> {
+        let sequence = self.current_sequence.unwrap_or(0) + 1;
+        let cmd = KvsCommand::set(key, value, sequence);
+        let cmd_bytes = cmd.encode_to_vec();
+        self.reserve(4 + cmd_bytes.len() as u64)?;
+        self.checkpoint();
+
+        self.current_sequence = Some(sequence);
+        let pos = self.writer.pos;
+
+        // Write length prefix (4 bytes, little endian)
+        self.writer.write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+
+        // Write actual message
+        self.writer.write_all(&cmd_bytes)?;
+        self.disk_bytes += self.writer.pos - pos;
+
+        // Update index and track uncompacted bytes
+        if let Some(kvs_command::Command::Set(set)) = cmd.command {
+            let key = decode_key(self.key_encoding.as_deref(), &set.key).into_owned();
+            self.indexes.insert(&set.key, &key, &set.value);
+            let len = self.writer.pos - pos;
+            if let Some(lru) = &mut self.lru {
+                lru.insert(&set.key, len);
+            }
+            let old_cmd = self
+                .index
+                .insert(set.key.clone(), CommandPos { gen: self.current_gen, pos, len });
+            if let Some(old_cmd) = &old_cmd {
+                self.uncompacted += old_cmd.len;
+            }
+            self.push_undo(set.key, old_cmd);
+            self.push_event(WriteEvent::Set(key, sequence));
+        }
+
+        Ok(())
+    }
+
+    /// Appends a remove command to the log and drops the key from the index, without flushing.
+    fn write_remove(&mut self, key: String) -> Result<()> {
+        self.checkpoint();
+        let sequence = self.current_sequence.unwrap_or(0) + 1;
+        self.current_sequence = Some(sequence);
+
+        let cmd = KvsCommand::remove(key, sequence);
+        let pos = self.writer.pos;
+
+        let cmd_bytes = cmd.encode_to_vec();
+
+        // Write length prefix (4 bytes, little endian)
+        self.writer.write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+
+        // Write actual message
+        self.writer.write_all(&cmd_bytes)?;
+        self.disk_bytes += self.writer.pos - pos;
+
+        if let Some(kvs_command::Command::Remove(remove)) = cmd.command {
+            let key = self.decode_key(&remove.key).into_owned();
+            self.push_event(WriteEvent::Remove(key, sequence));
+            self.indexes.remove(&remove.key);
+            if let Some(lru) = &mut self.lru {
+                lru.remove(&remove.key);
+            }
+            let old_cmd = self.index.remove(&remove.key);
+            if let Some(old_cmd) = &old_cmd {
+                // The remove command itself will be deleted in compaction
+                // once a key is removed, both the original set command and the remove command become "stale"
+                // and can be eliminated during compaction.
+                self.uncompacted += old_cmd.len;
+            }
+            self.push_undo(remove.key, old_cmd);
+        }
+
+        Ok(())
+    }
+
+    /// Appends a rename command to the log and moves the index entry, without flushing.
+    fn write_rename(&mut self, old_key: String, new_key: String, value: String) -> Result<()> {
+        let sequence = self.current_sequence.unwrap_or(0) + 1;
+        let cmd = KvsCommand::rename(old_key, new_key, value, sequence);
+        let cmd_bytes = cmd.encode_to_vec();
+        self.reserve(4 + cmd_bytes.len() as u64)?;
+        self.checkpoint();
+
+        self.current_sequence = Some(sequence);
+        let pos = self.writer.pos;
+
+        // Write length prefix (4 bytes, little endian)
+        self.writer.write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+
+        // Write actual message
+        self.writer.write_all(&cmd_bytes)?;
+        self.disk_bytes += self.writer.pos - pos;
+
+        if let Some(kvs_command::Command::Rename(rename)) = cmd.command {
+            let new_key = decode_key(self.key_encoding.as_deref(), &rename.new_key).into_owned();
+            let old_key = self.decode_key(&rename.old_key).into_owned();
+            self.push_event(WriteEvent::Remove(old_key, sequence));
+            self.indexes.remove(&rename.old_key);
+            self.indexes.insert(&rename.new_key, &new_key, &rename.value);
+            let len = self.writer.pos - pos;
+            if let Some(lru) = &mut self.lru {
+                lru.remove(&rename.old_key);
+                lru.insert(&rename.new_key, len);
+            }
+            let old_cmd = self.index.remove(&rename.old_key);
+            if let Some(old_cmd) = &old_cmd {
+                self.uncompacted += old_cmd.len;
+            }
+            self.push_undo(rename.old_key, old_cmd);
+            let old_cmd = self
+                .index
+                .insert(rename.new_key.clone(), CommandPos { gen: self.current_gen, pos, len });
+            if let Some(old_cmd) = &old_cmd {
+                self.uncompacted += old_cmd.len;
+            }
+            self.push_undo(rename.new_key, old_cmd);
+            self.push_event(WriteEvent::Set(new_key, sequence));
+        }
+
+        Ok(())
+    }
+
+    /// Runs `write` and flushes the log.
+    ///
+    /// If the disk fills up, every write since the last flush is rolled back and
+    /// `KvsError::DiskFull` is returned. Running into `max_disk_bytes` rolls back the same way.
+    fn write_and_flush(&mut self, write: impl FnOnce(&mut KvStore) -> Result<()>) -> Result<()> {
+        match write(self).and_then(|()| self.flush_writer()) {
+            Ok(()) => {
+                self.release_checkpoint();
+                if let Some(syncer) = &self.syncer {
+                    syncer.flushed(self.last_sequence());
+                }
+                Ok(())
+            }
+            Err(KvsError::IoError(e)) if e.kind() == io::ErrorKind::StorageFull => {
+                self.roll_back()?;
+                Err(KvsError::DiskFull)
+            }
+            Err(KvsError::QuotaExceeded) => {
+                self.roll_back()?;
+                Err(KvsError::QuotaExceeded)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush_writer(&mut self) -> Result<()> {
+        fail_point!("kv::flush", |_| Err(KvsError::injected("kv::flush")));
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Remembers the state at the last flush, unless there are unflushed writes already.
+    fn checkpoint(&mut self) {
+        if self.checkpoint.is_none() {
+            self.checkpoint = Some(Checkpoint {
+                pos: self.writer.pos,
+                uncompacted: self.uncompacted,
+                current_sequence: self.current_sequence,
+                disk_bytes: self.disk_bytes,
+                undo: Vec::new(),
+                events: Vec::new(),
+            });
+        }
+    }
+
+    /// Records the index entry a write replaced, so a roll back can restore it.
+    fn push_undo(&mut self, key: String, old_cmd: Option<CommandPos>) {
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.undo.push((key, old_cmd));
+        }
+    }
+
+    /// Drops the checkpoint once its writes are flushed, reporting them to the listeners.
+    fn release_checkpoint(&mut self) {
+        let Some(checkpoint) = self.checkpoint.take() else {
+            return;
+        };
+        for event in checkpoint.events {
+            match event {
+                WriteEvent::Set(key, sequence) => self.listeners.on_set(&key, sequence),
+                WriteEvent::Remove(key, sequence) => self.listeners.on_remove(&key, sequence),
+            }
+        }
+    }
+
+    /// Queues a write for the listeners, a roll back drops it unreported.
+    fn push_event(&mut self, event: WriteEvent) {
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.events.push(event);
+        }
+    }
+
+    /// Cuts the active log back to the last flush and restores the state at that point.
+    fn roll_back(&mut self) -> Result<()> {
+        let Some(checkpoint) = self.checkpoint.take() else {
+            return Ok(());
+        };
+
+        // a fresh writer, the old one must not flush its buffer on drop. It is dropped before
+        // the cut, writes that got past its buffer may still land then.
+        let log = self.placement.log_path(self.current_gen);
+        let writer = open_log_writer(self.storage.as_ref(), &log, self.writer_buffer_size)?;
+        mem::replace(&mut self.writer, writer).discard();
+        self.writer.get_ref().set_len(checkpoint.pos)?;
+        self.writer.seek(SeekFrom::End(0))?;
+
+        let mut touched = Vec::new();
+        for (key, old_cmd) in checkpoint.undo.into_iter().rev() {
+            if !self.indexes.is_empty() {
+                touched.push(key.clone());
+            }
+            if let Some(lru) = &mut self.lru {
+                match &old_cmd {
+                    Some(old_cmd) => lru.insert(&key, old_cmd.len),
+                    None => lru.remove(&key),
+                }
+            }
+            match old_cmd {
+                Some(old_cmd) => self.index.insert(key, old_cmd),
+                None => self.index.remove(&key),
+            };
+        }
+        // refile the restored values, their records come before the cut.
+        for key in touched {
+            match self.index.get(&key) {
+                Some(cmd_pos) => {
+                    let value = read_value(&mut self.readers, &self.listeners, cmd_pos)?;
+                    let decoded = decode_key(self.key_encoding.as_deref(), &key);
+                    self.indexes.insert(&key, &decoded, &value);
+                }
+                None => self.indexes.remove(&key),
+            }
+        }
+        self.uncompacted = checkpoint.uncompacted;
+        self.current_sequence = checkpoint.current_sequence;
+        self.disk_bytes = checkpoint.disk_bytes;
+        Ok(())
+    }
+
+    /// Records the current sequence in the store metadata.
+    fn save_meta(&mut self) -> Result<()> {
+        self.meta.sequence = self.current_sequence.unwrap_or(0);
+        self.meta.schema_version = CURRENT_SCHEMA_VERSION;
+        self.meta.save(self.storage.as_ref(), &self.path)
+    }
+
+    /// Adds the time since `started` to the histogram of `operation`, reporting it if it
+    /// is over `Options::slow_op_threshold`.
+    fn record_latency(&mut self, operation: Operation, key: Option<&str>, started: Instant) {
+        let duration = started.elapsed();
+        self.latencies.record(operation, duration);
+        if self.slow_op_threshold.is_some_and(|threshold| duration > threshold) {
+            self.listeners.on_slow_operation(operation, key, duration);
+        }
+    }
+
+    /// Compacts if the compaction policy asks for it after a write.
+    fn maybe_compact(&mut self) -> Result<()> {
+        let state = CompactionState { stale_bytes: self.uncompacted, disk_bytes: self.disk_bytes };
+        if self.compaction_policy.should_compact(&state) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a remove written at `timestamp` is still within
+    /// `Options::tombstone_retention`.
+    fn retains_tombstone(&self, timestamp: u64) -> bool {
+        self.tombstone_retention.is_some_and(|retention| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            timestamp >= now.saturating_sub(retention.as_secs())
+        })
+    }
+
+    /// Returns the removes compaction has to keep, as records by the key they remove.
+    ///
+    /// Reads every log, but only if `Options::tombstone_retention` is set.
+    fn retained_tombstones(&mut self) -> Result<BTreeMap<String, Vec<u8>>> {
+        if self.tombstone_retention.is_none() {
+            return Ok(BTreeMap::new());
+        }
+        let mut latest = HashMap::new();
+        for op in self.record_ops()? {
+            latest.insert(op.key.clone(), op);
+        }
+        // a rename copied for its new key removes the old one already.
+        let live: HashSet<(u64, u64)> =
+            self.index.values().map(|cmd_pos| (cmd_pos.gen, cmd_pos.pos)).collect();
+        Ok(latest
+            .into_values()
+            .filter(|op| !op.set && !live.contains(&(op.cmd_pos.gen, op.cmd_pos.pos)))
+            .filter(|op| self.retains_tombstone(op.timestamp))
+            .map(|op| (op.key.clone(), tombstone_record(&op)))
+            .collect())
+    }
+
+    /// Removes least recently used keys until the live data fits the cache budget, without flushing.
+    fn evict(&mut self) -> Result<()> {
+        while let Some(key) = self.lru.as_ref().and_then(Lru::victim).cloned() {
+            self.write_remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure `len` more bytes fit under `max_disk_bytes`, compacting if there is stale data.
+    fn reserve(&mut self, len: u64) -> Result<()> {
+        let Some(max_disk_bytes) = self.max_disk_bytes else {
+            return Ok(());
+        };
+        // compaction moves records out of the active log where a roll back can't cut them, so
+        // it only runs before the first write of a call.
+        let over = self.disk_bytes + len > max_disk_bytes;
+        if over && self.uncompacted > 0 && self.checkpoint.is_none() {
+            self.compact()?;
+        }
+        if self.disk_bytes + len > max_disk_bytes {
+            return Err(KvsError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// Reserves room for the set records of a whole call before the first of them is written,
+    /// so a compaction they need runs while the call can still be rolled back.
+    fn reserve_sets<'a>(
+        &mut self,
+        writes: impl Iterator<Item = (&'a String, &'a String)>,
+    ) -> Result<()> {
+        if self.max_disk_bytes.is_none() {
+            return Ok(());
+        }
+        let sequence = self.current_sequence.unwrap_or(0) + 1;
+        let len = writes
+            .map(|(key, value)| {
+                let cmd = KvsCommand::set(self.encode_key(key.clone()), value.clone(), sequence);
+                4 + cmd.encoded_len() as u64
+            })
+            .sum();
+        self.reserve(len)
+    }
+
+    /// Returns key, byte and segment counts for the store.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors reading the log file sizes.
+    pub fn stats(&self) -> Result<Stats> {
+        let live_bytes: u64 = self.index.values().map(|cmd_pos| cmd_pos.len).sum();
+        let mut disk_bytes = 0;
+        for &gen in self.readers.keys() {
+            disk_bytes += self.storage.file_len(&self.placement.log_path(gen))?;
+        }
+        let garbage_ratio = if disk_bytes == 0 {
+            0.0
+        } else {
+            disk_bytes.saturating_sub(live_bytes) as f64 / disk_bytes as f64
+        };
+
+        Ok(Stats {
+            id: self.meta.id.clone(),
+            keys: self.index.len() as u64,
+            live_bytes,
+            disk_bytes,
+            garbage_ratio,
+            segments: self.readers.len() as u64,
+            highest_sequence: self.current_sequence.unwrap_or(0),
+            latencies: self.latencies.clone(),
+        })
+    }
+
+    /// Returns the number and size of the live keys starting with `prefix`.
+    ///
+    /// Only the index is read, so it is cheap enough for per-tenant usage accounting. Keys
+    /// match like in `scan_prefix`.
+    pub fn prefix_stats(&self, prefix: &str) -> PrefixStats {
+        let range = prefix_range(self.encode_key(prefix.to_owned()));
+        let mut stats = PrefixStats::default();
+        for cmd_pos in self.index.range(range).map(|(_, cmd_pos)| cmd_pos) {
+            stats.keys += 1;
+            stats.live_bytes += cmd_pos.len;
+        }
+        stats
+    }
+
+    /// Returns the size, live data and record count of each log file, ordered by generation.
+    ///
+    /// Counting the records walks the length prefixes of every log, which reads about as
+    /// much as a replay without decoding anything.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors reading the log files.
+    pub fn segment_stats(&self) -> Result<Vec<SegmentStats>> {
+        // live bytes and keys per generation.
+        let mut live: HashMap<u64, (u64, u64)> = HashMap::new();
+        for cmd_pos in self.index.values() {
+            let (bytes, keys) = live.entry(cmd_pos.gen).or_default();
+            *bytes += cmd_pos.len;
+            *keys += 1;
+        }
+
+        let mut gens: Vec<u64> = self.readers.keys().cloned().collect();
+        gens.sort_unstable();
+        gens.into_iter()
+            .map(|gen| {
+                let log = self.placement.log_path(gen);
+                let disk_bytes = self.storage.file_len(&log)?;
+                let (live_bytes, live_keys) = live.get(&gen).cloned().unwrap_or_default();
+                let mut reader = BufReader::with_capacity(
+                    self.reader_buffer_size,
+                    self.storage.open_reader(&log)?,
+                );
+                Ok(SegmentStats {
+                    generation: gen,
+                    disk_bytes,
+                    live_bytes,
+                    garbage_bytes: disk_bytes.saturating_sub(live_bytes),
+                    records: count_records(&mut reader, disk_bytes)?,
+                    live_keys,
+                })
+            })
+            .collect()
+    }
+
+    /// Clears stale entries in the log. And rewrites latest values in a new log file
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_with(&CompactionHandle::new())
+    }
+
+    /// Compacts like `compact`, reporting progress to `handle` and stopping once it is
+    /// cancelled.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Cancelled` if the compaction was cancelled through `handle`
+    /// while copying records. The store stays consistent: the half-written compaction log is
+    /// kept as an ordinary log and the stale logs stay in place.
+    pub fn compact_with(&mut self, handle: &CompactionHandle) -> Result<()> {
+        let started = Instant::now();
+        self.listeners.on_compaction_start();
+        // the active log is flushed when its writer is replaced below.
+        self.release_checkpoint();
+
+        // increase current gen by 2. current_gen + 1 is for the compaction file.
+        let compaction_gen = self.current_gen + 1;
+        self.meta.compacting = Some(compaction_gen);
+        self.save_meta()?;
+        self.current_gen += 2;
+        self.writer = self.new_log_file(self.current_gen)?;
+        self.track_active_log()?;
+
+        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+        fail_point!("compaction::start", |_| Err(KvsError::injected("compaction::start")));
+
+        let tombstones = self.retained_tombstones()?;
+        let live_bytes: u64 = self.index.values().map(|cmd_pos| cmd_pos.len).sum();
+        let tombstone_bytes: u64 = tombstones.values().map(|record| record.len() as u64).sum();
+        handle.set_total_bytes(live_bytes + tombstone_bytes);
+        let copied = self.copy_live_records(compaction_gen, &mut compaction_writer, tombstones, handle);
+        let tombstones = match copied {
+            Err(KvsError::Cancelled) => return self.abort_compaction(compaction_writer),
+            copied => copied?,
+        };
+        fail_point!("compaction::copied", |_| Err(KvsError::injected("compaction::copied")));
+        let mut records: Vec<(&str, u64, u64)> = self
+            .index
+            .iter()
+            .map(|(key, cmd_pos)| (key.as_str(), cmd_pos.pos, cmd_pos.len))
+            .chain(tombstones.iter().map(|(key, pos, len)| (key.as_str(), *pos, *len)))
+            .collect();
+        records.sort_unstable_by_key(|&(_, pos, _)| pos);
+        SegmentFooter::new(records).write(&mut compaction_writer)?;
+        compaction_writer.flush()?;
+        // the stale logs are deleted below, the compaction log must not be lost in a crash.
+        compaction_writer.get_ref().sync_data()?;
+        drop(compaction_writer);
+        // it holds every live write, the new active log none yet.
+        self.mark_synced();
+
+        // the compaction log is complete, let the storage archive it.
+        let compaction_path = self.placement.seal(self.storage.as_ref(), compaction_gen)?;
+        self.storage.seal(&compaction_path)?;
+        self.readers.insert(
+            compaction_gen,
+            BufReaderWithPos::new(self.storage.open_reader(&compaction_path)?, self.reader_buffer_size)?,
+        );
+        fail_point!("compaction::sealed", |_| Err(KvsError::injected("compaction::sealed")));
+        self.meta.compacting = None;
+        self.save_meta()?;
+
+        // remove stale log files.
+        let stale_gens: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&gen| gen < compaction_gen)
+            .cloned()
+            .collect();
+        for stale_gen in stale_gens {
+            fail_point!("compaction::remove_stale", |_| {
+                Err(KvsError::injected("compaction::remove_stale"))
+            });
+            self.remove_log(stale_gen)?;
+        }
+        self.uncompacted = 0;
+        self.disk_bytes = 0;
+        for &gen in self.readers.keys() {
+            self.disk_bytes += self.storage.file_len(&self.placement.log_path(gen))?;
+        }
+        self.listeners.on_compaction_end();
+
+        self.record_latency(Operation::Compaction, None, started);
+        Ok(())
+    }
+
+    /// Keeps the log of a cancelled compaction as an ordinary log, the index points into it
+    /// for the records copied already, and returns `KvsError::Cancelled`.
+    fn abort_compaction(&mut self, mut compaction_writer: LogWriter) -> Result<()> {
+        compaction_writer.flush()?;
+        // no longer an orphan to remove on open, it must be complete in a crash then.
+        compaction_writer.get_ref().sync_data()?;
+        drop(compaction_writer);
+        self.meta.compacting = None;
+        self.save_meta()?;
+
+        // the originals of the copied records are stale now.
+        self.disk_bytes = 0;
+        for &gen in self.readers.keys() {
+            self.disk_bytes += self.storage.file_len(&self.placement.log_path(gen))?;
+        }
+        let live_bytes: u64 = self.index.values().map(|cmd_pos| cmd_pos.len).sum();
+        self.uncompacted = self.disk_bytes.saturating_sub(live_bytes);
+        Err(KvsError::Cancelled)
+    }
+
+    /// Writes a stream of key/value pairs in ascending key order straight into a new sealed log.
+    ///
+    /// Meant for initial loads far bigger than normal traffic: the log is flushed once at the
+    /// end, `Options::max_disk_bytes` is not checked and no compaction runs. A crash before
+    /// it returns leaves none of the pairs behind. Keys that already exist are overwritten.
+    ///
+    /// The log gets a hint file next to it with the position and sequence number of every
+    /// record, so a reopen indexes it without reading the records. There is no bloom filter:
+    /// every key is in the in-memory index, which answers a get before any log is read. One
+    /// only pays off for sealed logs kept out of that index and searched on demand.
+    ///
+    /// Returns the number of pairs written.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnsortedInput` if a key is not above the key before it, none of
+    /// the pairs are applied then.
+    ///
+    /// It propagates I/O or serialization errors during writing the log, none of the pairs
+    /// are applied then.
+    pub fn bulk_ingest(&mut self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<u64> {
+        self.bulk_ingest_with(pairs, &CancellationToken::new())
+    }
+
+    /// Ingests like `bulk_ingest`, stopping once `token` is cancelled or its deadline passed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Cancelled` if `token` was cancelled before every pair was
+    /// written, none of the pairs are applied then. Otherwise like `bulk_ingest`.
+    pub fn bulk_ingest_with(
+        &mut self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+        token: &CancellationToken,
+    ) -> Result<u64> {
+        self.ingest(pairs.into_iter().map(Ok), token)
+    }
+
+    /// Ingests like `bulk_ingest_with`, stopping at the first pair that is an error.
+    fn ingest(
+        &mut self,
+        pairs: impl IntoIterator<Item = Result<(String, String)>>,
+        token: &CancellationToken,
+    ) -> Result<u64> {
+        self.flush()?;
+        let bulk_gen = self.current_gen + 1;
+        self.meta.compacting = Some(bulk_gen);
+        self.save_meta()?;
+        self.current_gen += 2;
+        self.writer = self.new_log_file(self.current_gen)?;
+        self.track_active_log()?;
+
+        let written = self.write_bulk_log(bulk_gen, pairs, token);
+        let records = match written {
+            Ok(records) => records,
+            Err(e) => {
+                self.remove_log(bulk_gen)?;
+                self.meta.compacting = None;
+                self.save_meta()?;
+                return Err(e);
+            }
+        };
+
+        let bulk_path = self.placement.seal(self.storage.as_ref(), bulk_gen)?;
+        self.storage.seal(&bulk_path)?;
+        let first_sequence = self.current_sequence.unwrap_or(0) + 1;
+        let hint = Hint {
+            log_len: self.storage.file_len(&bulk_path)?,
+            records: (first_sequence..)
+                .zip(&records)
+                .map(|(sequence, (key, cmd_pos))| HintRecord {
+                    key: key.clone(),
+                    pos: cmd_pos.pos,
+                    len: cmd_pos.len,
+                    sequence,
+                })
+                .collect(),
+        };
+        hint.write(self.storage.as_ref(), &bulk_path)?;
+        self.readers.insert(
+            bulk_gen,
+            BufReaderWithPos::new(self.storage.open_reader(&bulk_path)?, self.reader_buffer_size)?,
+        );
+        self.disk_bytes += hint.log_len;
+        self.current_sequence = Some(first_sequence - 1 + records.len() as u64);
+        self.meta.compacting = None;
+        self.save_meta()?;
+
+        let count = records.len() as u64;
+        for (sequence, (key, cmd_pos)) in (first_sequence..).zip(records) {
+            let decoded = decode_key(self.key_encoding.as_deref(), &key);
+            self.listeners.on_set(&decoded, sequence);
+            if !self.indexes.is_empty() {
+                let value = read_value(&mut self.readers, &self.listeners, &cmd_pos)?;
+                self.indexes.insert(&key, &decoded, &value);
+            }
+            if let Some(lru) = &mut self.lru {
+                lru.insert(&key, cmd_pos.len);
+            }
+            if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
+                self.uncompacted += old_cmd.len;
+            }
+        }
+        self.write_and_flush(KvStore::evict)?;
+        Ok(count)
+    }
+
+    /// Writes every key/value pair to `writer`, see `export_with`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log or writing the stream.
+    pub fn export(&mut self, writer: impl Write) -> Result<u64> {
+        self.export_with(writer, &CancellationToken::new())
+    }
+
+    /// Writes a consistent snapshot of every key/value pair to `writer` as an export stream,
+    /// stopping once `token` is cancelled or its deadline passed.
+    ///
+    /// The stream is laid out like a sealed log: a set record for every key in key order,
+    /// then a footer holding the record count and key range. Keys are written as given to
+    /// the store, without the key encoding. Nothing else is read from the store directory,
+    /// so the stream can go to a socket or an object store as well as a file.
+    ///
+    /// Returns the number of pairs written.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Cancelled` if `token` was cancelled before every pair was
+    /// written, the stream ends without a footer then.
+    ///
+    /// It propagates I/O or serialization errors during reading the log or writing the stream.
+    pub fn export_with(&mut self, writer: impl Write, token: &CancellationToken) -> Result<u64> {
+        write_export(self.scan(..).cancel_on(token), writer)
+    }
+
+    /// Writes the key/value pairs whose key starts with `prefix` to `writer` as an export
+    /// stream, see `export_with`.
+    ///
+    /// Keys match like in `scan_prefix`, so a single tenant can be extracted from a shared
+    /// store and restored on its own with `restore_from_stream`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log or writing the stream.
+    pub fn export_prefix(&mut self, writer: impl Write, prefix: &str) -> Result<u64> {
+        write_export(self.scan_prefix(prefix), writer)
+    }
+
+    /// Writes the key/value pairs of `keys` to `writer` as an export stream in key order,
+    /// see `export_with`. Keys that don't exist are skipped.
+    ///
+    /// Returns the number of pairs written.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log or writing the stream.
+    pub fn export_keys(
+        &mut self,
+        writer: impl Write,
+        keys: impl IntoIterator<Item = String>,
+    ) -> Result<u64> {
+        let keys: BTreeSet<String> = keys.into_iter().map(|key| self.encode_key(key)).collect();
+        let pairs = keys.into_iter().filter_map(|key| {
+            let cmd_pos = self.index.get(&key)?;
+            let value = read_value(&mut self.readers, &self.listeners, cmd_pos);
+            let key = decode_key(self.key_encoding.as_deref(), &key).into_owned();
+            Some(value.map(|value| (key, value)))
+        });
+        write_export(pairs, writer)
+    }
+
+    /// Opens the store at `path` with `options` and fills it with the pairs of an export
+    /// stream, see `export_with`.
+    ///
+    /// The checksum of every record is verified and the number of records is checked against
+    /// the footer of the stream. The pairs go in like in `bulk_ingest`, so a stream that
+    /// fails verification leaves none of them behind.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StoreNotEmpty` if the store already holds keys.
+    ///
+    /// It returns `KvsError::CorruptedData` if a record fails its checksum or the footer
+    /// doesn't match the records, and an I/O error of kind `UnexpectedEof` if the stream
+    /// ends early. None of the pairs are applied then.
+    ///
+    /// It returns `KvsError::UnsortedInput` if the keys of the stream are not in the order
+    /// of `options`, which happens if it was exported from a store with another key encoding.
+    ///
+    /// It propagates errors during opening the store and I/O or serialization errors during
+    /// reading the stream or writing the log.
+    pub fn restore_from_stream(
+        path: impl Into<PathBuf>,
+        options: Options,
+        reader: impl Read,
+    ) -> Result<KvStore> {
+        let mut store = KvStore::open_with(path, options)?;
+        if !store.index.is_empty() {
+            return Err(KvsError::StoreNotEmpty);
+        }
+        store.ingest(ExportReader::new(BufReader::new(reader)), &CancellationToken::new())?;
+        Ok(store)
+    }
+
+    /// Writes a consistent snapshot of every key/value pair to a single archive file at
+    /// `path`, returning the number of pairs.
+    ///
+    /// The archive is a header naming the store, its highest sequence number and the codec
+    /// of the records, the export stream of `export` compressed with zlib and a CRC32 of
+    /// everything before it, so it can be moved around as one file and checked as a whole.
+    /// It is written to `path` with a `.tmp` suffix and renamed into place once synced.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading the log or writing the
+    /// archive.
+    pub fn export_snapshot(&mut self, path: impl AsRef<Path>) -> Result<u64> {
+        let path = path.as_ref();
+        // the suffix goes after any extension, so `a.snap` and `a.bak` don't share it.
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".");
+        tmp_path.push(TMP_EXTENSION);
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut writer = ChecksumWriter::new(BufWriter::new(fs::File::create(&tmp_path)?));
+        let guard = TempFileGuard(Some(tmp_path.clone()));
+        SnapshotHeader::new(self.id().to_owned(), self.last_sequence()).write(&mut writer)?;
+        let mut encoder = ZlibEncoder::new(&mut writer, Compression::default());
+        let count = self.export(&mut encoder)?;
+        encoder.finish()?;
+        let (mut writer, checksum) = writer.finish();
+        writer.write_all(&checksum.to_le_bytes())?;
+        let file = writer.into_inner().map_err(io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        guard.disarm();
+        Ok(count)
+    }
+
+    /// Opens the store at `path` with `options` and fills it with the pairs of the archive
+    /// at `archive`, see `export_snapshot` and `restore_from_stream`.
+    ///
+    /// The checksum of the whole archive is verified before any pair is read.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptedData` if the file is not a snapshot archive or fails
+    /// its checksum, and `KvsError::IncompatibleFormat` if its records are compressed with
+    /// a codec this version doesn't know. The store is not opened then. Otherwise like
+    /// `restore_from_stream`.
+    pub fn import_snapshot(
+        path: impl Into<PathBuf>,
+        options: Options,
+        archive: impl AsRef<Path>,
+    ) -> Result<KvStore> {
+        let archive = archive.as_ref();
+        let Some(body_len) = fs::metadata(archive)?.len().checked_sub(4) else {
+            return Err(KvsError::CorruptedData);
+        };
+        let mut file = BufReader::new(fs::File::open(archive)?);
+        let checksum = snapshot::checksum((&mut file).take(body_len))?;
+        let mut stored = [0u8; 4];
+        file.read_exact(&mut stored)?;
+        if u32::from_le_bytes(stored) != checksum {
+            return Err(KvsError::CorruptedData);
+        }
+        file.rewind()?;
+
+        let mut body = file.take(body_len);
+        SnapshotHeader::read(&mut body)?;
+        KvStore::restore_from_stream(path, options, ZlibDecoder::new(body))
+    }
+
+    /// Merges runs of adjacent sealed logs smaller than `max_bytes` into logs of up to
+    /// about `max_bytes`, dropping their stale records.
+    ///
+    /// Every reopen seals the active log, so a store reopened often collects many tiny
+    /// logs that each cost a reader and a replay. Unlike `compact` this only rewrites the
+    /// small logs. The active log is never merged, and it is replaced by a new one if
+    /// anything was merged.
+    ///
+    /// Returns the number of logs merged away.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the logs. The
+    /// logs of the run being merged stay in place then.
+    pub fn merge_segments(&mut self, max_bytes: u64) -> Result<usize> {
+        self.flush()?;
+        let mut sealed: Vec<u64> =
+            self.readers.keys().filter(|&&gen| gen != self.current_gen).cloned().collect();
+        sealed.sort_unstable();
+
+        let mut runs: Vec<Vec<u64>> = vec![Vec::new()];
+        let mut run_bytes = 0;
+        for &gen in &sealed {
+            let bytes = self.storage.file_len(&self.placement.log_path(gen))?;
+            if bytes >= max_bytes || run_bytes + bytes > max_bytes {
+                runs.push(Vec::new());
+                run_bytes = 0;
+            }
+            if bytes < max_bytes {
+                runs.last_mut().unwrap().push(gen);
+                run_bytes += bytes;
+            }
+        }
+        runs.retain(|run| run.len() > 1);
+        if runs.is_empty() {
+            return Ok(0);
+        }
+        // removes shadow sets in the other logs, they can only go along with all of them.
+        let keep_removes = runs[0].len() < sealed.len();
+
+        // like compaction logs, the merged logs are numbered above the active log.
+        let replaced_gen = self.current_gen;
+        let replaced_empty = self.writer.pos == 0;
+        let first_merged_gen = self.current_gen + 1;
+        self.current_gen += runs.len() as u64 + 1;
+        self.writer = self.new_log_file(self.current_gen)?;
+        self.track_active_log()?;
+        // an empty log would only be left to merge next time.
+        if replaced_empty {
+            self.remove_log(replaced_gen)?;
+        }
+
+        let mut merged = 0;
+        for (merged_gen, run) in (first_merged_gen..).zip(runs) {
+            self.meta.compacting = Some(merged_gen);
+            self.save_meta()?;
+            let merged_log = match self.write_merged_log(merged_gen, &run, keep_removes) {
+                Ok(merged_log) => merged_log,
+                Err(e) => {
+                    self.remove_log(merged_gen)?;
+                    self.meta.compacting = None;
+                    self.save_meta()?;
+                    return Err(e);
+                }
+            };
+
+            let merged_path = self.placement.seal(self.storage.as_ref(), merged_gen)?;
+            self.storage.seal(&merged_path)?;
+            self.readers.insert(
+                merged_gen,
+                BufReaderWithPos::new(self.storage.open_reader(&merged_path)?, self.reader_buffer_size)?,
+            );
+            self.index.extend(merged_log.relocated);
+            self.uncompacted =
+                (self.uncompacted + merged_log.remove_bytes).saturating_sub(merged_log.stale_bytes);
+            self.meta.compacting = None;
+            self.save_meta()?;
+
+            for gen in &run {
+                self.remove_log(*gen)?;
+            }
+            merged += run.len();
+        }
+
+        self.disk_bytes = 0;
+        for &gen in self.readers.keys() {
+            self.disk_bytes += self.storage.file_len(&self.placement.log_path(gen))?;
+        }
+        Ok(merged)
+    }
+
+    /// Writes the pairs of a bulk ingest into the log of `bulk_gen`, see `bulk_ingest`.
+    ///
+    /// Returns the stored key and position of every record, the store is left untouched.
+    fn write_bulk_log(
+        &mut self,
+        bulk_gen: u64,
+        pairs: impl IntoIterator<Item = Result<(String, String)>>,
+        token: &CancellationToken,
+    ) -> Result<Vec<(String, CommandPos)>> {
+        let mut writer = self.new_log_file(bulk_gen)?;
+        let mut sequence = self.current_sequence.unwrap_or(0);
+        let mut records: Vec<(String, CommandPos)> = Vec::new();
+        for pair in pairs {
+            token.check_cancelled()?;
+            let (key, value) = pair?;
+            let key = self.encode_key(key);
+            if records.last().is_some_and(|(previous, _)| key <= *previous) {
+                return Err(KvsError::UnsortedInput(self.decode_key(&key).into_owned()));
+            }
+            sequence += 1;
+            let cmd_bytes = KvsCommand::set(key.clone(), value, sequence).encode_to_vec();
+            let pos = writer.pos;
+            writer.write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+            writer.write_all(&cmd_bytes)?;
+            records.push((key, CommandPos { gen: bulk_gen, pos, len: writer.pos - pos }));
+        }
+        let footer = SegmentFooter::new(
+            records.iter().map(|(key, cmd_pos)| (key.as_str(), cmd_pos.pos, cmd_pos.len)),
+        );
+        footer.write(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        Ok(records)
+    }
+
+    /// Writes the newest record of every key in the logs of `run` into the log of
+    /// `merged_gen` in key order, see `merge_segments`. Removes are only written if
+    /// `keep_removes` is set or they are within `Options::tombstone_retention`.
+    ///
+    /// The store is left untouched.
+    fn write_merged_log(&mut self, merged_gen: u64, run: &[u64], keep_removes: bool) -> Result<MergedLog> {
+        let mut ops = Vec::new();
+        for gen in run {
+            let reader = self.readers.get_mut(gen).expect("Cannot find log reader");
+            ops.extend(record_ops(*gen, reader)?);
+        }
+        ops.sort_by_key(|op| (op.sequence, op.set));
+        // a rename yields two ops of the same record.
+        let records: HashMap<(u64, u64), u64> =
+            ops.iter().map(|op| ((op.cmd_pos.gen, op.cmd_pos.pos), op.cmd_pos.len)).collect();
+        let mut latest: BTreeMap<String, RecordOp> = BTreeMap::new();
+        for op in ops {
+            latest.insert(op.key.clone(), op);
+        }
+
+        // live records are copied as they are, a rename with them.
+        let live: Vec<&RecordOp> = latest
+            .values()
+            .filter(|op| {
+                op.set
+                    && self
+                        .index
+                        .get(&op.key)
+                        .is_some_and(|cmd_pos| cmd_pos.gen == op.cmd_pos.gen && cmd_pos.pos == op.cmd_pos.pos)
+            })
+            .collect();
+        let copied: HashSet<(u64, u64)> = live.iter().map(|op| (op.cmd_pos.gen, op.cmd_pos.pos)).collect();
+        let live_bytes: u64 = live.iter().map(|op| op.cmd_pos.len).sum();
+
+        let mut writer = self.new_log_file(merged_gen)?;
+        let mut relocated = Vec::new();
+        let mut written = Vec::new();
+        let mut record = Vec::new();
+        let mut remove_bytes = 0;
+        for op in latest.values() {
+            let pos = writer.pos;
+            if copied.contains(&(op.cmd_pos.gen, op.cmd_pos.pos)) {
+                if !op.set {
+                    // the old key of a rename copied for its new key.
+                    continue;
+                }
+                let reader = self.readers.get_mut(&op.cmd_pos.gen).expect("Cannot find log reader");
+                reader.seek(SeekFrom::Start(op.cmd_pos.pos))?;
+                read_into(reader, &mut record, op.cmd_pos.len as usize)?;
+                writer.write_all(&record)?;
+                relocated.push((op.key.clone(), CommandPos { gen: merged_gen, pos, len: op.cmd_pos.len }));
+            } else if !op.set && (keep_removes || self.retains_tombstone(op.timestamp)) {
+                // rewritten on its own, the record may be a rename of a stale value.
+                writer.write_all(&tombstone_record(op))?;
+                remove_bytes += writer.pos - pos;
+            } else {
+                continue;
+            }
+            written.push((op.key.as_str(), pos, writer.pos - pos));
+        }
+        SegmentFooter::new(written).write(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+
+        Ok(MergedLog {
+            relocated,
+            stale_bytes: records.values().sum::<u64>() - live_bytes,
+            remove_bytes,
+        })
+    }
+
+    /// Copies every live record into the compaction log and repoints the index at it.
+    ///
+    /// The `tombstones` are written among them in key order. Returns the key, position and
+    /// length of every tombstone written.
+    fn copy_live_records(
+        &mut self,
+        compaction_gen: u64,
+        compaction_writer: &mut LogWriter,
+        tombstones: BTreeMap<String, Vec<u8>>,
+        handle: &CompactionHandle,
+    ) -> Result<Vec<(String, u64, u64)>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.readers.values().all(|reader| reader.get_ref().as_file().is_some()) {
+            return self.copy_live_records_uring(compaction_gen, compaction_writer, tombstones, handle);
+        }
+
+        let mut throttle = Throttle::new(self.compaction_rate_limit);
+        let mut new_pos = 0; // pos in the new log file.
+        let mut tombstones = tombstones.into_iter().peekable();
+        let mut written_tombstones = Vec::new();
+        let mut msg_bytes = Vec::new();
+        for (key, cmd_pos) in self.index.iter_mut() {
+            handle.check_cancelled()?;
+            while let Some((tombstone_key, record)) =
+                tombstones.next_if(|(tombstone_key, _)| tombstone_key < key)
+            {
+                handle.add_bytes(record.len() as u64);
+                compaction_writer.write_all(&record)?;
+                written_tombstones.push((tombstone_key, new_pos, record.len() as u64));
+                new_pos += record.len() as u64;
+            }
+            let reader = self
+                .readers
+                .get_mut(&cmd_pos.gen)
+                .expect("Cannot find log reader");
+            if reader.pos != cmd_pos.pos {
+                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            }
+
+            // Read length prefix
+            let mut len_bytes = [0u8; 4];
+            reader.read_exact(&mut len_bytes)?;
+            let msg_len = u32::from_le_bytes(len_bytes) as usize;
+
+            // Read the message
+            read_into(reader, &mut msg_bytes, msg_len)?;
+
+            // Write length prefix to compaction file
+            compaction_writer.write_all(&len_bytes)?;
+
+            // Write message bytes to compaction file
+            compaction_writer.write_all(&msg_bytes)?;
+
+            // Update index to point to new location
+            *cmd_pos = CommandPos { gen: compaction_gen, pos: new_pos, len: 4 + msg_len as u64 };
+            new_pos += 4 + msg_len as u64;
+            handle.add_bytes(4 + msg_len as u64);
+            throttle.consume(4 + msg_len as u64);
+        }
+        for (tombstone_key, record) in tombstones {
+            handle.add_bytes(record.len() as u64);
+            compaction_writer.write_all(&record)?;
+            written_tombstones.push((tombstone_key, new_pos, record.len() as u64));
+            new_pos += record.len() as u64;
+        }
+        compaction_writer.flush()?;
+        Ok(written_tombstones)
+    }
+
+    /// Copies every live record into the compaction log and repoints the index at it.
+    ///
+    /// Records are fetched in batches with one io_uring submission per source generation,
+    /// and each batch is appended to the compaction log with a single write, which goes
+    /// through the writer's ring for local files.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn copy_live_records_uring(
+        &mut self,
+        compaction_gen: u64,
+        compaction_writer: &mut LogWriter,
+        tombstones: BTreeMap<String, Vec<u8>>,
+        handle: &CompactionHandle,
+    ) -> Result<Vec<(String, u64, u64)>> {
+        let mut throttle = Throttle::new(self.compaction_rate_limit);
+        let mut new_pos = compaction_writer.pos; // pos in the new log file.
+        let mut tombstones = tombstones.into_iter().peekable();
+        let mut written_tombstones = Vec::new();
+        let mut entries: Vec<(&String, &mut CommandPos)> = self.index.iter_mut().collect();
+        for batch in entries.chunks_mut(COMPACTION_BATCH_SIZE) {
+            handle.check_cancelled()?;
+            // group the batch by generation, remembering each record's slot in the batch.
+            let mut slots_by_gen: HashMap<u64, Vec<usize>> = HashMap::new();
+            for (slot, (_, cmd_pos)) in batch.iter().enumerate() {
+                slots_by_gen.entry(cmd_pos.gen).or_default().push(slot);
+            }
+
+            let mut records: Vec<Vec<u8>> = vec![Vec::new(); batch.len()];
+            for (gen, slots) in slots_by_gen {
+                let extents: Vec<(u64, usize)> = slots
+                    .iter()
+                    .map(|&slot| (batch[slot].1.pos, batch[slot].1.len as usize))
+                    .collect();
+                let reader = self.readers.get(&gen).expect("Cannot find log reader");
+                let file = reader.get_ref().as_file().expect("checked by copy_live_records");
+                for (slot, bytes) in slots.into_iter().zip(uring::read_extents(file, &extents)?) {
+                    records[slot] = bytes;
+                }
+            }
+
+            // tombstones go in front of the first live key above them.
+            let mut bytes = Vec::new();
+            let mut positions = Vec::new();
+            for ((key, _), record) in batch.iter().zip(records) {
+                while let Some((tombstone_key, tombstone)) =
+                    tombstones.next_if(|(tombstone_key, _)| tombstone_key.as_str() < key.as_str())
+                {
+                    let pos = new_pos + bytes.len() as u64;
+                    written_tombstones.push((tombstone_key, pos, tombstone.len() as u64));
+                    bytes.extend_from_slice(&tombstone);
+                }
+                positions.push((new_pos + bytes.len() as u64, record.len() as u64));
+                bytes.extend_from_slice(&record);
+            }
+            compaction_writer.write_all(&bytes)?;
+            let pos = compaction_writer.pos;
+            handle.add_bytes(pos - new_pos);
+            throttle.consume(pos - new_pos);
+            new_pos = pos;
+
+            // Update index to point to new location
+            for ((_, cmd_pos), (pos, len)) in batch.iter_mut().zip(positions) {
+                **cmd_pos = CommandPos { gen: compaction_gen, pos, len };
+            }
+        }
+
+        // tombstones above the last live key.
+        for (tombstone_key, record) in tombstones {
+            written_tombstones.push((tombstone_key, compaction_writer.pos, record.len() as u64));
+            compaction_writer.write_all(&record)?;
+        }
+        handle.add_bytes(compaction_writer.pos - new_pos);
+        compaction_writer.flush()?;
+        Ok(written_tombstones)
+    }
+
+    /// Create a new log file with given generation number and add the reader to the readers map.
+    ///
+    /// Returns the writer to the log.
+    fn new_log_file(&mut self, gen: u64) -> Result<LogWriter> {
+        new_log_file(
+            self.storage.as_ref(),
+            &self.placement.place(gen),
+            gen,
+            &mut self.readers,
+            self.reader_buffer_size,
+            self.writer_buffer_size,
+        )
+    }
+
+    /// Deletes the log of a generation along with its hint file, if it has one.
+    fn remove_log(&mut self, gen: u64) -> Result<()> {
+        self.readers.remove(&gen);
+        let log = self.placement.log_path(gen);
+        // the hint goes first, a log left without one is replayed.
+        hint::remove(self.storage.as_ref(), &log)?;
+        self.storage.remove_file(&log)?;
+        self.placement.remove(gen);
+        Ok(())
+    }
+}
+
+impl Drop for KvStore {
+    // best effort, errors can't be reported from drop. Call `sync` to see them.
+    fn drop(&mut self) {
+        if self.killed {
+            return;
+        }
+        let _ = self.flush();
+        if self.sync_on_drop {
+            let _ = self.writer.get_ref().sync_data();
+        }
+        let _ = self.save_meta();
+    }
+}
+
+/// A committed write, see `KvStore::changes_since`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// Sequence number of the write.
+    pub sequence: u64,
+    /// The key written.
+    pub key: String,
+    /// The new value, `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// Iterator over committed writes in sequence order, see `KvStore::changes_since`.
+pub struct Changes<'a> {
+    ops: vec::IntoIter<RecordOp>,
+    readers: &'a mut HashMap<u64, LogReader>,
+    listeners: &'a Listeners,
+    key_encoding: Option<&'a dyn KeyEncoding>,
+}
+
+impl Iterator for Changes<'_> {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = self.ops.next()?;
+        let value = if op.set {
+            match read_value(self.readers, self.listeners, &op.cmd_pos) {
+                Ok(value) => Some(value),
+                Err(e) => return Some(Err(e)),
+            }
+        } else {
+            None
+        };
+        Some(Ok(Change {
+            sequence: op.sequence,
+            key: decode_key(self.key_encoding, &op.key).into_owned(),
+            value,
+        }))
+    }
+}
+
+/// How a key differs between two sequence points, see `KvStore::diff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// The key didn't exist at the first point, holds the value at the second.
+    Added {
+        /// The key.
+        key: String,
+        /// Its value at the second point.
+        value: String,
+    },
+    /// The key was written in between, holds the value at the second point.
+    Modified {
+        /// The key.
+        key: String,
+        /// Its value at the second point.
+        value: String,
+    },
+    /// The key doesn't exist at the second point.
+    Removed {
+        /// The key.
+        key: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum DiffKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// Iterator over the keys that differ between two sequence points, see `KvStore::diff`.
+pub struct Diff<'a> {
+    entries: vec::IntoIter<(String, (DiffKind, Option<CommandPos>))>,
+    readers: &'a mut HashMap<u64, LogReader>,
+    listeners: &'a Listeners,
+    key_encoding: Option<&'a dyn KeyEncoding>,
+}
+
+impl Iterator for Diff<'_> {
+    type Item = Result<DiffEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, (kind, cmd_pos)) = self.entries.next()?;
+        let key = decode_key(self.key_encoding, &key).into_owned();
+        let value = match cmd_pos.map(|cmd_pos| read_value(self.readers, self.listeners, &cmd_pos)) {
+            Some(Ok(value)) => value,
+            Some(Err(e)) => return Some(Err(e)),
+            None => String::new(),
+        };
+        Some(Ok(match kind {
+            DiffKind::Added => DiffEntry::Added { key, value },
+            DiffKind::Modified => DiffEntry::Modified { key, value },
+            DiffKind::Removed => DiffEntry::Removed { key },
+        }))
+    }
+}
+
+/// Iterator over key/value pairs in key order, see `KvStore::scan`.
+pub struct Scan<'a> {
+    entries: btree_map::Range<'a, String, CommandPos>,
+    readers: &'a mut HashMap<u64, LogReader>,
+    listeners: &'a Listeners,
+    key_encoding: Option<&'a dyn KeyEncoding>,
+    read_ahead: ReadAhead,
+    token: Option<CancellationToken>,
+    // set once the cancellation was yielded.
+    cancelled: bool,
+}
+
+impl Scan<'_> {
+    /// Stops the scan once `token` is cancelled or its deadline passed: the next item is
+    /// `Err(KvsError::Cancelled)` and the scan ends after it.
+    pub fn cancel_on(mut self, token: &CancellationToken) -> Self {
+        self.token = Some(token.clone());
+        self
+    }
+
+    /// Returns the item to yield instead of the next pair once the scan was cancelled: the
+    /// cancellation the first time, the end of the scan after that.
+    fn cancellation(&mut self) -> Option<Option<Result<(String, String)>>> {
+        if self.cancelled {
+            return Some(None);
+        }
+        if !self.token.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            return None;
+        }
+        self.cancelled = true;
+        Some(Some(Err(KvsError::Cancelled)))
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.cancellation() {
+            return item;
+        }
+        let (key, cmd_pos) = self.entries.next()?;
+        let key = decode_key(self.key_encoding, key).into_owned();
+        let value = self.read_ahead.read_value(self.readers, self.listeners, cmd_pos);
+        Some(value.map(|value| (key, value)))
+    }
+}
+
+impl DoubleEndedIterator for Scan<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.cancellation() {
+            return item;
+        }
+        let (key, cmd_pos) = self.entries.next_back()?;
+        let key = decode_key(self.key_encoding, key).into_owned();
+        Some(read_value(self.readers, self.listeners, cmd_pos).map(|value| (key, value)))
+    }
+}
+
+/// Turns a stored key back into the key, see `Options::key_encoding`.
+fn decode_key<'k>(key_encoding: Option<&dyn KeyEncoding>, key: &'k str) -> Cow<'k, str> {
+    match key_encoding {
+        Some(encoding) => Cow::Owned(encoding.decode(key)),
+        None => Cow::Borrowed(key),
+    }
+}
+
+/// Returns the range of every string starting with `prefix`.
+fn prefix_range(prefix: String) -> (Bound<String>, Bound<String>) {
+    let end = match prefix_end(&prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix), end)
+}
+
+/// Returns the smallest string above every string starting with `prefix`, if there is one.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // the next scalar value, skipping over the surrogates.
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Reads and verifies the command stored at `cmd_pos`.
+///
+/// # Errors
+///
+/// It returns `KvsError::CorruptedData` if the checksum doesn't match.
+fn read_command(
+    readers: &mut HashMap<u64, LogReader>,
+    listeners: &Listeners,
+    cmd_pos: &CommandPos,
+) -> Result<KvsCommand> {
+    let reader = readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
+    decode_command(reader.read_message(cmd_pos.pos)?, listeners, cmd_pos)
+}
+
+/// Decodes and verifies the message of the record at `cmd_pos`.
+fn decode_command(msg_bytes: &[u8], listeners: &Listeners, cmd_pos: &CommandPos) -> Result<KvsCommand> {
+    let cmd = KvsCommand::decode(msg_bytes).inspect_err(|_| {
+        listeners.on_corruption_detected(cmd_pos.gen, cmd_pos.pos);
+    })?;
+    if !cmd.verify_checksum() {
+        listeners.on_corruption_detected(cmd_pos.gen, cmd_pos.pos);
+        return Err(KvsError::CorruptedData);
+    }
+    Ok(cmd)
+}
+
+/// Reads the value of the set or rename command stored at `cmd_pos`.
+///
+/// # Errors
+///
+/// It returns `KvsError::UnexpectedCommandType` if the command there sets no value.
+fn read_value(
+    readers: &mut HashMap<u64, LogReader>,
+    listeners: &Listeners,
+    cmd_pos: &CommandPos,
+) -> Result<String> {
+    command_value(read_command(readers, listeners, cmd_pos)?)
+}
+
+/// Reads the value of the set or rename command stored at `cmd_pos` like `read_value`,
+/// without verifying its checksum.
+fn read_value_unverified(
+    readers: &mut HashMap<u64, LogReader>,
+    listeners: &Listeners,
+    cmd_pos: &CommandPos,
+) -> Result<String> {
+    let reader = readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
+    let cmd = KvsCommand::decode(reader.read_message(cmd_pos.pos)?).inspect_err(|_| {
+        listeners.on_corruption_detected(cmd_pos.gen, cmd_pos.pos);
+    })?;
+    command_value(cmd)
+}
+
+/// Reads the value of the set or rename command stored at `cmd_pos` as a slice of the
+/// record, see `KvStore::get_bytes`. The checksum is verified if `verify` is set.
+///
+/// # Errors
+///
+/// It returns `KvsError::UnexpectedCommandType` if the record is not a set or rename.
+fn read_value_bytes(
+    readers: &mut HashMap<u64, LogReader>,
+    listeners: &Listeners,
+    cmd_pos: &CommandPos,
+    verify: bool,
+) -> Result<Bytes> {
+    let reader = readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
+    let msg_bytes = reader.read_message_bytes(cmd_pos.pos)?;
+    let view = ValueView::decode(msg_bytes).inspect_err(|_| {
+        listeners.on_corruption_detected(cmd_pos.gen, cmd_pos.pos);
+    })?;
+    let (fields, value) = match (view.set, view.rename) {
+        (Some(set), None) => ([set.key, Bytes::new()], set.value),
+        (None, Some(rename)) => ([rename.old_key, rename.new_key], rename.value),
+        _ => return Err(KvsError::UnexpectedCommandType),
+    };
+    if !verify {
+        return Ok(value);
+    }
+    // the same checksum as `Checksumable`, over the fields in place.
+    let mut hasher = Hasher::new();
+    fields.iter().for_each(|field| hasher.update(field));
+    hasher.update(&value);
+    if hasher.finalize() != view.checksum {
+        listeners.on_corruption_detected(cmd_pos.gen, cmd_pos.pos);
+        return Err(KvsError::CorruptedData);
+    }
+    Ok(value)
+}
+
+/// The fields of a set or rename record `read_value_bytes` needs, decoded from the same
+/// wire format as `KvsCommand` but into `Bytes` slices of the record instead of copies.
+#[derive(Clone, PartialEq, Message)]
+struct ValueView {
+    #[prost(uint32, tag = "3")]
+    checksum: u32,
+    #[prost(message, optional, tag = "5")]
+    set: Option<SetView>,
+    #[prost(message, optional, tag = "7")]
+    rename: Option<RenameView>,
+}
+
+/// `KvsSet` with `Bytes` fields, see `ValueView`.
+#[derive(Clone, PartialEq, Message)]
+struct SetView {
+    #[prost(bytes = "bytes", tag = "1")]
+    key: Bytes,
+    #[prost(bytes = "bytes", tag = "2")]
+    value: Bytes,
+}
+
+/// `KvsRename` with `Bytes` fields, see `ValueView`.
+#[derive(Clone, PartialEq, Message)]
+struct RenameView {
+    #[prost(bytes = "bytes", tag = "1")]
+    old_key: Bytes,
+    #[prost(bytes = "bytes", tag = "2")]
+    new_key: Bytes,
+    #[prost(bytes = "bytes", tag = "3")]
+    value: Bytes,
+}
+
+/// Returns the value of a set or rename command.
+fn command_value(cmd: KvsCommand) -> Result<String> {
+    match cmd.command {
+        Some(kvs_command::Command::Set(set)) => Ok(set.value),
+        Some(kvs_command::Command::Rename(rename)) => Ok(rename.value),
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Writes an export stream, see `KvStore::export_with`.
+struct ExportWriter<W: Write> {
+    writer: W,
+    pos: u64,
+    // key, position and length of every record written.
+    records: Vec<(String, u64, u64)>,
+}
+
+impl<W: Write> ExportWriter<W> {
+    fn new(writer: W) -> ExportWriter<W> {
+        ExportWriter {
+            writer,
+            pos: 0,
+            records: Vec::new(),
+        }
+    }
+
+    /// Appends a set record of the pair, numbered by its place in the stream.
+    fn write_pair(&mut self, key: String, value: String) -> Result<()> {
+        let sequence = self.records.len() as u64 + 1;
+        let cmd_bytes = KvsCommand::set(key.clone(), value, sequence).encode_to_vec();
+        self.writer.write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&cmd_bytes)?;
+        let len = 4 + cmd_bytes.len() as u64;
+        self.records.push((key, self.pos, len));
+        self.pos += len;
+        Ok(())
+    }
+
+    /// Ends the stream with its footer and flushes it, returning the number of pairs.
+    fn finish(mut self) -> Result<u64> {
+        let footer = SegmentFooter::new(
+            self.records.iter().map(|(key, pos, len)| (key.as_str(), *pos, *len)),
+        );
+        footer.write(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.records.len() as u64)
+    }
+}
+
+/// Writes `pairs` to `writer` as an export stream, returning the number of pairs.
+fn write_export(
+    pairs: impl Iterator<Item = Result<(String, String)>>,
+    writer: impl Write,
+) -> Result<u64> {
+    let mut export = ExportWriter::new(BufWriter::new(writer));
+    for pair in pairs {
+        let (key, value) = pair?;
+        export.write_pair(key, value)?;
+    }
+    export.finish()
+}
+
+/// Bytes of an export footer on top of the keys it holds, see `ExportReader::read_footer`.
+const MAX_EXPORT_FOOTER_OVERHEAD: u64 = 64 * 1024;
+
+/// Reads the pairs of an export stream back, verifying every record and the footer, see
+/// `KvStore::restore_from_stream`.
+struct ExportReader<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    // number and bytes of the records read so far.
+    records: u64,
+    records_len: u64,
+    // set once the footer or an error was read.
+    done: bool,
+}
+
+impl<R: Read> ExportReader<R> {
+    fn new(reader: R) -> ExportReader<R> {
+        ExportReader {
+            reader,
+            buf: Vec::new(),
+            records: 0,
+            records_len: 0,
+            done: false,
+        }
+    }
+
+    /// Reads the next pair, `None` once the footer was read and matches the records.
+    fn read_pair(&mut self) -> Result<Option<(String, String)>> {
+        let mut len_bytes = [0u8; 4];
+        self.reader.read_exact(&mut len_bytes)?;
+        let len = u32::from_le_bytes(len_bytes);
+        if len == FOOTER_MARKER {
+            return self.read_footer().map(|()| None);
+        }
+        // the length comes from outside, the buffer only grows with the bytes actually read.
+        self.buf.clear();
+        (&mut self.reader).take(len as u64).read_to_end(&mut self.buf)?;
+        if self.buf.len() < len as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.records_len += 4 + len as u64;
+        let cmd = KvsCommand::decode(self.buf.as_slice())?;
+        if !cmd.verify_checksum() {
+            return Err(KvsError::CorruptedData);
+        }
+        match cmd.command {
+            Some(kvs_command::Command::Set(set)) => {
+                self.records += 1;
+                Ok(Some((set.key, set.value)))
+            }
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Reads the footer after its marker and checks it against the records read.
+    ///
+    /// A footer holds a key of at most every `SPARSE_INDEX_INTERVAL` bytes of records, so it
+    /// is never much bigger than the records even with every character escaped. Reads past
+    /// that bound count as corruption.
+    fn read_footer(&mut self) -> Result<()> {
+        let max_len = MAX_EXPORT_FOOTER_OVERHEAD + 6 * self.records_len;
+        let mut rest = Vec::new();
+        (&mut self.reader).take(max_len + 1).read_to_end(&mut rest)?;
+        if rest.len() as u64 > max_len {
+            return Err(KvsError::CorruptedData);
+        }
+        // the footer, its length and the magic.
+        let Some(footer_len) = rest.len().checked_sub(8 + FOOTER_MAGIC.len()) else {
+            return Err(KvsError::CorruptedData);
+        };
+        let len_bytes = rest[footer_len..footer_len + 8].try_into().unwrap();
+        if !rest.ends_with(FOOTER_MAGIC) || u64::from_le_bytes(len_bytes) != footer_len as u64 {
+            return Err(KvsError::CorruptedData);
+        }
+        let footer: SegmentFooter = serde_json::from_slice(&rest[..footer_len])?;
+        if footer.records != self.records {
+            return Err(KvsError::CorruptedData);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for ExportReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let pair = self.read_pair().transpose();
+        self.done = !matches!(pair, Some(Ok(_)));
+        pair
+    }
+}
+
+/// Bytes a forward scan reads at once from a log once it reads consecutive records of it.
+const SCAN_READ_AHEAD: u64 = 256 * 1024;
+
+/// A chunk of one log read ahead by a forward scan.
+///
+/// Compacted and bulk-ingested logs are sorted by key, so a scan over them reads record
+/// after record. Reading those in chunks takes one seek and read per chunk instead of per
+/// record. Records out of order are read one by one as before.
+#[derive(Default)]
+struct ReadAhead {
+    gen: u64,
+    // log position of the first byte of `bytes`.
+    start: u64,
+    bytes: Vec<u8>,
+    // generation and end of the record read last.
+    last_end: Option<(u64, u64)>,
+}
+
+impl ReadAhead {
+    /// Reads the value at `cmd_pos` like `read_value`, from the chunk when it holds the
+    /// record or the record follows the one read before.
+    fn read_value(
+        &mut self,
+        readers: &mut HashMap<u64, LogReader>,
+        listeners: &Listeners,
+        cmd_pos: &CommandPos,
+    ) -> Result<String> {
+        let sequential = self.last_end == Some((cmd_pos.gen, cmd_pos.pos));
+        self.last_end = Some((cmd_pos.gen, cmd_pos.pos + cmd_pos.len));
+        if !self.holds(cmd_pos) {
+            if !sequential {
+                return read_value(readers, listeners, cmd_pos);
+            }
+            let reader = readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            self.bytes.clear();
+            reader.take(max(SCAN_READ_AHEAD, cmd_pos.len)).read_to_end(&mut self.bytes)?;
+            self.gen = cmd_pos.gen;
+            self.start = cmd_pos.pos;
+            if !self.holds(cmd_pos) {
+                // the log is shorter than the index says, fail like a plain read.
+                return read_value(readers, listeners, cmd_pos);
+            }
+        }
+
+        let offset = (cmd_pos.pos - self.start) as usize;
+        let record = &self.bytes[offset..offset + cmd_pos.len as usize];
+        let msg_len = u32::from_le_bytes(record[..4].try_into().unwrap()) as usize;
+        match record.get(4..4 + msg_len) {
+            Some(msg_bytes) => command_value(decode_command(msg_bytes, listeners, cmd_pos)?),
+            None => Err(KvsError::CorruptedData),
+        }
+    }
+
+    fn holds(&self, cmd_pos: &CommandPos) -> bool {
+        self.gen == cmd_pos.gen
+            && cmd_pos.pos >= self.start
+            && cmd_pos.pos + cmd_pos.len <= self.start + self.bytes.len() as u64
+    }
+}
+
+/// Create a new log file at `path` for the given generation number and add the reader to the
+/// readers map.
+///
+/// Returns the writer to the log.
+fn new_log_file(
+    storage: &dyn Storage,
+    path: &Path,
+    gen: u64,
+    readers: &mut HashMap<u64, LogReader>,
+    reader_buffer_size: usize,
+    writer_buffer_size: usize,
+) -> Result<LogWriter> {
+    let writer = open_log_writer(storage, path, writer_buffer_size)?;
+    readers.insert(gen, BufReaderWithPos::new(storage.open_reader(path)?, reader_buffer_size)?);
+    Ok(writer)
+}
 
-            // Prefix
-            let mut len_bytes = [0u8; 4];
-            reader.read_exact(&mut len_bytes)?;
-            let msg_len = u32::from_le_bytes(len_bytes) as usize;
+/// Opens a log for appending.
+///
+/// With the `io-uring` feature, writes to local files are submitted through a ring.
+fn open_log_writer(storage: &dyn Storage, path: &Path, buffer_size: usize) -> Result<LogWriter> {
+    let writer = storage.open_writer(path)?;
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    let writer = uring::RingWriter::wrap(writer);
+    BufWriterWithPos::new(writer, buffer_size)
+}
 
-            // Read message
-            let mut msg_bytes = vec![0; msg_len];
-            reader.read_exact(&mut msg_bytes)?;
+/// Creates the recency of cache mode for the live keys, ranked by where their record sits in
+/// the logs.
+fn new_lru(index: &BTreeMap<String, CommandPos>, budget: u64) -> Lru {
+    let mut by_position: Vec<_> = index.iter().collect();
+    by_position.sort_unstable_by_key(|(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+    let mut lru = Lru::new(budget);
+    for (key, cmd_pos) in by_position {
+        lru.insert(key, cmd_pos.len);
+    }
+    lru
+}
 
-            let cmd = KvsCommand::decode(&msg_bytes[..])?;
-            if !cmd.verify_checksum() {
-                return Err(KvsError::CorruptedData);
-            }
+/// Removes or quarantines leftovers of interrupted operations, returning their paths.
+///
+/// Only files the store itself creates are touched, anything else in the directories is left
+/// alone. Quarantined files move to a `quarantine` subdirectory of the directory they are in.
+fn clean_orphans(
+    storage: &dyn Storage,
+    dirs: &[PathBuf],
+    unfinished_compaction: Option<PathBuf>,
+    delete: bool,
+) -> Result<Vec<PathBuf>> {
+    // a bulk ingest may have written the hint of its log already.
+    let unfinished_hint = unfinished_compaction.as_deref().map(hint::path);
+    let mut orphans = Vec::new();
+    for dir in dirs {
+        orphans.extend(storage.list_files(dir)?.into_iter().filter(|file| {
+            Some(file) == unfinished_compaction.as_ref()
+                || Some(file) == unfinished_hint.as_ref()
+                || file.extension() == Some(TMP_EXTENSION.as_ref())
+                || file.extension() == Some("fetch".as_ref())
+        }));
+    }
+    orphans.sort_unstable();
 
-            if let Some(command) = cmd.command {
-                if let kvs_command::Command::Set(set) = command {
-                    Ok(Some(set.value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
-                }
-            } else {
-                Ok(None)
-            }
+    for orphan in &orphans {
+        if delete {
+            storage.remove_file(orphan)?;
         } else {
-            Ok(None)
+            let quarantine = orphan.parent().expect("listed files have a parent").join("quarantine");
+            storage.create_dir_all(&quarantine)?;
+            let name = orphan.file_name().expect("listed files have a name");
+            storage.rename(orphan, &quarantine.join(name))?;
         }
     }
+    Ok(orphans)
+}
 
-    /// Removes a given key.
-    ///
-    /// # Errors
-    ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    ///
-    /// It propagates I/O or serialization errors during writing the log.
-    pub fn remove_v2(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-
-            let sequence = self.current_sequence.unwrap_or(0) + 1;
-            self.current_sequence = Some(sequence);
+/// Copies the records replay skipped to the `corrupt` subdirectory of the store.
+///
+/// A record goes to `<generation>-<offset>.bin` as it was in the log, next to a `.json`
+/// file saying where it came from. Records found again on a later open are rewritten.
+fn quarantine_corrupted(
+    storage: &dyn Storage,
+    dir: &Path,
+    placement: &Placement,
+    partials: &[PartialIndex],
+) -> Result<Vec<CorruptedRecord>> {
+    let corrupt_dir = dir.join(CORRUPT_DIR);
+    let mut records = Vec::new();
+    for partial in partials {
+        for (offset, bytes) in &partial.corrupted {
+            storage.create_dir_all(&corrupt_dir)?;
+            let name = format!("{}-{}", partial.gen, offset);
+            let path = corrupt_dir.join(format!("{}.bin", name));
+            meta::write_atomically(storage, &path, bytes)?;
+            let description = serde_json::json!({
+                "generation": partial.gen,
+                "offset": offset,
+                "len": bytes.len(),
+                "log": placement.log_path(partial.gen),
+            });
+            let description_path = corrupt_dir.join(format!("{}.json", name));
+            meta::write_atomically(storage, &description_path, &serde_json::to_vec_pretty(&description)?)?;
+            records.push(CorruptedRecord {
+                generation: partial.gen,
+                offset: *offset,
+                len: bytes.len() as u64,
+                path,
+            });
+        }
+    }
+    records.sort_unstable_by_key(|record| (record.generation, record.offset));
+    Ok(records)
+}
 
-            let cmd = KvsCommand::remove(key, sequence);
+/// The latest operation on a key seen while replaying a generation.
+enum Replayed {
+    Set { sequence: u64, cmd_pos: CommandPos },
+    Remove { sequence: u64 },
+}
 
-            let cmd_bytes = cmd.encode_to_vec();
+impl Replayed {
+    fn sequence(&self) -> u64 {
+        match self {
+            Replayed::Set { sequence, .. } | Replayed::Remove { sequence } => *sequence,
+        }
+    }
+}
 
-            // Write length prefix (4 bytes, little endian)
-            self.writer.write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+/// The index recovered from a single generation.
+///
+/// Removes are kept so they can shadow sets replayed from older generations.
+struct PartialIndex {
+    gen: u64,
+    entries: HashMap<String, Replayed>,
+    // bytes already known to be stale within the generation.
+    uncompacted: u64,
+    highest_sequence: u64,
+    // sequence number and key checksum of every record, in log order.
+    sequences: Vec<(u64, u32)>,
+    // whether every record's key sorts after the previous one's, like compaction output.
+    key_sorted: bool,
+    // whether the log ends with a footer, like compaction, merge and bulk ingest output.
+    sealed: bool,
+    // position and bytes of the records skipped as corrupted.
+    corrupted: Vec<(u64, Vec<u8>)>,
+}
 
-            // Write actual message
-            self.writer.write_all(&cmd_bytes)?;
-            self.writer.flush()?;
+/// Replays every generation on a pool of scoped worker threads.
+///
+/// Workers pull generations off a shared queue, so one huge generation doesn't hold
+/// back the rest.
+fn replay_all(
+    readers: &mut HashMap<u64, LogReader>,
+    skip: &HashSet<u64>,
+    progress: &Progress,
+    listeners: &Listeners,
+    skip_corrupted: bool,
+) -> Result<Vec<PartialIndex>> {
+    let workers = thread::available_parallelism()
+        .map_or(1, usize::from)
+        .min(readers.len())
+        .max(1);
+    let jobs = Mutex::new(readers.iter_mut().filter(|(gen, _)| !skip.contains(gen)));
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let job = jobs.lock().unwrap().next();
+                let Some((&gen, reader)) = job else { break };
+                progress.start_generation(gen);
+                let partial = replay(gen, reader, progress, listeners, skip_corrupted);
+                results.lock().unwrap().push(partial);
+            });
+        }
+    });
 
-            if let Some(command) = cmd.command {
-                if let kvs_command::Command::Remove(remove) = command {
-                    if let Some(old_cmd) = self.index.remove(&remove.key) {
-                        // The remove command itself will be deleted in compaction
-                        // once a key is removed, both the original set command and the remove command become "stale"
-                        // and can be eliminated during compaction.
-                        self.uncompacted += old_cmd.len;
-                    }
-                }
-            }
+    results.into_inner().unwrap().into_iter().collect()
+}
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
+/// Indexes the generations whose log has a hint file written for it, like bulk ingest
+/// output, from the hint alone.
+///
+/// The records of these logs are not read, so they are only checked once a get reads them.
+fn load_hints(
+    storage: &dyn Storage,
+    placement: &Placement,
+    gens: &[u64],
+    progress: &Progress,
+) -> Result<Vec<PartialIndex>> {
+    let mut partials = Vec::new();
+    for &gen in gens {
+        progress.check_cancelled()?;
+        let log = placement.log_path(gen);
+        let Some(hint) = Hint::read(storage, &log)? else {
+            continue;
+        };
+        let log_len = storage.file_len(&log)?;
+        if hint.log_len != log_len {
+            continue;
+        }
+        progress.start_generation(gen);
+        progress.add_bytes(log_len);
 
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+        let mut entries = HashMap::new();
+        let mut uncompacted = 0;
+        let mut sequences = Vec::with_capacity(hint.records.len());
+        let key_sorted = hint.records.windows(2).all(|pair| pair[0].key < pair[1].key);
+        for HintRecord { key, pos, len, sequence } in hint.records {
+            sequences.push((sequence, crc32fast::hash(key.as_bytes())));
+            let cmd_pos = CommandPos { gen, pos, len };
+            uncompacted += replay_entry(&mut entries, key, Replayed::Set { sequence, cmd_pos });
         }
+        partials.push(PartialIndex {
+            gen,
+            entries,
+            uncompacted,
+            highest_sequence: sequences.iter().map(|&(sequence, _)| sequence).max().unwrap_or(0),
+            sequences,
+            key_sorted,
+            sealed: true,
+            corrupted: Vec::new(),
+        });
     }
+    Ok(partials)
+}
 
-    /// Clears stale entries in the log. And rewrites latest values in a new log file
-    pub fn compact(&mut self) -> Result<()> {
-        println!("Debug: Starting compaction. Current size: {}", self.uncompacted);
-
-        // increase current gen by 2. current_gen + 1 is for the compaction file.
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
-
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+/// Looks for sequence numbers lost or reused across the replayed generations.
+///
+/// A log continues the sequences of the adjacent log before it if it only holds newer
+/// ones, like appended logs and bulk ingest output, so a gap is also looked for at their
+/// boundary, such as the lost tail of the first. Compaction and merge output copies older
+/// records and drops the overwritten ones, so no gaps are looked for in it and the chain
+/// starts over at the next appended log.
+fn check_sequences(partials: &[PartialIndex]) -> RecoveryReport {
+    let mut partials: Vec<&PartialIndex> = partials.iter().collect();
+    partials.sort_unstable_by_key(|partial| partial.gen);
+
+    let mut report = RecoveryReport::default();
+    // key checksum per sequence, compaction copies records with the same key.
+    let mut seen: HashMap<u64, u32> = HashMap::new();
+    // generation and highest sequence of the last log in the chain.
+    let mut tail: Option<(u64, u64)> = None;
+    for partial in partials {
+        let mut in_gen = HashSet::new();
+        let mut previous = None;
+        for &(sequence, key_crc) in &partial.sequences {
+            let reused = !in_gen.insert(sequence)
+                || matches!(seen.insert(sequence, key_crc), Some(crc) if crc != key_crc);
+            if reused {
+                report.duplicates.push(sequence);
+            }
+            match previous {
+                Some(previous) if !partial.key_sorted && sequence > previous + 1 => {
+                    report.gaps.push(SequenceGap {
+                        generation: partial.gen,
+                        missing: previous + 1..=sequence - 1,
+                    });
+                }
+                _ => {}
+            }
+            previous = Some(sequence);
+        }
 
-        let mut new_pos = 0; // pos in the new log file.
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut sequences: Vec<u64> = partial.sequences.iter().map(|&(sequence, _)| sequence).collect();
+        let appended = !partial.sealed && sequences.is_sorted();
+        sequences.sort_unstable();
+        sequences.dedup();
+        let chained = tail.filter(|&(gen, _)| gen + 1 == partial.gen).map(|(_, sequence)| sequence);
+        tail = match (chained, sequences.first(), sequences.last()) {
+            (Some(last), None, _) => Some((partial.gen, last)),
+            (Some(last), Some(&first), Some(&highest)) if first > last => {
+                // the gaps within logs not in key order were found above.
+                let checked = if partial.key_sorted { &sequences[..] } else { &sequences[..1] };
+                let mut last = last;
+                for &sequence in checked {
+                    if sequence > last + 1 {
+                        report.gaps.push(SequenceGap {
+                            generation: partial.gen,
+                            missing: last + 1..=sequence - 1,
+                        });
+                    }
+                    last = sequence;
+                }
+                Some((partial.gen, highest))
             }
+            (None, _, Some(&highest)) if appended => Some((partial.gen, highest)),
+            _ => None,
+        };
+    }
+    report.duplicates.sort_unstable();
+    report.duplicates.dedup();
+    report
+}
 
-            // Read length prefix
-            let mut len_bytes = [0u8; 4];
-            reader.read_exact(&mut len_bytes)?;
-            let msg_len = u32::from_le_bytes(len_bytes) as usize;
+/// Merges partial indexes by sequence number, newest operation per key wins.
+///
+/// Returns the index, how many bytes can be saved after a compaction and the highest sequence.
+fn merge_partial_indexes(partials: Vec<PartialIndex>) -> (BTreeMap<String, CommandPos>, u64, u64) {
+    let mut latest: HashMap<String, Replayed> = HashMap::new();
+    let mut uncompacted = 0;
+    let mut highest_sequence = 0;
 
-            // Read the message
-            let mut msg_bytes = vec![0; msg_len];
-            reader.read_exact(&mut msg_bytes)?;
+    for partial in partials {
+        uncompacted += partial.uncompacted;
+        highest_sequence = max(highest_sequence, partial.highest_sequence);
 
-            // Write length prefix to compaction file
-            compaction_writer.write_all(&len_bytes)?;
+        for (key, replayed) in partial.entries {
+            uncompacted += replay_entry(&mut latest, key, replayed);
+        }
+    }
 
-            // Write message bytes to compaction file
-            compaction_writer.write_all(&msg_bytes)?;
+    let index = latest
+        .into_iter()
+        .filter_map(|(key, replayed)| match replayed {
+            Replayed::Set { cmd_pos, .. } => Some((key, cmd_pos)),
+            Replayed::Remove { .. } => None,
+        })
+        .collect();
+    (index, uncompacted, highest_sequence)
+}
 
-            // Update index to point to new location
-            *cmd_pos = CommandPos { gen: compaction_gen, pos: new_pos, len: 4 + msg_len as u64 };
-            new_pos += 4 + msg_len as u64;
+/// Records `replayed` as the operation on `key` unless a newer one was replayed already.
+///
+/// Returns the length of the set record that became stale. Remove records are counted as
+/// stale by the generation that holds them.
+fn replay_entry(entries: &mut HashMap<String, Replayed>, key: String, replayed: Replayed) -> u64 {
+    match entries.entry(key) {
+        hash_map::Entry::Vacant(slot) => {
+            slot.insert(replayed);
+            0
         }
-        compaction_writer.flush()?;
-
-        // remove stale log files.
-        let stale_gens: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+        hash_map::Entry::Occupied(mut slot) => {
+            let stale = if replayed.sequence() > slot.get().sequence() {
+                slot.insert(replayed)
+            } else {
+                replayed
+            };
+            match stale {
+                Replayed::Set { cmd_pos, .. } => cmd_pos.len,
+                Replayed::Remove { .. } => 0,
+            }
         }
-        self.uncompacted = 0;
-
-        Ok(())
     }
+}
 
-    /// Create a new log file with given generation number and add the reader to the readers map.
-    ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers, self.writer_buffer_size, self.reader_buffer_size)
-    }
+/// Replays one generation, see `load_v2`.
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+fn replay(
+    gen: u64,
+    reader: &mut LogReader,
+    progress: &Progress,
+    listeners: &Listeners,
+    skip_corrupted: bool,
+) -> Result<PartialIndex> {
+    load_v2(gen, reader, progress, listeners, skip_corrupted)
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Replays one generation, see `load_v2`.
 ///
-/// Returns the writer to the log.
-fn new_log_file(
-    path: &Path,
+/// Local files are read with a bounded window of io_uring reads kept in flight ahead of the
+/// decoding.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn replay(
     gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-    reader_buffer_size: usize,
-    writer_buffer_size: usize,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-        writer_buffer_size,
-    )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?, reader_buffer_size)?);
-    Ok(writer)
+    reader: &mut LogReader,
+    progress: &Progress,
+    listeners: &Listeners,
+    skip_corrupted: bool,
+) -> Result<PartialIndex> {
+    match reader.get_ref().as_file() {
+        Some(file) => {
+            let mut chunks = uring::ChunkedReader::new(file)?;
+            load_v2(gen, &mut chunks, progress, listeners, skip_corrupted)
+        }
+        None => load_v2(gen, reader, progress, listeners, skip_corrupted),
+    }
 }
 
-/// Returns sorted generation numbers in the given directory.
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
-    gen_list.sort_unstable();
-    Ok(gen_list)
+/// A write to one key found in the logs, a rename is split into two.
+#[derive(Clone, Debug)]
+struct RecordOp {
+    sequence: u64,
+    // seconds since the Unix epoch.
+    timestamp: u64,
+    // stored form of the key.
+    key: String,
+    // whether the key was set rather than removed.
+    set: bool,
+    cmd_pos: CommandPos,
+}
+
+/// Encodes a remove of the record op as a length-prefixed record, keeping its sequence and
+/// timestamp.
+fn tombstone_record(op: &RecordOp) -> Vec<u8> {
+    let cmd = KvsCommand { timestamp: op.timestamp, ..KvsCommand::remove(op.key.clone(), op.sequence) };
+    let cmd_bytes = cmd.encode_to_vec();
+    let mut record = (cmd_bytes.len() as u32).to_le_bytes().to_vec();
+    record.extend(cmd_bytes);
+    record
+}
+
+/// What `write_merged_log` wrote.
+struct MergedLog {
+    // stored key and new position of every live record.
+    relocated: Vec<(String, CommandPos)>,
+    // bytes of the stale records in the merged logs.
+    stale_bytes: u64,
+    // bytes of the removes written, stale again.
+    remove_bytes: u64,
+}
+
+/// Returns the writes of every record of a generation, in log order.
+fn record_ops(gen: u64, reader: &mut LogReader) -> Result<Vec<RecordOp>> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut ops = Vec::new();
+    let mut msg_bytes = Vec::new();
+    loop {
+        let mut len_bytes = [0u8; 4];
+        match reader.read_exact(&mut len_bytes) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        if u32::from_le_bytes(len_bytes) == FOOTER_MARKER {
+            break;
+        }
+        let msg_len = u32::from_le_bytes(len_bytes) as u64;
+        if msg_len > file_len - pos - 4 {
+            return Err(KvsError::CorruptedData);
+        }
+        read_into(reader, &mut msg_bytes, msg_len as usize)?;
+
+        let cmd = KvsCommand::decode(&msg_bytes[..])?;
+        let sequence = cmd.sequence_number;
+        let timestamp = cmd.timestamp;
+        let cmd_pos = CommandPos { gen, pos, len: 4 + msg_len };
+        pos += 4 + msg_len;
+        match cmd.command {
+            Some(kvs_command::Command::Set(set)) => {
+                ops.push(RecordOp { sequence, timestamp, key: set.key, set: true, cmd_pos });
+            }
+            Some(kvs_command::Command::Remove(remove)) => {
+                ops.push(RecordOp { sequence, timestamp, key: remove.key, set: false, cmd_pos });
+            }
+            Some(kvs_command::Command::Rename(rename)) => {
+                let old_key = rename.old_key;
+                let old_pos = cmd_pos.clone();
+                ops.push(RecordOp { sequence, timestamp, key: old_key, set: false, cmd_pos: old_pos });
+                ops.push(RecordOp { sequence, timestamp, key: rename.new_key, set: true, cmd_pos });
+            }
+            None => return Err(KvsError::UnexpectedCommandType),
+        }
+    }
+    Ok(ops)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Load the whole log file and store value locations in a partial index.
 ///
-/// Returns how many bytes can be saved after a compaction.
-fn load_v2(
+/// Replayed bytes are reported to `progress`, and the replay stops once it is cancelled.
+/// With `skip_corrupted` records that don't decode are kept in `PartialIndex::corrupted`
+/// instead of failing the replay, and so is a torn tail.
+fn load_v2<R: Read + Seek>(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<(u64, u64)> {
+    reader: &mut R,
+    progress: &Progress,
+    listeners: &Listeners,
+    skip_corrupted: bool,
+) -> Result<PartialIndex> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
     let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut corrupted = Vec::new();
+    let mut entries = HashMap::new();
     let mut uncompacted = 0;
     let mut highest_sequence = 0;
+    let mut sequences = Vec::new();
+    let mut key_sorted = true;
+    let mut sealed = false;
+    let mut previous_key: Option<String> = None;
+    let mut msg_bytes = Vec::new();
 
     loop {
+        progress.check_cancelled()?;
         let start_pos = pos;
 
         // Read the message length (4 bytes) prefix:
@@ -357,52 +3333,97 @@ fn load_v2(
         match reader.read_exact(&mut len_bytes) {
             Ok(_) => (),
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                // reached eof
+                // reached eof, a partial prefix is a torn write.
+                if skip_corrupted && pos < file_len {
+                    listeners.on_corruption_detected(gen, start_pos);
+                    corrupted.push((start_pos, read_rest(reader, start_pos)?));
+                    progress.add_bytes(file_len - start_pos);
+                }
                 break;
             },
             Err(e) => return Err(e.into()),
         }
+        if u32::from_le_bytes(len_bytes) == FOOTER_MARKER {
+            // the footer only summarizes the records.
+            sealed = true;
+            progress.add_bytes(reader.seek(SeekFrom::End(0))? - start_pos);
+            break;
+        }
 
         let msg_len = u32::from_le_bytes(len_bytes) as usize;
         pos += 4;
+        if msg_len as u64 > file_len - pos {
+            // a torn or corrupted prefix, don't allocate what it claims.
+            listeners.on_corruption_detected(gen, start_pos);
+            if !skip_corrupted {
+                return Err(KvsError::CorruptedData);
+            }
+            // the records after it can't be found, keep the rest of the log.
+            corrupted.push((start_pos, read_rest(reader, start_pos)?));
+            progress.add_bytes(file_len - start_pos);
+            break;
+        }
 
         // Read message bytes
-        let mut msg_bytes = vec![0u8; msg_len];
-        reader.read_exact(&mut msg_bytes)?;
+        read_into(reader, &mut msg_bytes, msg_len)?;
         pos += msg_len as u64;
 
         // Deserialize the protobuf message
-        let cmd = match KvsCommand::decode(&msg_bytes[..]) {
+        let decoded = match KvsCommand::decode(&msg_bytes[..]) {
+            Ok(cmd) if cmd.verify_checksum() => Ok(cmd),
+            Ok(_) => Err(KvsError::CorruptedData),
+            Err(e) => Err(KvsError::Deserialize(e)),
+        };
+        progress.add_bytes(pos - start_pos);
+        let cmd = match decoded {
             Ok(cmd) => cmd,
-            Err(e) => return Err(KvsError::Deserialize(e))
+            Err(e) => {
+                listeners.on_corruption_detected(gen, start_pos);
+                if !skip_corrupted {
+                    return Err(e);
+                }
+                let mut record = len_bytes.to_vec();
+                record.extend_from_slice(&msg_bytes);
+                corrupted.push((start_pos, record));
+                continue;
+            }
         };
 
-        if !cmd.verify_checksum() {
-            return Err(KvsError::CorruptedData);
+        let sequence = cmd.sequence_number;
+        highest_sequence = max(highest_sequence, sequence);
+        let key = match &cmd.command {
+            Some(kvs_command::Command::Set(set)) => &set.key,
+            Some(kvs_command::Command::Remove(remove)) => &remove.key,
+            // compaction copies a rename at the position of the key it sets.
+            Some(kvs_command::Command::Rename(rename)) => &rename.new_key,
+            None => return Err(KvsError::UnexpectedCommandType),
+        };
+        sequences.push((sequence, crc32fast::hash(key.as_bytes())));
+        if previous_key.as_ref().is_some_and(|previous| key <= previous) {
+            key_sorted = false;
         }
+        previous_key = Some(key.clone());
 
-        highest_sequence = max(highest_sequence, cmd.sequence_number);
+        let cmd_pos = CommandPos {
+            gen,
+            pos: start_pos,
+            len: pos - start_pos,
+        };
+        // compaction output is in key order, so a newer record of a key can come first.
         match cmd.command {
             Some(kvs_command::Command::Set(set)) => {
-                let key = set.key;
-                let new_pos = CommandPos {
-                    gen,
-                    pos: start_pos,
-                    len: pos - start_pos,
-                };
-
-                if let Some(old_cmd) = index.insert(key, new_pos){
-                    uncompacted += old_cmd.len;
-                }
+                uncompacted += replay_entry(&mut entries, set.key, Replayed::Set { sequence, cmd_pos });
             }
 
             Some(kvs_command::Command::Remove(remove)) => {
-                let key = remove.key;
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
-                }
+                uncompacted += replay_entry(&mut entries, remove.key, Replayed::Remove { sequence });
                 // The remove command itself can be deleted in compaction
-                uncompacted += pos - start_pos;
+                uncompacted += cmd_pos.len;
+            }
+
+            Some(kvs_command::Command::Rename(rename)) => {
+                uncompacted += replay_entry(&mut entries, rename.new_key, Replayed::Set { sequence, cmd_pos });
+                uncompacted += replay_entry(&mut entries, rename.old_key, Replayed::Remove { sequence });
             }
             None => {
                 return Err(KvsError::UnexpectedCommandType);
@@ -410,11 +3431,57 @@ fn load_v2(
         }
     }
 
-    Ok((uncompacted, highest_sequence))
+    Ok(PartialIndex {
+        gen,
+        entries,
+        uncompacted,
+        highest_sequence,
+        sequences,
+        key_sorted,
+        sealed,
+        corrupted,
+    })
+}
+
+/// Counts the records of a log, stopping at the first one that runs past the end or fails
+/// its checksum like replay does, so a torn tail isn't counted.
+fn count_records(reader: &mut BufReader<Box<dyn StorageReader>>, file_len: u64) -> Result<u64> {
+    let mut records = 0;
+    let mut pos = 0;
+    let mut msg_bytes = Vec::new();
+    loop {
+        let mut len_bytes = [0u8; 4];
+        match reader.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            // a partial prefix is a torn write.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_le_bytes(len_bytes);
+        if len == FOOTER_MARKER {
+            break;
+        }
+        pos += 4;
+        // like replay, a record past the end or failing its checksum is torn or corrupted.
+        if len as u64 > file_len - pos {
+            break;
+        }
+        read_into(reader, &mut msg_bytes, len as usize)?;
+        pos += len as u64;
+        match KvsCommand::decode(&msg_bytes[..]) {
+            Ok(cmd) if cmd.verify_checksum() => records += 1,
+            _ => break,
+        }
+    }
+    Ok(records)
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
+/// Reads the log from `pos` to its end.
+fn read_rest(reader: &mut (impl Read + Seek), pos: u64) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    Ok(rest)
 }
 
 /// Struct representing a command.
@@ -450,6 +3517,14 @@ impl Checksumable for kvs_command::Command{
                 fields.extend_from_slice(remove.key.as_bytes());
                 fields
             }
+
+            _command @ kvs_command::Command::Rename(rename) => {
+                let mut fields = Vec::new();
+                fields.extend_from_slice(rename.old_key.as_bytes());
+                fields.extend_from_slice(rename.new_key.as_bytes());
+                fields.extend_from_slice(rename.value.as_bytes());
+                fields
+            }
         }
     }
 }
@@ -481,6 +3556,18 @@ impl KvsCommand {
         }
     }
 
+    fn rename(old_key: String, new_key: String, value: String, sequence: u64) -> KvsCommand {
+        let command = kvs_command::Command::Rename(KvsRename { old_key, new_key, value });
+        let checksum = command.calculate_checksum();
+        KvsCommand {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            sequence_number: sequence,
+            checksum,
+            version: CURRENT_SCHEMA_VERSION as u32,
+            command: command.into(),
+        }
+    }
+
     fn verify_checksum(&self) -> bool {
         let stored_checksum = self.checksum;
 
@@ -493,8 +3580,26 @@ impl KvsCommand {
     }
 }
 
+/// Store state at the last flush, restored if the disk fills up before the next one.
+struct Checkpoint {
+    pos: u64,
+    uncompacted: u64,
+    current_sequence: Option<u64>,
+    disk_bytes: u64,
+    // index entries replaced by the writes since, oldest first.
+    undo: Vec<(String, Option<CommandPos>)>,
+    // the writes since, reported to the listeners once they are flushed.
+    events: Vec<WriteEvent>,
+}
+
+/// A write waiting in a checkpoint to be reported, with its decoded key and sequence number.
+enum WriteEvent {
+    Set(String, u64),
+    Remove(String, u64),
+}
+
 /// Represents the position and length of a json-serialized command in the log.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct CommandPos {
     gen: u64,
     pos: u64,
@@ -511,9 +3616,48 @@ impl From<(u64, Range<u64>)> for CommandPos {
     }
 }
 
+/// Removes a temporary store directory when dropped.
+struct TempDirGuard(PathBuf);
+
+impl Drop for TempDirGuard {
+    fn drop(&mut self) {
+        // best effort, there is nobody to report a failure to.
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Removes a temporary file when dropped, unless `disarm` was called once it was moved into
+/// place.
+struct TempFileGuard(Option<PathBuf>);
+
+impl TempFileGuard {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            // best effort, the error that got us here is the one to report.
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Buffered reader over a log file opened through the store's `Storage`.
+type LogReader = BufReaderWithPos<Box<dyn StorageReader>>;
+
+/// Buffered writer over a log file opened through the store's `Storage`.
+type LogWriter = BufWriterWithPos<Box<dyn StorageWriter>>;
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
+    // message of the record read last, reused by the next read.
+    scratch: Vec<u8>,
+    // block the messages handed out as `Bytes` are read into, see `read_message_bytes`.
+    arena: BytesMut,
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
@@ -522,8 +3666,72 @@ impl<R: Read + Seek> BufReaderWithPos<R> {
         Ok(BufReaderWithPos {
             reader: BufReader::with_capacity(buffer_size, inner),
             pos,
+            scratch: Vec::new(),
+            arena: BytesMut::new(),
         })
     }
+
+    /// Reads the message of the record at `pos`, without its length prefix, into a buffer
+    /// reused across reads.
+    fn read_message(&mut self, pos: u64) -> Result<&[u8]> {
+        let mut scratch = mem::take(&mut self.scratch);
+        if scratch.capacity() > MAX_SCRATCH_BYTES {
+            // don't hold on to the buffer of a huge value.
+            scratch = Vec::new();
+        }
+        self.read_message_into(pos, &mut scratch)?;
+        self.scratch = scratch;
+        Ok(&self.scratch)
+    }
+
+    /// Reads the message of the record at `pos`, without its length prefix, as a slice of a
+    /// block shared with the messages read before it.
+    ///
+    /// A new block is only allocated once the current one is used up, and each lives as
+    /// long as the slices handed out of it.
+    fn read_message_bytes(&mut self, pos: u64) -> Result<Bytes> {
+        self.seek(SeekFrom::Start(pos))?;
+        let mut len_bytes = [0u8; 4];
+        self.read_exact(&mut len_bytes)?;
+        let len = u32::from_le_bytes(len_bytes) as usize;
+        let mut arena = mem::take(&mut self.arena);
+        if arena.capacity() < len {
+            arena.reserve(max(len, MAX_SCRATCH_BYTES));
+        }
+        arena.resize(len, 0);
+        let read = self.read_exact(&mut arena);
+        let message = arena.split().freeze();
+        self.arena = arena;
+        read?;
+        Ok(message)
+    }
+
+    /// Reads the message of the record at `pos`, without its length prefix, into `buf`.
+    fn read_message_into(&mut self, pos: u64, buf: &mut Vec<u8>) -> Result<()> {
+        self.seek(SeekFrom::Start(pos))?;
+        let mut len_bytes = [0u8; 4];
+        self.read_exact(&mut len_bytes)?;
+        read_into(self, buf, u32::from_le_bytes(len_bytes) as usize)?;
+        Ok(())
+    }
+}
+
+/// Largest record message a reader keeps its buffer for after reading it, and the size of
+/// the blocks `read_message_bytes` reads into.
+const MAX_SCRATCH_BYTES: usize = 64 * 1024;
+
+/// Reads exactly `len` bytes into `buf`, reusing its allocation.
+fn read_into(reader: &mut impl Read, buf: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    buf.resize(len, 0);
+    reader.read_exact(buf)
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    /// Gets a reference to the underlying reader.
+    #[cfg_attr(not(all(target_os = "linux", feature = "io-uring")), allow(dead_code))]
+    fn get_ref(&self) -> &R {
+        self.reader.get_ref()
+    }
 }
 
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
@@ -556,6 +3764,18 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     }
 }
 
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    /// Gets a reference to the underlying writer.
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    /// Drops the writer without flushing the buffered bytes.
+    fn discard(self) {
+        let _ = self.writer.into_parts();
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
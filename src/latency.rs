@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Number of histogram buckets, the last one takes every duration of 2^38 µs (about three
+/// days) and up.
+const BUCKETS: usize = 40;
+
+/// An operation timed by the store, see `Latencies`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// `KvStore::get_v2` and `KvStore::get_bytes`.
+    Get,
+    /// `KvStore::set_v2`, including a compaction it triggers.
+    Set,
+    /// `KvStore::remove_v2`, including a compaction it triggers.
+    Remove,
+    /// `KvStore::compact`, whether called directly or after a write.
+    Compaction,
+}
+
+/// Durations of one kind of operation, bucketed by powers of two microseconds.
+///
+/// Bucket `i` counts the durations below 2^i µs not counted by the one before, so
+/// quantiles are exact to a factor of two.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LatencyHistogram {
+    count: u64,
+    total_micros: u64,
+    max_micros: u64,
+    buckets: Vec<u64>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram {
+            count: 0,
+            total_micros: 0,
+            max_micros: 0,
+            buckets: vec![0; BUCKETS],
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total_micros = self.total_micros.saturating_add(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    /// Returns the number of operations timed.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the mean duration, zero if nothing was timed.
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(self.total_micros.checked_div(self.count).unwrap_or(0))
+    }
+
+    /// Returns the longest duration, zero if nothing was timed.
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros)
+    }
+
+    /// Returns a duration at least as long as the fraction `quantile` of the timed
+    /// operations, at most twice as long as the exact figure. Zero if nothing was timed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `quantile` is not between 0 and 1.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        assert!((0.0..=1.0).contains(&quantile), "quantiles go from 0 to 1");
+        let rank = (quantile * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                let upper = if bucket == 0 { 0 } else { (1u64 << bucket) - 1 };
+                return Duration::from_micros(upper.min(self.max_micros));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// Latency histograms of the timed operations since the store was opened, see
+/// `Stats::latencies`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Latencies {
+    /// Durations of `KvStore::get_v2` and `KvStore::get_bytes`.
+    pub get: LatencyHistogram,
+    /// Durations of `KvStore::set_v2`.
+    pub set: LatencyHistogram,
+    /// Durations of `KvStore::remove_v2`.
+    pub remove: LatencyHistogram,
+    /// Durations of compactions.
+    pub compaction: LatencyHistogram,
+}
+
+impl Latencies {
+    pub(crate) fn record(&mut self, operation: Operation, duration: Duration) {
+        let histogram = match operation {
+            Operation::Get => &mut self.get,
+            Operation::Set => &mut self.set,
+            Operation::Remove => &mut self.remove,
+            Operation::Compaction => &mut self.compaction,
+        };
+        histogram.record(duration);
+    }
+}
@@ -1,11 +1,57 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
+pub use batch::WriteBatch;
+pub use cancel::CancellationToken;
+pub use compaction::{
+    CompactionHandle, CompactionPolicy, CompactionState, CompactionTrigger, GarbageRatio,
+    NeverCompact, SizeThreshold, TimeWindow,
+};
+pub use encoding::KeyEncoding;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
+pub use kv::{Change, Changes, Diff, DiffEntry, KvStore, Scan};
+pub use latency::{Latencies, LatencyHistogram, Operation};
+pub use listener::EventListener;
+pub use namespace::Namespace;
+pub use options::{ChecksumVerification, Config, Options};
+pub use progress::Progress;
+pub use recovery::{CorruptedRecord, RecoveryReport, SequenceGap};
+pub use secondary::SecondaryIndex;
+pub use simulation::SimulatedStorage;
+pub use stats::{PrefixStats, SegmentStats, Stats};
+pub use storage::{LocalStorage, MemoryStorage, Storage, StorageReader, StorageWriter};
+pub use syncer::DurabilityWatcher;
+pub use tiered::{DirObjectStore, ObjectStore, TieredStorage};
 
+mod batch;
+mod cache;
+mod cancel;
+mod compaction;
+mod encoding;
+mod entry;
 mod error;
+mod footer;
+mod hint;
 mod kv;
+mod latency;
+mod listener;
+mod meta;
+mod namespace;
+mod options;
+mod placement;
+mod progress;
+mod recovery;
+mod secondary;
+mod simulation;
+mod snapshot;
+mod stats;
+mod storage;
+mod syncer;
+mod throttle;
+mod tiered;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 
 #[allow(missing_docs)]
 pub mod kvs_command {
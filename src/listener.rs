@@ -0,0 +1,77 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Operation;
+
+/// Callbacks on store events, registered through `Options::listener`.
+///
+/// Every method does nothing by default. Callbacks run synchronously on the thread doing
+/// the operation (replay runs on worker threads), so they should return quickly.
+pub trait EventListener: Debug + Send + Sync {
+    /// Called once a set has been flushed to the log.
+    ///
+    /// Writes of a `WriteBatch` are reported one by one, after the batch is flushed. Writes
+    /// rolled back because the disk filled up are not reported.
+    fn on_set(&self, _key: &str, _sequence: u64) {}
+
+    /// Called once a remove has been flushed to the log.
+    fn on_remove(&self, _key: &str, _sequence: u64) {}
+
+    /// Called before a compaction starts copying live records.
+    fn on_compaction_start(&self) {}
+
+    /// Called after a compaction removed the stale logs, not called if it failed.
+    fn on_compaction_end(&self) {}
+
+    /// Called when the record at `pos` of generation `generation` fails to decode or
+    /// doesn't match its checksum, before the error is returned.
+    fn on_corruption_detected(&self, _generation: u64, _pos: u64) {}
+
+    /// Called once the writes up to `sequence` are on durable storage, after `KvStore::sync`
+    /// or a sync of the background syncer, see `Options::background_sync`. The syncer calls it
+    /// on its own thread.
+    fn on_durable(&self, _sequence: u64) {}
+
+    /// Called after an operation took longer than `Options::slow_op_threshold`, with the
+    /// key it was given, `None` for a compaction.
+    fn on_slow_operation(&self, _operation: Operation, _key: Option<&str>, _duration: Duration) {}
+}
+
+/// The listeners registered on a store, notified in registration order.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Listeners(pub(crate) Vec<Arc<dyn EventListener>>);
+
+impl Listeners {
+    pub(crate) fn on_set(&self, key: &str, sequence: u64) {
+        self.0.iter().for_each(|listener| listener.on_set(key, sequence));
+    }
+
+    pub(crate) fn on_remove(&self, key: &str, sequence: u64) {
+        self.0.iter().for_each(|listener| listener.on_remove(key, sequence));
+    }
+
+    pub(crate) fn on_compaction_start(&self) {
+        self.0.iter().for_each(|listener| listener.on_compaction_start());
+    }
+
+    pub(crate) fn on_compaction_end(&self) {
+        self.0.iter().for_each(|listener| listener.on_compaction_end());
+    }
+
+    pub(crate) fn on_corruption_detected(&self, generation: u64, pos: u64) {
+        self.0
+            .iter()
+            .for_each(|listener| listener.on_corruption_detected(generation, pos));
+    }
+
+    pub(crate) fn on_durable(&self, sequence: u64) {
+        self.0.iter().for_each(|listener| listener.on_durable(sequence));
+    }
+
+    pub(crate) fn on_slow_operation(&self, operation: Operation, key: Option<&str>, duration: Duration) {
+        self.0
+            .iter()
+            .for_each(|listener| listener.on_slow_operation(operation, key, duration));
+    }
+}
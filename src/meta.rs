@@ -0,0 +1,196 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fail::fail_point;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result, Storage};
+
+/// Name of the metadata file in a store directory.
+pub(crate) const META_FILE: &str = "META";
+
+/// Name of the file holding the on-disk format version of a store directory.
+pub(crate) const FORMAT_FILE: &str = "FORMAT";
+
+/// Extension of the names store files are written under before they are renamed into place.
+pub(crate) const TMP_EXTENSION: &str = "tmp";
+
+/// On-disk format version this version of the crate writes.
+///
+/// 1: length-prefixed protobuf records, lengths little endian.
+/// 2: sealed logs may end with a footer.
+/// 3: logs may sit in shard subdirectories and have descriptive names.
+pub(crate) const FORMAT_VERSION: u64 = 3;
+
+/// Facts about a store that don't live in the logs, kept as JSON in `META_FILE`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StoreMeta {
+    /// Random UUID given to the store when it was created.
+    pub(crate) id: String,
+    /// Creation time in seconds since the Unix epoch.
+    pub(crate) created_at: u64,
+    /// Record schema version the store was last written with.
+    pub(crate) schema_version: u64,
+    /// A sequence number at or below the highest one ever written.
+    ///
+    /// Compaction can drop the records with the highest sequences, so replay alone could
+    /// hand out a sequence number twice.
+    pub(crate) sequence: u64,
+    /// Storage engine that wrote the store.
+    pub(crate) engine: String,
+    /// Encoding of the log records.
+    pub(crate) codec: String,
+    /// Generation of a compaction or bulk ingest log still being written.
+    ///
+    /// Set while the stale logs it replaces still hold all of its records, so after a crash
+    /// it can be discarded as a whole.
+    #[serde(default)]
+    pub(crate) compacting: Option<u64>,
+    /// Name of the `KeyEncoding` the keys are stored in.
+    #[serde(default)]
+    pub(crate) key_encoding: Option<String>,
+    /// Directories besides the store directory that hold log files.
+    #[serde(default)]
+    pub(crate) data_dirs: Vec<PathBuf>,
+    /// Number of shard subdirectories in each data directory, 0 for a flat layout.
+    #[serde(default)]
+    pub(crate) segment_shards: u32,
+    /// Whether new logs get names with their creation time and sealed flag.
+    #[serde(default)]
+    pub(crate) descriptive_names: bool,
+}
+
+impl StoreMeta {
+    /// Creates the metadata of a new store.
+    pub(crate) fn new(schema_version: u64) -> StoreMeta {
+        StoreMeta {
+            id: random_uuid(),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            schema_version,
+            sequence: 0,
+            engine: "kvs".to_owned(),
+            codec: "protobuf".to_owned(),
+            compacting: None,
+            key_encoding: None,
+            data_dirs: Vec::new(),
+            segment_shards: 0,
+            descriptive_names: false,
+        }
+    }
+
+    /// Reads the metadata of a store directory, `None` if it has none yet.
+    pub(crate) fn load(storage: &dyn Storage, dir: &Path) -> Result<Option<StoreMeta>> {
+        let mut reader = match storage.open_reader(&dir.join(META_FILE)) {
+            Ok(reader) => reader,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    /// Replaces the metadata of a store directory, see `write_atomically`.
+    pub(crate) fn save(&self, storage: &dyn Storage, dir: &Path) -> Result<()> {
+        write_atomically(storage, &dir.join(META_FILE), &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Checks that the store was written by this engine and codec, in a schema it can read.
+    pub(crate) fn check_compatible(&self, schema_version: u64) -> Result<()> {
+        if self.engine != "kvs" || self.codec != "protobuf" {
+            return Err(KvsError::IncompatibleFormat(format!(
+                "engine {} with codec {}",
+                self.engine, self.codec
+            )));
+        }
+        if self.schema_version > schema_version {
+            return Err(KvsError::IncompatibleFormat(format!(
+                "record schema version {}, this version reads up to {}",
+                self.schema_version, schema_version
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Checks the format version of a store directory, recording the current one if the
+/// directory has none or an older one.
+///
+/// Older formats can still be read, so only newer or unreadable ones are refused.
+pub(crate) fn check_format(storage: &dyn Storage, dir: &Path) -> Result<()> {
+    let path = dir.join(FORMAT_FILE);
+    let version = match storage.open_reader(&path) {
+        Ok(mut reader) => {
+            let mut contents = String::new();
+            reader.read_to_string(&mut contents)?;
+            match contents.trim().parse::<u64>() {
+                Ok(version) => Some(version),
+                Err(_) => {
+                    return Err(KvsError::IncompatibleFormat(format!(
+                        "unreadable format version {:?}",
+                        contents.trim()
+                    )))
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    match version {
+        Some(version) if version > FORMAT_VERSION => Err(KvsError::IncompatibleFormat(format!(
+            "format version {}, this version reads up to {}",
+            version, FORMAT_VERSION
+        ))),
+        Some(FORMAT_VERSION) => Ok(()),
+        _ => write_atomically(storage, &path, format!("{}\n", FORMAT_VERSION).as_bytes()),
+    }
+}
+
+/// Replaces the contents of a file in a store directory.
+///
+/// The new contents are synced under a temporary name and renamed over the old file, so
+/// a crash leaves either the old or the new contents.
+pub(crate) fn write_atomically(storage: &dyn Storage, path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension(TMP_EXTENSION);
+    // writers append, so clear out leftovers of an interrupted save.
+    match storage.remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut writer = storage.open_writer(&tmp)?;
+    writer.write_all(contents)?;
+    writer.flush()?;
+    writer.sync_data()?;
+    drop(writer);
+    fail_point!("meta::rename", |_| Err(KvsError::injected("meta::rename")));
+    storage.rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Returns a random version 4 UUID in its hyphenated form.
+fn random_uuid() -> String {
+    // every `RandomState` is seeded with fresh randomness from the OS.
+    let mut bytes = [0u8; 16];
+    for half in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos());
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
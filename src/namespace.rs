@@ -0,0 +1,80 @@
+use crate::{KvStore, PrefixStats, Result};
+
+/// Separates the namespace name from the key in stored keys.
+const SEPARATOR: char = '\0';
+
+/// A view of the keys of one namespace of a store, see `KvStore::namespace`.
+///
+/// Keys are stored as the namespace name, a NUL character and the key, so namespaces never
+/// see each other's keys and a server can hand each application one of its own. Keys
+/// written outside any namespace only collide with them if they contain a NUL character.
+pub struct Namespace<'a> {
+    pub(crate) store: &'a mut KvStore,
+    pub(crate) prefix: String,
+}
+
+impl Namespace<'_> {
+    /// Returns the name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.prefix[..self.prefix.len() - SEPARATOR.len_utf8()]
+    }
+
+    /// Sets `key` in the namespace, see `KvStore::set_v2`.
+    ///
+    /// # Errors
+    ///
+    /// Like `KvStore::set_v2`.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let key = self.stored_key(&key);
+        self.store.set_v2(key, value)
+    }
+
+    /// Gets the value of `key` in the namespace, see `KvStore::get_v2`.
+    ///
+    /// # Errors
+    ///
+    /// Like `KvStore::get_v2`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.stored_key(&key);
+        self.store.get_v2(key)
+    }
+
+    /// Removes `key` from the namespace, see `KvStore::remove_v2`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the namespace doesn't hold the key. Otherwise
+    /// like `KvStore::remove_v2`.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let key = self.stored_key(&key);
+        self.store.remove_v2(key)
+    }
+
+    /// Iterates over the key/value pairs of the namespace in ascending key order, the keys
+    /// without the namespace name.
+    pub fn scan(&mut self) -> impl DoubleEndedIterator<Item = Result<(String, String)>> + '_ {
+        let prefix_len = self.prefix.len();
+        self.store
+            .scan_prefix(&self.prefix)
+            .map(move |pair| pair.map(|(key, value)| (key[prefix_len..].to_owned(), value)))
+    }
+
+    /// Returns the number and size of the keys in the namespace, see `KvStore::prefix_stats`.
+    pub fn stats(&self) -> PrefixStats {
+        self.store.prefix_stats(&self.prefix)
+    }
+
+    fn stored_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+/// Returns the prefix of the stored keys of namespace `name`.
+///
+/// # Panics
+///
+/// Panics if `name` contains a NUL character.
+pub(crate) fn prefix(name: &str) -> String {
+    assert!(!name.contains(SEPARATOR), "namespace names cannot contain NUL characters");
+    format!("{}{}", name, SEPARATOR)
+}
@@ -0,0 +1,311 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::listener::Listeners;
+use crate::{CompactionPolicy, EventListener, KeyEncoding, Progress, SecondaryIndex, Storage};
+
+/// Options for opening a `KvStore`, see `KvStore::open_with`.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub(crate) reader_buffer_size: Option<usize>,
+    pub(crate) writer_buffer_size: Option<usize>,
+    pub(crate) progress: Option<Progress>,
+    pub(crate) storage: Option<Arc<dyn Storage>>,
+    pub(crate) listeners: Listeners,
+    pub(crate) max_disk_bytes: Option<u64>,
+    pub(crate) cache_budget: Option<u64>,
+    pub(crate) compaction_rate_limit: Option<u64>,
+    pub(crate) compaction_policy: Option<Arc<dyn CompactionPolicy>>,
+    pub(crate) compact_on_open: Option<f64>,
+    pub(crate) tombstone_retention: Option<Duration>,
+    pub(crate) slow_op_threshold: Option<Duration>,
+    pub(crate) sync_on_drop: bool,
+    pub(crate) background_sync: Option<Duration>,
+    pub(crate) strict_recovery: bool,
+    pub(crate) skip_corrupted: bool,
+    pub(crate) checksum_verification: ChecksumVerification,
+    pub(crate) expected_id: Option<String>,
+    pub(crate) delete_orphans: bool,
+    pub(crate) key_encoding: Option<Arc<dyn KeyEncoding>>,
+    pub(crate) secondary_indexes: Vec<Arc<dyn SecondaryIndex>>,
+    pub(crate) data_dirs: Vec<PathBuf>,
+    pub(crate) segment_shards: u32,
+    pub(crate) descriptive_names: bool,
+}
+
+impl Options {
+    /// Creates options with every setting at its default.
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Sets the buffer size of the log readers, 8kb by default.
+    pub fn reader_buffer_size(mut self, size: usize) -> Options {
+        self.reader_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the buffer size of the log writer, 8kb by default.
+    pub fn writer_buffer_size(mut self, size: usize) -> Options {
+        self.writer_buffer_size = Some(size);
+        self
+    }
+
+    /// Reports replay progress through the given handle, which can also cancel the open.
+    pub fn progress(mut self, progress: Progress) -> Options {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Keeps the logs in the given storage backend, the local filesystem by default.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Options {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Registers a listener notified of writes, compactions and corruption, see `EventListener`.
+    ///
+    /// Can be called several times, listeners are notified in registration order.
+    pub fn listener(mut self, listener: Arc<dyn EventListener>) -> Options {
+        self.listeners.0.push(listener);
+        self
+    }
+
+    /// Caps the total size of the log files, unbounded by default.
+    ///
+    /// A set that would grow the logs past the cap compacts first if there is stale data,
+    /// and fails with `KvsError::QuotaExceeded` if that doesn't free enough. Removes are
+    /// always written, so space can be reclaimed from a full store.
+    pub fn max_disk_bytes(mut self, bytes: u64) -> Options {
+        self.max_disk_bytes = Some(bytes);
+        self
+    }
+
+    /// Turns the store into a persistent cache holding at most `bytes` of live records.
+    ///
+    /// Writes that push the live data over the budget remove the least recently used keys,
+    /// so a value larger than the budget evicts itself. Sets and hits of `get_v2` count as
+    /// uses, scans don't. Recency is not persisted: after a reopen keys rank by where their
+    /// record sits in the log until they are used again.
+    pub fn cache_budget(mut self, bytes: u64) -> Options {
+        self.cache_budget = Some(bytes);
+        self
+    }
+
+    /// Limits compaction to copying `bytes_per_sec` bytes per second, unlimited by default.
+    ///
+    /// Keeps compaction from starving other IO on a shared disk, at the cost of longer
+    /// compactions. Can be changed later with `KvStore::set_compaction_rate_limit`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is zero.
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> Options {
+        assert!(bytes_per_sec > 0, "compaction rate limit must be positive");
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Decides when writes compact, `SizeThreshold` with 1 MiB by default.
+    ///
+    /// Can be changed later with `KvStore::set_compaction_policy`.
+    pub fn compaction_policy(mut self, policy: Arc<dyn CompactionPolicy>) -> Options {
+        self.compaction_policy = Some(policy);
+        self
+    }
+
+    /// Compacts before open returns if stale bytes make up more than `ratio` of the logs,
+    /// off by default.
+    ///
+    /// The compaction policy only runs after writes, so a store that crashed or was closed
+    /// before compacting carries its garbage until written to again otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ratio is not between 0 and 1.
+    pub fn compact_on_open(mut self, ratio: f64) -> Options {
+        assert!((0.0..=1.0).contains(&ratio), "compact on open ratio must be between 0 and 1");
+        self.compact_on_open = Some(ratio);
+        self
+    }
+
+    /// Keeps removes through compaction until they are older than `retention`, by default
+    /// compaction drops them right away.
+    ///
+    /// Backups and followers reading the logs, for example with `KvStore::changes_since`,
+    /// then still see a remove that happened within the retention, instead of missing it
+    /// and keeping the key. Compaction has to read every log to find the removes.
+    pub fn tombstone_retention(mut self, retention: Duration) -> Options {
+        self.tombstone_retention = Some(retention);
+        self
+    }
+
+    /// Reports gets, sets, removes and compactions taking longer than `threshold` through
+    /// `EventListener::on_slow_operation`, off by default.
+    ///
+    /// Durations are recorded in `Stats::latencies` either way.
+    pub fn slow_op_threshold(mut self, threshold: Duration) -> Options {
+        self.slow_op_threshold = Some(threshold);
+        self
+    }
+
+    /// Makes dropping the store fsync the active log after flushing it, off by default.
+    pub fn sync_on_drop(mut self, sync: bool) -> Options {
+        self.sync_on_drop = sync;
+        self
+    }
+
+    /// Fsyncs the active log every `interval` on a background thread, off by default.
+    ///
+    /// Writes don't wait for it. Use `KvStore::durability_watcher` to wait for a write to
+    /// become durable, or `EventListener::on_durable` to hear about it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the interval is zero.
+    pub fn background_sync(mut self, interval: Duration) -> Options {
+        assert!(!interval.is_zero(), "background sync interval must be positive");
+        self.background_sync = Some(interval);
+        self
+    }
+
+    /// Makes open fail with `KvsError::InconsistentLog` if replay finds sequence number
+    /// gaps or duplicates, off by default. They are only reported through
+    /// `KvStore::recovery_report` otherwise.
+    pub fn strict_recovery(mut self, strict: bool) -> Options {
+        self.strict_recovery = strict;
+        self
+    }
+
+    /// Makes open skip records it can't decode instead of failing, off by default.
+    ///
+    /// Each skipped record is copied to the `corrupt` subdirectory of the store for later
+    /// inspection and listed in `RecoveryReport::corrupted`. The writes in it are lost, and
+    /// with a corrupted length prefix so is the rest of its log.
+    pub fn skip_corrupted(mut self, skip: bool) -> Options {
+        self.skip_corrupted = skip;
+        self
+    }
+
+    /// Sets which gets verify the checksum of the record they read, every get by default.
+    ///
+    /// Replay, compaction and the other reads always verify it.
+    pub fn checksum_verification(mut self, verification: ChecksumVerification) -> Options {
+        self.checksum_verification = verification;
+        self
+    }
+
+    /// Makes open fail with `KvsError::StoreMismatch` unless the store has the given id,
+    /// to catch a backup restored into the wrong directory. A new store never matches.
+    pub fn expected_id(mut self, id: impl Into<String>) -> Options {
+        self.expected_id = Some(id.into());
+        self
+    }
+
+    /// Deletes leftovers of interrupted operations found on open, off by default.
+    ///
+    /// They are moved to a `quarantine` subdirectory of the store otherwise. Either way
+    /// they are listed in `RecoveryReport::orphans`.
+    pub fn delete_orphans(mut self, delete: bool) -> Options {
+        self.delete_orphans = delete;
+        self
+    }
+
+    /// Stores keys in the given encoding, which sets their order in scans, see `KeyEncoding`.
+    ///
+    /// Open fails with `KvsError::KeyEncodingMismatch` if the store was created with
+    /// another encoding, or without one.
+    pub fn key_encoding(mut self, encoding: Arc<dyn KeyEncoding>) -> Options {
+        self.key_encoding = Some(encoding);
+        self
+    }
+
+    /// Maintains a secondary index for lookups with `KvStore::get_by_index`.
+    ///
+    /// The index is updated with every write and rolled back with it. It is kept in memory
+    /// and rebuilt from the logs on open, which reads every live value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an index of the same name was added already.
+    pub fn secondary_index(mut self, index: Arc<dyn SecondaryIndex>) -> Options {
+        assert!(
+            self.secondary_indexes.iter().all(|other| other.name() != index.name()),
+            "duplicate secondary index {}",
+            index.name()
+        );
+        self.secondary_indexes.push(index);
+        self
+    }
+
+    /// Spreads new log files over `dir` as well as the store directory, for example one
+    /// directory per disk.
+    ///
+    /// Can be called several times. New logs go to the directories round-robin. The
+    /// directories are recorded in the store metadata and used on every later open, so they
+    /// can be added but not taken away.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Options {
+        self.data_dirs.push(dir.into());
+        self
+    }
+
+    /// Places new log files in `shards` subdirectories of each data directory, `segments/00`
+    /// to `segments/NN`, picked by generation number. Off by default, which keeps the logs
+    /// directly in the data directories.
+    ///
+    /// The number is recorded in the store metadata. It can be raised on a later open but
+    /// not lowered, and logs already written stay where they are.
+    pub fn segment_shards(mut self, shards: u32) -> Options {
+        self.segment_shards = shards;
+        self
+    }
+
+    /// Names new log files after their generation and creation time, `7-1700000000.log`,
+    /// and marks key-sorted logs with a footer as sealed, `7-1700000000.sealed.log`. Off by
+    /// default, which names logs `7.log`.
+    ///
+    /// Recorded in the store metadata, so once turned on it stays on.
+    pub fn descriptive_segment_names(mut self, descriptive: bool) -> Options {
+        self.descriptive_names = descriptive;
+        self
+    }
+}
+
+/// Which gets verify the checksum of the record they read, see
+/// `Options::checksum_verification`.
+///
+/// A get that skips the check still fails on a record that doesn't decode, but returns a
+/// value with flipped bits as it is. Turn it down only where the page cache is trusted and
+/// the CPU of hot gets matters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumVerification {
+    /// Every get verifies.
+    #[default]
+    Always,
+    /// Gets never verify, only replay on open and the other reads do.
+    ///
+    /// Bulk ingest logs are indexed from their hint file on open, so only the other reads
+    /// verify their records.
+    OnReplay,
+    /// One get in every `n` verifies, 0 and 1 meaning every get.
+    Sample(u32),
+}
+
+/// The settings of an open store that can change without reopening it, see
+/// `KvStore::set_config`.
+///
+/// Each field works as the `Options` method of the same name, `None` meaning the default.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Decides when writes compact.
+    pub compaction_policy: Arc<dyn CompactionPolicy>,
+    /// Bytes per second compaction copies, unlimited if `None`.
+    pub compaction_rate_limit: Option<u64>,
+    /// Budget of cache mode, off if `None`.
+    pub cache_budget: Option<u64>,
+    /// Cap on the size of the log files, unbounded if `None`.
+    pub max_disk_bytes: Option<u64>,
+    /// Whether dropping the store fsyncs the active log.
+    pub sync_on_drop: bool,
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fail::fail_point;
+
+use crate::{Result, Storage};
+
+/// Subdirectory of a data directory that holds the shard directories.
+const SEGMENTS_DIR: &str = "segments";
+
+/// Suffix of the log names of sealed, key-sorted logs with descriptive names.
+const SEALED_SUFFIX: &str = ".sealed";
+
+/// The directories log files are spread over and where each generation lives, see
+/// `Options::data_dir` and `Options::segment_shards`.
+///
+/// Logs are found by their generation number whatever directory or name form they have,
+/// so stores written with another layout open as they are.
+#[derive(Debug)]
+pub(crate) struct Placement {
+    // the store directory first.
+    dirs: Vec<PathBuf>,
+    shards: u32,
+    descriptive_names: bool,
+    gens: HashMap<u64, PathBuf>,
+    // data directory of the next new log.
+    next: usize,
+}
+
+impl Placement {
+    pub(crate) fn new(dirs: Vec<PathBuf>, shards: u32, descriptive_names: bool) -> Placement {
+        assert!(!dirs.is_empty(), "a store needs at least one directory");
+        Placement {
+            dirs,
+            shards,
+            descriptive_names,
+            gens: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    /// Returns every directory that can hold logs, the data directories before their shards.
+    pub(crate) fn dirs(&self) -> Vec<PathBuf> {
+        let shards = self
+            .dirs
+            .iter()
+            .flat_map(|dir| (0..self.shards).map(move |shard| shard_dir(dir, shard)));
+        self.dirs.iter().cloned().chain(shards).collect()
+    }
+
+    /// Creates the shard directories.
+    pub(crate) fn create_dirs(&self, storage: &dyn Storage) -> Result<()> {
+        for dir in self.dirs() {
+            storage.create_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the log of every generation in the directories, returning the generation
+    /// numbers in ascending order.
+    pub(crate) fn discover(&mut self, storage: &dyn Storage) -> Result<Vec<u64>> {
+        self.gens.clear();
+        for dir in self.dirs() {
+            for (gen, path) in logs_in(storage, &dir)? {
+                self.gens.insert(gen, path);
+            }
+        }
+        let mut gen_list: Vec<u64> = self.gens.keys().cloned().collect();
+        gen_list.sort_unstable();
+        self.next = gen_list.len() % self.dirs.len();
+        Ok(gen_list)
+    }
+
+    /// Returns the path of the log of a generation.
+    ///
+    /// A generation that is not placed yet maps to a plain name in the store directory.
+    pub(crate) fn log_path(&self, gen: u64) -> PathBuf {
+        match self.gens.get(&gen) {
+            Some(path) => path.clone(),
+            None => self.dirs[0].join(format!("{}.log", gen)),
+        }
+    }
+
+    /// Picks the directory of a new generation round-robin and its shard by generation
+    /// number, returning the path of its log.
+    pub(crate) fn place(&mut self, gen: u64) -> PathBuf {
+        let mut dir = self.dirs[self.next].clone();
+        self.next = (self.next + 1) % self.dirs.len();
+        if self.shards > 0 {
+            dir = shard_dir(&dir, (gen % self.shards as u64) as u32);
+        }
+        let name = if self.descriptive_names {
+            let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            format!("{}-{}.log", gen, created)
+        } else {
+            format!("{}.log", gen)
+        };
+        let path = dir.join(name);
+        self.gens.insert(gen, path.clone());
+        path
+    }
+
+    /// Marks the log of a generation as sealed in its name, returning its new path.
+    ///
+    /// Plain names carry no flag and are left as they are.
+    pub(crate) fn seal(&mut self, storage: &dyn Storage, gen: u64) -> Result<PathBuf> {
+        let path = self.log_path(gen);
+        let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+        if !self.descriptive_names || stem.ends_with(SEALED_SUFFIX) {
+            return Ok(path);
+        }
+        let sealed = path.with_file_name(format!("{}{}.log", stem, SEALED_SUFFIX));
+        fail_point!("placement::seal", |_| Err(crate::KvsError::injected("placement::seal")));
+        storage.rename(&path, &sealed)?;
+        self.gens.insert(gen, sealed.clone());
+        Ok(sealed)
+    }
+
+    pub(crate) fn remove(&mut self, gen: u64) {
+        self.gens.remove(&gen);
+    }
+}
+
+fn shard_dir(dir: &Path, shard: u32) -> PathBuf {
+    dir.join(SEGMENTS_DIR).join(format!("{:02}", shard))
+}
+
+/// Returns the generation number and path of the logs in a directory.
+///
+/// Accepts plain names, `7.log`, and descriptive ones, `7-1700000000.log` or
+/// `7-1700000000.sealed.log`.
+fn logs_in(storage: &dyn Storage, dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    Ok(storage
+        .list_files(dir)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .filter_map(|path| {
+            let stem = path.file_stem().and_then(OsStr::to_str)?;
+            let gen = stem.split(['-', '.']).next()?.parse::<u64>().ok()?;
+            Some((gen, path))
+        })
+        .collect())
+}
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::{KvsError, Result};
+
+/// A handle reporting how far an in-flight `KvStore::open` has replayed the log.
+///
+/// Clones share the same state, so one clone can be handed to `Options::progress` while
+/// another is polled or cancelled from a different thread.
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    inner: Arc<ProgressInner>,
+}
+
+#[derive(Debug, Default)]
+struct ProgressInner {
+    bytes_replayed: AtomicU64,
+    total_bytes: AtomicU64,
+    // 0 until the first generation starts replaying, generations start at 1.
+    current_gen: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    /// Creates a new progress handle.
+    pub fn new() -> Progress {
+        Progress::default()
+    }
+
+    /// Returns the number of log bytes replayed so far.
+    pub fn bytes_replayed(&self) -> u64 {
+        self.inner.bytes_replayed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of log bytes to replay, known once `open` listed the generations.
+    pub fn total_bytes(&self) -> u64 {
+        self.inner.total_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the generation that most recently started replaying.
+    pub fn current_generation(&self) -> Option<u64> {
+        match self.inner.current_gen.load(Ordering::Relaxed) {
+            0 => None,
+            gen => Some(gen),
+        }
+    }
+
+    /// Asks the `open` using this handle to stop.
+    ///
+    /// The open returns `KvsError::Cancelled` without creating a new log file.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether `cancel` was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_total_bytes(&self, total: u64) {
+        self.inner.total_bytes.store(total, Ordering::Relaxed);
+    }
+
+    pub(crate) fn start_generation(&self, gen: u64) {
+        self.inner.current_gen.store(gen, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes(&self, bytes: u64) {
+        self.inner.bytes_replayed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns `KvsError::Cancelled` once the handle was cancelled.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(KvsError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
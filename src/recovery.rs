@@ -0,0 +1,55 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+/// Anomalies found and fixed while opening a store, see `KvStore::recovery_report`.
+///
+/// Generations whose records are in key order may be compaction output, where gaps are
+/// expected, so only generations written in arrival order are checked for gaps.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Sequence numbers missing in the middle of a generation, which indicate lost writes.
+    pub gaps: Vec<SequenceGap>,
+    /// Sequence numbers used by more than one record, which indicate mixed-up files.
+    ///
+    /// Identical copies left behind by an interrupted compaction don't count.
+    pub duplicates: Vec<u64>,
+    /// Leftovers of interrupted operations that were removed or quarantined before replay,
+    /// such as a half-written compaction log or temporary files.
+    pub orphans: Vec<PathBuf>,
+    /// Records skipped with `Options::skip_corrupted`, in log order.
+    pub corrupted: Vec<CorruptedRecord>,
+}
+
+impl RecoveryReport {
+    /// Returns whether no sequence number anomaly or corrupted record was found, orphans are
+    /// expected after a crash.
+    pub fn is_clean(&self) -> bool {
+        self.gaps.is_empty() && self.duplicates.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// A record replay could not decode, skipped with `Options::skip_corrupted`.
+///
+/// A corrupted length prefix hides where the following records start, so the record then
+/// runs to the end of the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptedRecord {
+    /// Generation number of the log file.
+    pub generation: u64,
+    /// Position of the record in the log.
+    pub offset: u64,
+    /// Length of the record in bytes.
+    pub len: u64,
+    /// Copy of the record in the `corrupt` subdirectory of the store, a `.json` file of the
+    /// same name describes it.
+    pub path: PathBuf,
+}
+
+/// A run of sequence numbers missing from a generation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequenceGap {
+    /// Generation number of the log file.
+    pub generation: u64,
+    /// The sequence numbers missing between two consecutive records.
+    pub missing: RangeInclusive<u64>,
+}
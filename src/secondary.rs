@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Derives the key a record is filed under in a secondary index, see `Options::secondary_index`.
+pub trait SecondaryIndex: Debug + Send + Sync {
+    /// Returns the name lookups refer to the index by, see `KvStore::get_by_index`.
+    fn name(&self) -> &str;
+
+    /// Returns the index key of a record, `None` leaves the record out of the index.
+    fn index_key(&self, key: &str, value: &str) -> Option<String>;
+}
+
+/// The secondary indexes of a store, updated along with the primary index.
+#[derive(Debug, Default)]
+pub(crate) struct Indexes(Vec<IndexState>);
+
+#[derive(Debug)]
+struct IndexState {
+    definition: Arc<dyn SecondaryIndex>,
+    // stored keys filed under every index key.
+    entries: BTreeMap<String, BTreeSet<String>>,
+    // index key of every stored key, to unfile it on overwrite or remove.
+    index_keys: HashMap<String, String>,
+}
+
+impl Indexes {
+    pub(crate) fn new(definitions: Vec<Arc<dyn SecondaryIndex>>) -> Indexes {
+        Indexes(
+            definitions
+                .into_iter()
+                .map(|definition| IndexState {
+                    definition,
+                    entries: BTreeMap::new(),
+                    index_keys: HashMap::new(),
+                })
+                .collect(),
+        )
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Files a new value of `stored_key`, `key` is the decoded form passed to the definitions.
+    pub(crate) fn insert(&mut self, stored_key: &str, key: &str, value: &str) {
+        for index in &mut self.0 {
+            index.remove(stored_key);
+            if let Some(index_key) = index.definition.index_key(key, value) {
+                index.entries.entry(index_key.clone()).or_default().insert(stored_key.to_owned());
+                index.index_keys.insert(stored_key.to_owned(), index_key);
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, stored_key: &str) {
+        for index in &mut self.0 {
+            index.remove(stored_key);
+        }
+    }
+
+    /// Returns the stored keys filed under `index_key`, `None` if there is no such index.
+    pub(crate) fn get(&self, name: &str, index_key: &str) -> Option<Vec<String>> {
+        let index = self.0.iter().find(|index| index.definition.name() == name)?;
+        Some(index.entries.get(index_key).into_iter().flatten().cloned().collect())
+    }
+}
+
+impl IndexState {
+    fn remove(&mut self, stored_key: &str) {
+        let Some(index_key) = self.index_keys.remove(stored_key) else {
+            return;
+        };
+        if let Some(keys) = self.entries.get_mut(&index_key) {
+            keys.remove(stored_key);
+            if keys.is_empty() {
+                self.entries.remove(&index_key);
+            }
+        }
+    }
+}
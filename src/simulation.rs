@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::storage::{locked, not_found};
+use crate::{Storage, StorageReader, StorageWriter};
+
+/// In-memory `Storage` that injects faults deterministically from a seed, to exercise
+/// recovery.
+///
+/// Written data only becomes durable once its file is synced. `crash` loses the rest the
+/// way a power cut could: each unsynced write is dropped, kept or torn, in any order, so a
+/// later write can survive an earlier one. Writes can also come back short, and every write,
+/// sync and file operation advances a simulated clock by a random latency, see `elapsed`.
+///
+/// Faults are drawn from the seed in the order of the calls, so a run that makes the same
+/// calls with the same seed sees the same faults and a failure can be replayed from its
+/// seed. Reads draw nothing and can run in any order. Creating, renaming and removing files
+/// is durable at once. Clones share the same files.
+#[derive(Clone, Debug)]
+pub struct SimulatedStorage {
+    inner: Arc<Mutex<SimulatedFs>>,
+}
+
+#[derive(Debug)]
+struct SimulatedFs {
+    seed: u64,
+    rng: u64,
+    max_latency: Duration,
+    short_writes: bool,
+    elapsed: Duration,
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, SimulatedFile>,
+    locked: BTreeSet<PathBuf>,
+}
+
+/// File state shared between every handle opened on the file.
+type SimulatedFile = Arc<Mutex<FileState>>;
+
+#[derive(Debug, Default)]
+struct FileState {
+    // what readers see.
+    data: Vec<u8>,
+    // what survives a crash.
+    durable: Vec<u8>,
+    // ranges of `data` written since the last sync, in write order.
+    unsynced: Vec<Range<usize>>,
+}
+
+impl SimulatedStorage {
+    /// Creates an empty storage drawing its faults from `seed`, with no latency and no short
+    /// writes.
+    pub fn new(seed: u64) -> SimulatedStorage {
+        SimulatedStorage {
+            inner: Arc::new(Mutex::new(SimulatedFs {
+                seed,
+                // xorshift gets stuck at zero.
+                rng: seed ^ 0x9e37_79b9_7f4a_7c15,
+                max_latency: Duration::ZERO,
+                short_writes: false,
+                elapsed: Duration::ZERO,
+                dirs: BTreeSet::new(),
+                files: BTreeMap::new(),
+                locked: BTreeSet::new(),
+            })),
+        }
+    }
+
+    /// Delays each write, sync and file operation by up to `latency` of simulated time.
+    pub fn max_latency(self, latency: Duration) -> SimulatedStorage {
+        self.inner.lock().unwrap().max_latency = latency;
+        self
+    }
+
+    /// Makes some writes take only part of the buffer they are given, off by default.
+    pub fn short_writes(self, short: bool) -> SimulatedStorage {
+        self.inner.lock().unwrap().short_writes = short;
+        self
+    }
+
+    /// Returns the seed the faults are drawn from.
+    pub fn seed(&self) -> u64 {
+        self.inner.lock().unwrap().seed
+    }
+
+    /// Returns the simulated time spent in the operations so far.
+    pub fn elapsed(&self) -> Duration {
+        self.inner.lock().unwrap().elapsed
+    }
+
+    /// Simulates a power cut: every file goes back to its synced contents plus whichever
+    /// unsynced writes the seed lets through, some of them cut short.
+    ///
+    /// Stores on this storage must be dropped first, or killed with `KvStore::kill`.
+    pub fn crash(&self) {
+        let mut fs = self.inner.lock().unwrap();
+        let files: Vec<SimulatedFile> = fs.files.values().cloned().collect();
+        for file in files {
+            let mut file = file.lock().unwrap();
+            let mut unsynced = mem::take(&mut file.unsynced);
+            // writes reach the disk in any order.
+            for i in (1..unsynced.len()).rev() {
+                unsynced.swap(i, fs.next() as usize % (i + 1));
+            }
+            let mut durable = mem::take(&mut file.durable);
+            for range in unsynced {
+                let end = match fs.next() % 3 {
+                    0 => continue,
+                    1 => range.end,
+                    _ => range.start + fs.next() as usize % range.len(),
+                };
+                if durable.len() < end {
+                    durable.resize(end, 0);
+                }
+                durable[range.start..end].copy_from_slice(&file.data[range.start..end]);
+            }
+            file.data = durable.clone();
+            file.durable = durable;
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<SimulatedFile> {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+impl SimulatedFs {
+    /// Returns the next number drawn from the seed, xorshift64.
+    fn next(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Advances the simulated clock by a random latency.
+    fn delay(&mut self) {
+        let max_nanos = self.max_latency.as_nanos() as u64;
+        if max_nanos > 0 {
+            let nanos = self.next() % (max_nanos + 1);
+            self.elapsed += Duration::from_nanos(nanos);
+        }
+    }
+}
+
+impl Storage for SimulatedStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut fs = self.inner.lock().unwrap();
+        fs.delay();
+        for dir in path.ancestors() {
+            fs.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let fs = self.inner.lock().unwrap();
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(fs
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.file(path)?.lock().unwrap().data.len() as u64)
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        Ok(Box::new(SimulatedReader {
+            file: self.file(path)?,
+            pos: 0,
+        }))
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        let mut fs = self.inner.lock().unwrap();
+        fs.delay();
+        match path.parent() {
+            Some(dir) if fs.dirs.contains(dir) => {}
+            _ => return Err(not_found(path)),
+        }
+        let file = fs.files.entry(path.to_path_buf()).or_default().clone();
+        let pos = file.lock().unwrap().data.len() as u64;
+        Ok(Box::new(SimulatedWriter {
+            fs: self.inner.clone(),
+            file,
+            pos,
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut fs = self.inner.lock().unwrap();
+        fs.delay();
+        let file = fs.files.remove(from).ok_or_else(|| not_found(from))?;
+        fs.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut fs = self.inner.lock().unwrap();
+        fs.delay();
+        match fs.files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn lock_dir(&self, dir: &Path) -> io::Result<Box<dyn Send>> {
+        if !self.inner.lock().unwrap().locked.insert(dir.to_path_buf()) {
+            return Err(locked(dir));
+        }
+        Ok(Box::new(SimulatedLock {
+            fs: self.inner.clone(),
+            dir: dir.to_path_buf(),
+        }))
+    }
+}
+
+/// Releases a `SimulatedStorage` directory lock when dropped.
+struct SimulatedLock {
+    fs: Arc<Mutex<SimulatedFs>>,
+    dir: PathBuf,
+}
+
+impl Drop for SimulatedLock {
+    fn drop(&mut self) {
+        self.fs.lock().unwrap().locked.remove(&self.dir);
+    }
+}
+
+/// A reader on a `SimulatedStorage` file.
+struct SimulatedReader {
+    file: SimulatedFile,
+    pos: u64,
+}
+
+impl Read for SimulatedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.file.lock().unwrap();
+        let start = (self.pos as usize).min(file.data.len());
+        let len = buf.len().min(file.data.len() - start);
+        buf[..len].copy_from_slice(&file.data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for SimulatedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.lock().unwrap().data.len() as u64;
+        self.pos = seek_pos(self.pos, len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageReader for SimulatedReader {}
+
+/// An append-only writer on a `SimulatedStorage` file.
+struct SimulatedWriter {
+    fs: Arc<Mutex<SimulatedFs>>,
+    file: SimulatedFile,
+    pos: u64,
+}
+
+impl Write for SimulatedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut fs = self.fs.lock().unwrap();
+        fs.delay();
+        let len = if fs.short_writes && buf.len() > 1 && fs.next().is_multiple_of(4) {
+            1 + fs.next() as usize % (buf.len() - 1)
+        } else {
+            buf.len()
+        };
+
+        let mut file = self.file.lock().unwrap();
+        let start = file.data.len();
+        file.data.extend_from_slice(&buf[..len]);
+        file.unsynced.push(start..start + len);
+        self.pos = file.data.len() as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SimulatedWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.lock().unwrap().data.len() as u64;
+        self.pos = seek_pos(self.pos, len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageWriter for SimulatedWriter {
+    fn sync_data(&self) -> io::Result<()> {
+        self.fs.lock().unwrap().delay();
+        let mut file = self.file.lock().unwrap();
+        file.durable = file.data.clone();
+        file.unsynced.clear();
+        Ok(())
+    }
+
+    // durable at once, like the other metadata changes.
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.fs.lock().unwrap().delay();
+        let len = len as usize;
+        let mut file = self.file.lock().unwrap();
+        file.data.resize(len, 0);
+        file.durable.truncate(len);
+        for range in &mut file.unsynced {
+            range.end = range.end.min(len);
+            range.start = range.start.min(range.end);
+        }
+        file.unsynced.retain(|range| !range.is_empty());
+        Ok(())
+    }
+}
+
+fn seek_pos(current: u64, len: u64, pos: SeekFrom) -> io::Result<u64> {
+    let new_pos = match pos {
+        SeekFrom::Start(offset) => offset as i64,
+        SeekFrom::End(offset) => len as i64 + offset,
+        SeekFrom::Current(offset) => current as i64 + offset,
+    };
+    if new_pos < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative position",
+        ));
+    }
+    Ok(new_pos as u64)
+}
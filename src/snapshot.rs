@@ -0,0 +1,119 @@
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// First bytes of a snapshot archive.
+pub(crate) const SNAPSHOT_MAGIC: &[u8; 8] = b"KVSSNAP1";
+
+/// Codec of the record section of the archives written by this version.
+pub(crate) const SNAPSHOT_COMPRESSION: &str = "zlib";
+
+/// Describes the store a snapshot archive was exported from, see `KvStore::export_snapshot`.
+///
+/// Lays out as the magic, the length of the header as a little endian u64 and the header as
+/// JSON, so the archive can be recognised and described from its first bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SnapshotHeader {
+    /// Id of the exported store.
+    pub(crate) store_id: String,
+    /// Highest sequence number of the store at the export.
+    pub(crate) sequence: u64,
+    /// Export time in seconds since the Unix epoch.
+    pub(crate) exported_at: u64,
+    /// Codec the export stream after the header is compressed with.
+    pub(crate) compression: String,
+}
+
+impl SnapshotHeader {
+    pub(crate) fn new(store_id: String, sequence: u64) -> SnapshotHeader {
+        SnapshotHeader {
+            store_id,
+            sequence,
+            exported_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            compression: SNAPSHOT_COMPRESSION.to_owned(),
+        }
+    }
+
+    /// Writes the header at the start of an archive.
+    pub(crate) fn write(&self, writer: &mut impl Write) -> Result<()> {
+        let bytes = serde_json::to_vec(self)?;
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Reads the header at the start of an archive.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptedData` if the archive doesn't start with the magic, and
+    /// `KvsError::IncompatibleFormat` if its records are compressed with an unknown codec.
+    pub(crate) fn read(reader: &mut impl Read) -> Result<SnapshotHeader> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != *SNAPSHOT_MAGIC {
+            return Err(KvsError::CorruptedData);
+        }
+        let mut len_bytes = [0u8; 8];
+        reader.read_exact(&mut len_bytes)?;
+        let mut bytes = Vec::new();
+        reader.take(u64::from_le_bytes(len_bytes)).read_to_end(&mut bytes)?;
+        let header: SnapshotHeader = serde_json::from_slice(&bytes)?;
+        if header.compression != SNAPSHOT_COMPRESSION {
+            return Err(KvsError::IncompatibleFormat(format!(
+                "snapshot compressed with {}",
+                header.compression
+            )));
+        }
+        Ok(header)
+    }
+}
+
+/// Passes writes through to `writer`, keeping a CRC32 of every byte written.
+pub(crate) struct ChecksumWriter<W: Write> {
+    writer: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub(crate) fn new(writer: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            writer,
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// Returns the writer and the CRC32 of the bytes written through it.
+    pub(crate) fn finish(self) -> (W, u32) {
+        (self.writer, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Returns the CRC32 of everything `reader` yields.
+pub(crate) fn checksum(mut reader: impl Read) -> io::Result<u32> {
+    let mut hasher = Hasher::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(hasher.finalize()),
+            len => hasher.update(&buf[..len]),
+        }
+    }
+}
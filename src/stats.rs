@@ -0,0 +1,51 @@
+use serde::Serialize;
+
+use crate::Latencies;
+
+/// A point-in-time summary of a `KvStore`, see `KvStore::stats`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stats {
+    /// Persistent UUID of the store, see `KvStore::id`.
+    pub id: String,
+    /// Number of live keys.
+    pub keys: u64,
+    /// Bytes of the log records holding the live values.
+    pub live_bytes: u64,
+    /// Bytes of all log files on disk.
+    pub disk_bytes: u64,
+    /// Fraction of the disk bytes not holding live values, between 0 and 1.
+    pub garbage_ratio: f64,
+    /// Number of log files (generations).
+    pub segments: u64,
+    /// Highest sequence number written or replayed.
+    pub highest_sequence: u64,
+    /// Durations of gets, sets, removes and compactions since the store was opened.
+    pub latencies: Latencies,
+}
+
+/// Usage of the keys under a prefix, see `KvStore::prefix_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PrefixStats {
+    /// Number of live keys starting with the prefix.
+    pub keys: u64,
+    /// Bytes of the log records holding their values.
+    pub live_bytes: u64,
+}
+
+/// Size and contents of a single log file, see `KvStore::segment_stats`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SegmentStats {
+    /// Generation number of the log file.
+    pub generation: u64,
+    /// Size of the log file.
+    pub disk_bytes: u64,
+    /// Bytes of the records in this file holding live values.
+    pub live_bytes: u64,
+    /// Bytes of the file not holding live values, overwritten or removed records and the
+    /// footer of a sealed log.
+    pub garbage_bytes: u64,
+    /// Number of records in the file, live or not.
+    pub records: u64,
+    /// Number of live keys whose value is in this file.
+    pub live_keys: u64,
+}
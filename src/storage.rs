@@ -0,0 +1,493 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Name of the lock file `LocalStorage` keeps in a store directory.
+const LOCK_FILE: &str = "LOCK";
+
+/// The file operations a `KvStore` needs from the place it keeps its logs.
+///
+/// `LocalStorage` is the default. Other backends (in-memory for tests, object storage,
+/// encrypted filesystems) can be plugged in through `Options::storage`.
+pub trait Storage: Debug + Send + Sync {
+    /// Creates a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the files (not directories) directly inside a directory.
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Returns the length in bytes of a file.
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Opens an existing file for reading.
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>>;
+
+    /// Opens a file for appending, creating it if it does not exist.
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>>;
+
+    /// Renames a file, replacing the destination if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Deletes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Takes an exclusive lock on a store directory, held until the returned guard is dropped.
+    ///
+    /// Fails with `io::ErrorKind::WouldBlock` if the directory is locked already. Does not
+    /// lock anything by default.
+    fn lock_dir(&self, _dir: &Path) -> io::Result<Box<dyn Send>> {
+        Ok(Box::new(()))
+    }
+
+    /// Called once a file is complete and will never be written again.
+    ///
+    /// Readers opened before sealing are reopened afterwards. Does nothing by default.
+    fn seal(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file opened for reading through a `Storage`.
+pub trait StorageReader: Read + Seek + Send {
+    /// Returns the underlying OS file, if the backend has one.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+/// A file opened for appending through a `Storage`.
+pub trait StorageWriter: Write + Seek + Send {
+    /// Forces written data down to durable storage.
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Truncates or extends the file to `len` bytes.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Returns the underlying OS file, if the backend has one.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+/// `Storage` backed by the local filesystem through `std::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalStorage;
+
+impl Storage for LocalStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn lock_dir(&self, dir: &Path) -> io::Result<Box<dyn Send>> {
+        // an OS file lock goes away with the process, so a crash never leaves a stale lock.
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(file)),
+            Err(TryLockError::WouldBlock) => Err(locked(dir)),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+impl StorageReader for File {
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+impl StorageWriter for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// `Storage` keeping every file in memory, for tests and ephemeral caches.
+///
+/// Clones share the same files, so a store can be dropped and reopened on a clone to
+/// exercise replay without touching the disk.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    inner: Arc<Mutex<MemoryFs>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryFs {
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, MemoryFile>,
+    locked: BTreeSet<PathBuf>,
+}
+
+/// Releases a `MemoryStorage` directory lock when dropped.
+struct MemoryLock {
+    fs: Arc<Mutex<MemoryFs>>,
+    dir: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.fs.lock().unwrap().locked.remove(&self.dir);
+    }
+}
+
+/// File contents shared between every handle opened on the file.
+type MemoryFile = Arc<RwLock<Vec<u8>>>;
+
+impl MemoryStorage {
+    /// Creates an empty in-memory storage.
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn file(&self, path: &Path) -> io::Result<MemoryFile> {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut fs = self.inner.lock().unwrap();
+        for dir in path.ancestors() {
+            fs.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let fs = self.inner.lock().unwrap();
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(fs
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.file(path)?.read().unwrap().len() as u64)
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        Ok(Box::new(MemoryHandle {
+            data: self.file(path)?,
+            pos: 0,
+        }))
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        let mut fs = self.inner.lock().unwrap();
+        match path.parent() {
+            Some(dir) if fs.dirs.contains(dir) => {}
+            _ => return Err(not_found(path)),
+        }
+        let data = fs.files.entry(path.to_path_buf()).or_default().clone();
+        let pos = data.read().unwrap().len() as u64;
+        Ok(Box::new(MemoryHandle { data, pos }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut fs = self.inner.lock().unwrap();
+        let data = fs.files.remove(from).ok_or_else(|| not_found(from))?;
+        fs.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        // open handles keep the contents alive, like unlinking an open file.
+        match self.inner.lock().unwrap().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn lock_dir(&self, dir: &Path) -> io::Result<Box<dyn Send>> {
+        if !self.inner.lock().unwrap().locked.insert(dir.to_path_buf()) {
+            return Err(locked(dir));
+        }
+        Ok(Box::new(MemoryLock {
+            fs: self.inner.clone(),
+            dir: dir.to_path_buf(),
+        }))
+    }
+}
+
+/// A reader or append-only writer on a `MemoryStorage` file.
+struct MemoryHandle {
+    data: MemoryFile,
+    pos: u64,
+}
+
+impl Read for MemoryHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryHandle {
+    // writes always append, like a file opened in append mode.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.write().unwrap();
+        data.extend_from_slice(buf);
+        self.pos = data.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.read().unwrap().len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl StorageReader for MemoryHandle {}
+
+impl StorageWriter for MemoryHandle {
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// `Storage` reading through to `base` but keeping every change in memory, so a store can be
+/// opened, replayed and even written without changing its directory, see
+/// `KvStore::open_dry_run`.
+///
+/// A file is copied into memory the first time it is written. Removes and renames of files
+/// still in `base` are only recorded.
+#[derive(Debug)]
+pub(crate) struct OverlayStorage {
+    base: Arc<dyn Storage>,
+    memory: MemoryStorage,
+    state: Mutex<Overlay>,
+}
+
+#[derive(Debug, Default)]
+struct Overlay {
+    // files of `base` hidden by a remove or a rename.
+    removed: BTreeSet<PathBuf>,
+    // renamed files of `base`, by their new path.
+    moved: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl OverlayStorage {
+    pub(crate) fn new(base: Arc<dyn Storage>) -> OverlayStorage {
+        OverlayStorage {
+            base,
+            memory: MemoryStorage::new(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Returns the path in `base` the unchanged file at `path` is read from, if any.
+    fn base_path(&self, path: &Path) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        match state.moved.get(path) {
+            Some(from) => Some(from.clone()),
+            None if state.removed.contains(path) => None,
+            None => Some(path.to_path_buf()),
+        }
+    }
+
+    fn in_memory(&self, path: &Path) -> bool {
+        self.memory.file(path).is_ok()
+    }
+}
+
+impl Storage for OverlayStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.memory.create_dir_all(path)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = BTreeSet::new();
+        let base = self.base.list_files(dir);
+        let memory = self.memory.list_files(dir);
+        if let (Err(e), Err(_)) = (&base, &memory) {
+            return Err(io::Error::new(e.kind(), e.to_string()));
+        }
+        {
+            let state = self.state.lock().unwrap();
+            for path in base.unwrap_or_default() {
+                if !state.removed.contains(&path) {
+                    files.insert(path);
+                }
+            }
+            files.extend(state.moved.keys().filter(|path| path.parent() == Some(dir)).cloned());
+        }
+        files.extend(memory.unwrap_or_default());
+        Ok(files.into_iter().collect())
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        if self.in_memory(path) {
+            return self.memory.file_len(path);
+        }
+        match self.base_path(path) {
+            Some(base_path) => self.base.file_len(&base_path),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        if self.in_memory(path) {
+            return self.memory.open_reader(path);
+        }
+        match self.base_path(path) {
+            Some(base_path) => self.base.open_reader(&base_path),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        if !self.in_memory(path) {
+            if let Some(dir) = path.parent() {
+                self.memory.create_dir_all(dir)?;
+            }
+            let mut contents = Vec::new();
+            if let Some(base_path) = self.base_path(path) {
+                match self.base.open_reader(&base_path) {
+                    Ok(mut reader) => {
+                        reader.read_to_end(&mut contents)?;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.memory.open_writer(path)?.write_all(&contents)?;
+        }
+        self.memory.open_writer(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let _ = self.memory.remove_file(to);
+        if self.in_memory(from) {
+            self.memory.rename(from, to)?;
+            let mut state = self.state.lock().unwrap();
+            state.moved.remove(to);
+            state.removed.insert(to.to_path_buf());
+            return Ok(());
+        }
+        let base_path = self.base_path(from).ok_or_else(|| not_found(from))?;
+        self.base.file_len(&base_path)?;
+        let mut state = self.state.lock().unwrap();
+        state.moved.remove(from);
+        state.removed.insert(from.to_path_buf());
+        state.removed.insert(to.to_path_buf());
+        state.moved.insert(to.to_path_buf(), base_path);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let in_memory = self.memory.remove_file(path).is_ok();
+        let in_base = match self.base_path(path) {
+            Some(base_path) => self.base.file_len(&base_path).is_ok(),
+            None => false,
+        };
+        if !in_memory && !in_base {
+            return Err(not_found(path));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.moved.remove(path);
+        state.removed.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn lock_dir(&self, dir: &Path) -> io::Result<Box<dyn Send>> {
+        self.base.lock_dir(dir)
+    }
+}
+
+pub(crate) fn locked(dir: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        format!("{} is locked", dir.display()),
+    )
+}
+
+pub(crate) fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
@@ -0,0 +1,190 @@
+use std::cmp::max;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::listener::Listeners;
+use crate::{KvsError, Result, StorageWriter};
+
+/// Fsyncs the active log on a thread of its own, see `Options::background_sync`.
+///
+/// The store reports the sequence number of every flush, the thread syncs the logs written
+/// since the last sync once per interval and publishes the highest durable sequence number.
+/// Stops after a last sync when dropped.
+pub(crate) struct Syncer {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<SyncState>,
+    // signalled when the durable sequence number moves or the syncer stops.
+    changed: Condvar,
+}
+
+struct SyncState {
+    // handles on the logs written since the last sync, the active log last.
+    logs: Vec<Box<dyn StorageWriter>>,
+    // highest sequence number flushed to the logs.
+    flushed: u64,
+    // highest sequence number synced.
+    durable: u64,
+    // the error of the last sync, if it failed.
+    error: Option<String>,
+    // set when the store is dropped.
+    stopping: bool,
+    // set once the last sync is done.
+    stopped: bool,
+}
+
+impl Syncer {
+    /// Starts syncing the active log `log`, the writes up to `sequence` counting as durable.
+    pub(crate) fn start(
+        log: Box<dyn StorageWriter>,
+        sequence: u64,
+        interval: Duration,
+        listeners: Listeners,
+    ) -> io::Result<Syncer> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(SyncState {
+                logs: vec![log],
+                flushed: sequence,
+                durable: sequence,
+                error: None,
+                stopping: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread = thread::Builder::new().name("kvs-sync".to_owned()).spawn({
+            let shared = shared.clone();
+            move || run(&shared, interval, &listeners)
+        })?;
+        Ok(Syncer {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Records that the writes up to `sequence` were flushed to the logs.
+    pub(crate) fn flushed(&self, sequence: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.flushed = max(state.flushed, sequence);
+    }
+
+    /// Records that the writes up to `sequence` were synced by the store itself.
+    pub(crate) fn synced(&self, sequence: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.flushed = max(state.flushed, sequence);
+        state.durable = max(state.durable, sequence);
+        self.shared.changed.notify_all();
+    }
+
+    /// Adds the new active log after the store switched to it.
+    pub(crate) fn track(&self, log: Box<dyn StorageWriter>) {
+        self.shared.state.lock().unwrap().logs.push(log);
+    }
+
+    /// Returns the highest durable sequence number.
+    pub(crate) fn durable(&self) -> u64 {
+        self.shared.state.lock().unwrap().durable
+    }
+
+    pub(crate) fn watcher(&self) -> DurabilityWatcher {
+        DurabilityWatcher {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopping = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The loop of the sync thread.
+fn run(shared: &Shared, interval: Duration, listeners: &Listeners) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        let stopping = state.stopping;
+        if state.flushed > state.durable {
+            let flushed = state.flushed;
+            let mut logs = mem::take(&mut state.logs);
+            drop(state);
+            let synced = logs.iter().try_for_each(|log| log.sync_data());
+
+            state = shared.state.lock().unwrap();
+            match synced {
+                Ok(()) => {
+                    // the logs before the active one are done with.
+                    logs.drain(..logs.len().saturating_sub(1));
+                    state.durable = max(state.durable, flushed);
+                    state.error = None;
+                }
+                Err(e) => state.error = Some(e.to_string()),
+            }
+            // logs the store switched to meanwhile come after these.
+            state.logs.splice(0..0, logs);
+            shared.changed.notify_all();
+
+            if state.error.is_none() {
+                let durable = state.durable;
+                drop(state);
+                listeners.on_durable(durable);
+                state = shared.state.lock().unwrap();
+            }
+        }
+        if stopping {
+            break;
+        }
+        // stopping may have been set during the sync, its notification is gone then.
+        if !state.stopping {
+            state = shared.changed.wait_timeout(state, interval).unwrap().0;
+        }
+    }
+    state.stopped = true;
+    shared.changed.notify_all();
+}
+
+/// Waits for writes to become durable, see `KvStore::durability_watcher`.
+///
+/// Can be cloned and sent to other threads, for example to acknowledge a write to a client
+/// once it is durable without holding up the writes after it.
+#[derive(Clone)]
+pub struct DurabilityWatcher {
+    shared: Arc<Shared>,
+}
+
+impl DurabilityWatcher {
+    /// Returns the highest sequence number on durable storage.
+    pub fn durable_sequence(&self) -> u64 {
+        self.shared.state.lock().unwrap().durable
+    }
+
+    /// Blocks until every write up to sequence number `sequence` is on durable storage.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::IoError` with the error of the last sync if it failed, and
+    /// `KvsError::Cancelled` if the store was dropped before the writes became durable.
+    pub fn wait_for(&self, sequence: u64) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.durable < sequence {
+            if let Some(error) = &state.error {
+                return Err(io::Error::other(error.clone()).into());
+            }
+            if state.stopped {
+                return Err(KvsError::Cancelled);
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        Ok(())
+    }
+}
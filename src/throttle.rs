@@ -0,0 +1,33 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Paces a stream of IO to a byte rate, sleeping whenever it runs ahead.
+pub(crate) struct Throttle {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    /// Creates a throttle, `None` never sleeps.
+    pub(crate) fn new(bytes_per_sec: Option<u64>) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Accounts for `bytes` more bytes of IO, sleeping until they fit under the rate.
+    pub(crate) fn consume(&mut self, bytes: u64) {
+        let Some(bytes_per_sec) = self.bytes_per_sec else {
+            return;
+        };
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{self, Debug};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::{Storage, StorageReader, StorageWriter};
+
+/// A bucket of immutable objects addressed by key, such as an S3 bucket.
+///
+/// Keys are `/`-separated paths relative to the root of a `TieredStorage`.
+pub trait ObjectStore: Debug + Send + Sync {
+    /// Uploads an object, replacing any previous object with the same key.
+    fn put(&self, key: &str, data: &mut dyn Read) -> io::Result<()>;
+
+    /// Downloads an object into `dest`, returning the number of bytes written.
+    fn get(&self, key: &str, dest: &mut dyn Write) -> io::Result<u64>;
+
+    /// Returns the size of an object in bytes.
+    fn len(&self, key: &str) -> io::Result<u64>;
+
+    /// Lists the keys of every object.
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// Deletes an object.
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// `ObjectStore` keeping objects as files under a directory.
+///
+/// Useful for buckets mounted into the filesystem and as a stand-in for a real object
+/// store in tests.
+#[derive(Clone, Debug)]
+pub struct DirObjectStore {
+    dir: PathBuf,
+}
+
+impl DirObjectStore {
+    /// Creates an object store rooted at `dir`, creating the directory if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<DirObjectStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(DirObjectStore { dir })
+    }
+}
+
+impl ObjectStore for DirObjectStore {
+    fn put(&self, key: &str, data: &mut dyn Read) -> io::Result<()> {
+        let path = self.dir.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // upload under a temporary name so a torn upload never looks like an object.
+        let tmp = self.dir.join(format!("{}.upload", key));
+        io::copy(data, &mut File::create(&tmp)?)?;
+        fs::rename(tmp, path)
+    }
+
+    fn get(&self, key: &str, dest: &mut dyn Write) -> io::Result<u64> {
+        io::copy(&mut File::open(self.dir.join(key))?, dest)
+    }
+
+    fn len(&self, key: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.dir.join(key))?.len())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension() != Some("upload".as_ref()) {
+                    let key = path.strip_prefix(&self.dir).expect("listed under the root");
+                    keys.push(key_from_relative(key));
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(key))
+    }
+}
+
+/// Default for `TieredStorage::cache_budget`.
+const DEFAULT_CACHE_BUDGET: u64 = 256 * 1024 * 1024;
+
+/// `Storage` that archives sealed log files to an `ObjectStore`.
+///
+/// Active logs live in the local storage. Once the store seals a file (the output of a
+/// compaction) it is uploaded and the local copy dropped. Archived files are fetched back
+/// into the local storage the first time they are read and cached there, dropping the
+/// least recently read ones once the cache is over `cache_budget`.
+///
+/// Opening a store replays every generation, so it fetches every archived file once, but
+/// only keeps the last ones read around.
+#[derive(Clone, Debug)]
+pub struct TieredStorage {
+    local: Arc<dyn Storage>,
+    objects: Arc<dyn ObjectStore>,
+    // local paths under `root` map to object keys relative to it.
+    root: PathBuf,
+    // shared between clones, so every store on them stays within the budget.
+    cache: Arc<Mutex<FetchCache>>,
+    // signalled whenever a fetch finishes, successfully or not.
+    fetched: Arc<Condvar>,
+    cache_budget: u64,
+}
+
+impl TieredStorage {
+    /// Creates a tiered storage for files under `root`.
+    pub fn new(local: Arc<dyn Storage>, objects: Arc<dyn ObjectStore>, root: impl Into<PathBuf>) -> TieredStorage {
+        TieredStorage {
+            local,
+            objects,
+            root: root.into(),
+            cache: Arc::default(),
+            fetched: Arc::default(),
+            cache_budget: DEFAULT_CACHE_BUDGET,
+        }
+    }
+
+    /// Sets how many bytes of fetched archived files are kept in the local storage,
+    /// 256 MiB by default.
+    ///
+    /// The file read last is always kept, even if it is larger than the budget.
+    pub fn cache_budget(mut self, bytes: u64) -> TieredStorage {
+        self.cache_budget = bytes;
+        self
+    }
+
+    /// Drops the local copy of an archived file, it is fetched again on the next read.
+    pub fn evict(&self, path: &Path) -> io::Result<()> {
+        let key = self.key(path)?;
+        // never drop the only copy of a file.
+        self.objects.len(&key)?;
+        self.cache.lock().unwrap().remove(path);
+        match self.local.remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Returns whether a file has been archived to the object store.
+    pub fn is_archived(&self, path: &Path) -> io::Result<bool> {
+        match self.objects.len(&self.key(path)?) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn key(&self, path: &Path) -> io::Result<String> {
+        let relative = path.strip_prefix(&self.root).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside of {}", path.display(), self.root.display()),
+            )
+        })?;
+        Ok(key_from_relative(relative))
+    }
+
+    fn is_cached(&self, path: &Path) -> io::Result<bool> {
+        match self.local.file_len(path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns a reader on the local copy of an archived file, fetching it first unless it
+    /// is cached, and marks it as read last.
+    ///
+    /// The download runs without holding the cache, so reads of other files go on meanwhile.
+    /// Readers of a file being fetched wait for that fetch instead of starting their own.
+    fn cached(&self, path: &Path) -> io::Result<SharedReader> {
+        let mut cache = self.cache.lock().unwrap();
+        loop {
+            if let Some(reader) = cache.touch(path) {
+                return Ok(reader);
+            }
+            if !cache.fetching.contains(path) {
+                break;
+            }
+            cache = self.fetched.wait(cache).unwrap();
+        }
+        cache.fetching.insert(path.to_path_buf());
+        drop(cache);
+
+        let fetched = self.fetch(path).and_then(|()| {
+            let reader = self.local.open_reader(path)?;
+            Ok((reader, self.local.file_len(path)?))
+        });
+        let mut cache = self.cache.lock().unwrap();
+        cache.fetching.remove(path);
+        // waiters retry on a failed fetch.
+        self.fetched.notify_all();
+        let (reader, len) = fetched?;
+        let reader = Arc::new(Mutex::new(reader));
+        cache.files.push_back(CachedFile { path: path.to_path_buf(), len, reader: reader.clone() });
+        cache.bytes += cache.files.back().expect("pushed above").len;
+        while cache.bytes > self.cache_budget && cache.files.len() > 1 {
+            let file = cache.files.pop_front().expect("more than one file");
+            cache.bytes -= file.len;
+            // closes the reader, readers of the file fetch it again on their next read.
+            drop(file.reader);
+            match self.local.remove_file(&file.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(reader)
+    }
+
+    /// Downloads an archived file into the local storage unless it is cached already.
+    fn fetch(&self, path: &Path) -> io::Result<()> {
+        if self.is_cached(path)? {
+            return Ok(());
+        }
+        let key = self.key(path)?;
+        let mut fetch_path = path.as_os_str().to_owned();
+        fetch_path.push(".fetch");
+        let fetch_path = PathBuf::from(fetch_path);
+        // writers append, so clear out leftovers of an interrupted fetch.
+        match self.local.remove_file(&fetch_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        let mut writer = self.local.open_writer(&fetch_path)?;
+        self.objects.get(&key, &mut writer)?;
+        writer.flush()?;
+        writer.sync_data()?;
+        drop(writer);
+        self.local.rename(&fetch_path, path)
+    }
+}
+
+impl Storage for TieredStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.local.create_dir_all(path)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = self.local.list_files(dir)?;
+        for key in self.objects.list()? {
+            let path = self.root.join(&key);
+            if path.parent() == Some(dir) && !files.contains(&path) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        match self.local.file_len(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.objects.len(&self.key(path)?),
+            res => res,
+        }
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        // fetched files may be dropped from the cache, so they are only read through it.
+        let fetched = self.cache.lock().unwrap().len(path);
+        let len = match fetched {
+            Some(len) => len,
+            None if self.is_cached(path)? => return self.local.open_reader(path),
+            // fail early on files that don't exist in either tier.
+            None => self.objects.len(&self.key(path)?)?,
+        };
+        Ok(Box::new(FetchOnReadReader {
+            storage: self.clone(),
+            path: path.to_path_buf(),
+            len,
+            pos: 0,
+        }))
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        self.local.open_writer(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.cache.lock().unwrap().remove(from);
+        self.local.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.cache.lock().unwrap().remove(path);
+        // active logs were never uploaded, only sealed files have an object to delete.
+        let archived = self.is_archived(path)?;
+        if archived {
+            self.objects.delete(&self.key(path)?)?;
+        }
+        match self.local.remove_file(path) {
+            Err(e) if archived && e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn lock_dir(&self, dir: &Path) -> io::Result<Box<dyn Send>> {
+        self.local.lock_dir(dir)
+    }
+
+    fn seal(&self, path: &Path) -> io::Result<()> {
+        let mut reader = self.local.open_reader(path)?;
+        self.objects.put(&self.key(path)?, &mut reader)?;
+        drop(reader);
+        self.local.remove_file(path)
+    }
+}
+
+/// A reader on a fetched file, shared by every `FetchOnReadReader` of the file.
+type SharedReader = Arc<Mutex<Box<dyn StorageReader>>>;
+
+/// The archived files fetched into the local storage, least recently read first.
+#[derive(Default)]
+struct FetchCache {
+    files: VecDeque<CachedFile>,
+    bytes: u64,
+    // files being downloaded by some reader.
+    fetching: HashSet<PathBuf>,
+}
+
+struct CachedFile {
+    path: PathBuf,
+    len: u64,
+    reader: SharedReader,
+}
+
+impl FetchCache {
+    /// Returns the reader on a cached file and moves it to the back.
+    fn touch(&mut self, path: &Path) -> Option<SharedReader> {
+        let index = self.files.iter().position(|file| file.path == path)?;
+        let file = self.files.remove(index).expect("found above");
+        let reader = file.reader.clone();
+        self.files.push_back(file);
+        Some(reader)
+    }
+
+    fn len(&self, path: &Path) -> Option<u64> {
+        self.files.iter().find(|file| file.path == path).map(|file| file.len)
+    }
+
+    /// Forgets a file, it is left in the local storage.
+    fn remove(&mut self, path: &Path) {
+        if let Some(index) = self.files.iter().position(|file| file.path == path) {
+            let file = self.files.remove(index).expect("found above");
+            self.bytes -= file.len;
+        }
+    }
+}
+
+impl Debug for FetchCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FetchCache")
+            .field("files", &self.files.iter().map(|file| &file.path).collect::<Vec<_>>())
+            .field("bytes", &self.bytes)
+            .field("fetching", &self.fetching)
+            .finish()
+    }
+}
+
+/// Reader over an archived file that fetches it into the local storage on first read.
+///
+/// Opening readers happens for every generation, so seeks are tracked locally and only
+/// an actual read triggers the download. The local copy is read through the cache, so
+/// a file dropped from it is fetched again and its handle is closed in the meantime.
+struct FetchOnReadReader {
+    storage: TieredStorage,
+    path: PathBuf,
+    len: u64,
+    pos: u64,
+}
+
+impl Read for FetchOnReadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = self.storage.cached(&self.path)?;
+        let mut inner = reader.lock().unwrap();
+        inner.seek(SeekFrom::Start(self.pos))?;
+        let len = inner.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for FetchOnReadReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl StorageReader for FetchOnReadReader {}
+
+fn key_from_relative(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
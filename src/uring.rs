@@ -0,0 +1,456 @@
+//! Batched positional IO through io_uring.
+//!
+//! Only compiled on Linux with the `io-uring` feature. Replay and compaction use it to keep
+//! many reads in flight at once instead of paying one synchronous syscall per record, and
+//! log writers queue their appends and submit them together on flush; the `std::fs` path
+//! stays the portable default.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::StorageWriter;
+
+/// Maximum number of operations kept in flight on the ring.
+const QUEUE_DEPTH: u32 = 64;
+
+/// Size of each read issued by `ChunkedReader`.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Number of chunks `ChunkedReader` keeps in flight, so it buffers at most 4 MiB.
+const READ_AHEAD: usize = 16;
+
+/// Offset telling the kernel to use and advance the file position, like `write(2)`.
+///
+/// Needs `IORING_FEAT_RW_CUR_POS`, see `RingWriter::wrap`.
+const CURRENT_POSITION: u64 = u64::MAX;
+
+/// A single positional read into a caller-owned buffer.
+struct Op {
+    offset: u64,
+    ptr: *mut u8,
+    len: usize,
+    // bytes already transferred, short transfers are resubmitted for the remainder.
+    done: usize,
+}
+
+/// Reads a file front to back, keeping the next `READ_AHEAD` chunks in flight.
+///
+/// Replay reads each log sequentially, so this keeps the disk busy like slurping the whole
+/// file would while buffering a bounded number of chunks. A seek drops the read-ahead.
+pub(crate) struct ChunkedReader<'a> {
+    file: &'a File,
+    ring: IoUring,
+    len: u64,
+    pos: u64,
+    // offset of the first byte not requested yet.
+    next_offset: u64,
+    // requested chunks in file order, `pos` is in the front one.
+    chunks: VecDeque<Chunk>,
+    in_flight: usize,
+    // buffers of consumed chunks, reused by the next ones.
+    free: Vec<Vec<u8>>,
+}
+
+struct Chunk {
+    offset: u64,
+    buf: Vec<u8>,
+    // bytes read so far, short reads are resubmitted for the remainder.
+    filled: usize,
+    in_flight: bool,
+    error: Option<io::Error>,
+}
+
+impl<'a> ChunkedReader<'a> {
+    pub(crate) fn new(file: &'a File) -> io::Result<ChunkedReader<'a>> {
+        Ok(ChunkedReader {
+            file,
+            ring: IoUring::new(QUEUE_DEPTH)?,
+            len: file.metadata()?.len(),
+            pos: 0,
+            next_offset: 0,
+            chunks: VecDeque::new(),
+            in_flight: 0,
+            free: Vec::new(),
+        })
+    }
+
+    /// Requests chunks until `READ_AHEAD` are queued or the end of the file is reached.
+    fn fill(&mut self) -> io::Result<()> {
+        while self.chunks.len() < READ_AHEAD && self.next_offset < self.len {
+            let len = (self.len - self.next_offset).min(CHUNK_SIZE as u64) as usize;
+            let mut buf = self.free.pop().unwrap_or_default();
+            buf.resize(len, 0);
+            self.chunks.push_back(Chunk {
+                offset: self.next_offset,
+                buf,
+                filled: 0,
+                in_flight: false,
+                error: None,
+            });
+            self.next_offset += len as u64;
+            let index = self.chunks.len() - 1;
+            self.submit(index)?;
+        }
+        Ok(())
+    }
+
+    /// Submits a read of the rest of a queued chunk, tagged with the chunk's offset.
+    fn submit(&mut self, index: usize) -> io::Result<()> {
+        let chunk = &mut self.chunks[index];
+        // Safety: `filled < buf.len()`, so the pointer stays within the chunk's buffer.
+        let ptr = unsafe { chunk.buf.as_mut_ptr().add(chunk.filled) };
+        let len = (chunk.buf.len() - chunk.filled) as u32;
+        let entry = opcode::Read::new(types::Fd(self.file.as_raw_fd()), ptr, len)
+            .offset(chunk.offset + chunk.filled as u64)
+            .build()
+            .user_data(chunk.offset);
+        // Safety: the buffer is only dropped or reused once its read completed.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        chunk.in_flight = true;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Waits for at least one read to complete and records the results.
+    fn reap(&mut self) -> io::Result<()> {
+        self.ring.submit_and_wait(1)?;
+        let completed: Vec<(u64, i32)> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (offset, res) in completed {
+            self.in_flight -= 1;
+            let index = self
+                .chunks
+                .iter()
+                .position(|chunk| chunk.offset == offset)
+                .expect("completed reads belong to a queued chunk");
+            let chunk = &mut self.chunks[index];
+            chunk.in_flight = false;
+            match res {
+                res if res < 0 => {
+                    let err = io::Error::from_raw_os_error(-res);
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        self.submit(index)?;
+                    } else {
+                        chunk.error = Some(err);
+                    }
+                }
+                0 => chunk.error = Some(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n => {
+                    chunk.filled += n as usize;
+                    if chunk.filled < chunk.buf.len() {
+                        self.submit(index)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for every read in flight and drops the queued chunks.
+    fn drain(&mut self) -> io::Result<()> {
+        while self.in_flight > 0 {
+            self.reap()?;
+        }
+        let chunks = std::mem::take(&mut self.chunks);
+        self.free.extend(chunks.into_iter().map(|chunk| chunk.buf));
+        Ok(())
+    }
+}
+
+impl Read for ChunkedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        self.fill()?;
+        while self.chunks.front().expect("filled above").in_flight {
+            self.reap()?;
+        }
+        let chunk = self.chunks.front_mut().expect("filled above");
+        if let Some(err) = chunk.error.take() {
+            // the chunk is read again on the next call.
+            self.drain()?;
+            self.next_offset = self.pos;
+            return Err(err);
+        }
+        let start = (self.pos - chunk.offset) as usize;
+        let len = buf.len().min(chunk.filled - start);
+        buf[..len].copy_from_slice(&chunk.buf[start..start + len]);
+        self.pos += len as u64;
+        if start + len == chunk.buf.len() {
+            let chunk = self.chunks.pop_front().expect("filled above");
+            self.free.push(chunk.buf);
+        }
+        Ok(len)
+    }
+}
+
+impl Seek for ChunkedReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        if new_pos as u64 != self.pos {
+            self.drain()?;
+            self.pos = new_pos as u64;
+            self.next_offset = self.pos;
+        }
+        Ok(self.pos)
+    }
+}
+
+impl Drop for ChunkedReader<'_> {
+    // the kernel must never write into a buffer after it is freed.
+    fn drop(&mut self) {
+        while self.in_flight > 0 {
+            if self.reap().is_err() {
+                // can't wait for the reads, leak their buffers instead.
+                std::mem::forget(std::mem::take(&mut self.chunks));
+                return;
+            }
+        }
+    }
+}
+
+/// A log writer queueing its writes and submitting them to its own ring on flush.
+///
+/// The queued writes go out as one linked chain at the file position, once `QUEUE_DEPTH` are
+/// queued or on flush, so they land in order like with `std::fs` while costing one wait per
+/// batch. Short writes are resubmitted for the remainder. Writes still queued when it is
+/// dropped are submitted then.
+pub(crate) struct RingWriter {
+    inner: Box<dyn StorageWriter>,
+    ring: IoUring,
+    // copies of the writes not submitted yet, in order.
+    queued: Vec<Vec<u8>>,
+    // buffers of submitted writes, reused by the next ones.
+    free: Vec<Vec<u8>>,
+}
+
+impl RingWriter {
+    /// Wraps a writer on a local file, returning it as is if it has none or the ring can't
+    /// be set up or can't write at the file position.
+    pub(crate) fn wrap(inner: Box<dyn StorageWriter>) -> Box<dyn StorageWriter> {
+        if inner.as_file().is_none() {
+            return inner;
+        }
+        match IoUring::new(QUEUE_DEPTH) {
+            Ok(ring) if ring.params().is_feature_rw_cur_pos() => Box::new(RingWriter {
+                inner,
+                ring,
+                queued: Vec::new(),
+                free: Vec::new(),
+            }),
+            _ => inner,
+        }
+    }
+
+    /// Submits the queued writes and waits for all of them.
+    ///
+    /// A short or failed write cancels the rest of the chain, which is resubmitted from the
+    /// remainder of that write on. On error the queued writes are dropped.
+    fn submit_queued(&mut self) -> io::Result<()> {
+        let fd = types::Fd(self.inner.as_file().expect("checked by wrap").as_raw_fd());
+        // bytes of each queued write on the file already.
+        let mut done = vec![0; self.queued.len()];
+        let mut next = 0;
+        let mut result = Ok(());
+        while next < self.queued.len() {
+            let count = self.queued.len() - next;
+            for (i, buf) in self.queued.iter().enumerate().skip(next) {
+                let rest = &buf[done[i]..];
+                let len = rest.len().min(u32::MAX as usize) as u32;
+                let mut entry = opcode::Write::new(fd, rest.as_ptr(), len)
+                    .offset(CURRENT_POSITION)
+                    .build()
+                    .user_data(i as u64);
+                if i + 1 < self.queued.len() {
+                    entry = entry.flags(squeue::Flags::IO_LINK);
+                }
+                // Safety: the queued buffers outlive the writes, they are reaped below.
+                unsafe { self.ring.submission().push(&entry) }
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            }
+
+            let mut errors = vec![None; count];
+            let mut reaped = 0;
+            while reaped < count {
+                match self.ring.submit_and_wait(count - reaped) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        // can't wait for the writes, leak their buffers instead.
+                        std::mem::forget(std::mem::take(&mut self.queued));
+                        return Err(e);
+                    }
+                    Ok(_) => {}
+                }
+                for cqe in self.ring.completion() {
+                    reaped += 1;
+                    let i = cqe.user_data() as usize;
+                    match cqe.result() {
+                        res if res < 0 => errors[i - next] = Some(-res),
+                        n => done[i] += n as usize,
+                    }
+                }
+            }
+
+            // the chain stopped at the first write that didn't complete, if any.
+            let Some(stopped) = (next..self.queued.len()).find(|&i| done[i] < self.queued[i].len())
+            else {
+                break;
+            };
+            match errors[stopped - next].map(io::Error::from_raw_os_error) {
+                Some(err) if err.kind() != io::ErrorKind::Interrupted => {
+                    result = Err(err);
+                    break;
+                }
+                _ => next = stopped,
+            }
+        }
+        self.free.append(&mut self.queued);
+        result
+    }
+}
+
+impl Write for RingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.queued.len() == QUEUE_DEPTH as usize {
+            self.submit_queued()?;
+        }
+        let mut queued = self.free.pop().unwrap_or_default();
+        queued.clear();
+        queued.extend_from_slice(buf);
+        self.queued.push(queued);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.submit_queued()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for RingWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // the queued writes move the file position.
+        self.submit_queued()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageWriter for RingWriter {
+    fn sync_data(&self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        self.inner.as_file()
+    }
+}
+
+impl Drop for RingWriter {
+    fn drop(&mut self) {
+        let _ = self.submit_queued();
+    }
+}
+
+/// Reads every `(offset, len)` extent of the file, returning the buffers in the same order.
+pub(crate) fn read_extents(file: &File, extents: &[(u64, usize)]) -> io::Result<Vec<Vec<u8>>> {
+    let mut bufs: Vec<Vec<u8>> = extents.iter().map(|&(_, len)| vec![0u8; len]).collect();
+    let mut ops: Vec<Op> = bufs
+        .iter_mut()
+        .zip(extents)
+        .map(|(buf, &(offset, len))| Op {
+            offset,
+            ptr: buf.as_mut_ptr(),
+            len,
+            done: 0,
+        })
+        .collect();
+    run(file, &mut ops)?;
+    Ok(bufs)
+}
+
+/// Drives all reads to completion, keeping up to `QUEUE_DEPTH` of them in flight.
+///
+/// On error no new operations are submitted, but the ones already in flight are still
+/// reaped before returning so the kernel never touches a buffer after it is freed.
+fn run(file: &File, ops: &mut [Op]) -> io::Result<()> {
+    let mut ring = IoUring::new(QUEUE_DEPTH)?;
+    let fd = types::Fd(file.as_raw_fd());
+
+    let mut pending: VecDeque<usize> = (0..ops.len()).filter(|&i| ops[i].len > 0).collect();
+    let mut in_flight = 0;
+    let mut error = None;
+
+    while in_flight > 0 || (error.is_none() && !pending.is_empty()) {
+        while error.is_none() && in_flight < QUEUE_DEPTH as usize {
+            let Some(i) = pending.pop_front() else { break };
+            let op = &ops[i];
+            // Safety: `done < len`, so the pointer stays within the caller's buffer.
+            let ptr = unsafe { op.ptr.add(op.done) };
+            let len = (op.len - op.done).min(u32::MAX as usize) as u32;
+            let offset = op.offset + op.done as u64;
+            let entry = opcode::Read::new(fd, ptr, len).offset(offset).build();
+            // Safety: the buffer outlives the ring, every submitted entry is reaped below.
+            unsafe { ring.submission().push(&entry.user_data(i as u64)) }
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            in_flight += 1;
+        }
+
+        ring.submit_and_wait(1)?;
+
+        let completed: Vec<(usize, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+            .collect();
+        for (i, res) in completed {
+            in_flight -= 1;
+            let op = &mut ops[i];
+            match res {
+                res if res < 0 => {
+                    let err = io::Error::from_raw_os_error(-res);
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        pending.push_back(i);
+                    } else {
+                        error.get_or_insert(err);
+                    }
+                }
+                0 => {
+                    error.get_or_insert(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                n => {
+                    op.done += n as usize;
+                    if op.done < op.len {
+                        pending.push_back(i);
+                    }
+                }
+            }
+        }
+    }
+
+    match error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,100 @@
+//! Crash tests, run with `cargo test --features failpoints --test crash`.
+#![cfg(feature = "failpoints")]
+
+use fail::FailScenario;
+use kvs_project::{KvStore, KvsError, Options, SizeThreshold};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// Every failpoint of the crate.
+const FAILPOINTS: [&str; 7] = [
+    "kv::flush",
+    "meta::rename",
+    "placement::seal",
+    "compaction::start",
+    "compaction::copied",
+    "compaction::sealed",
+    "compaction::remove_stale",
+];
+
+fn open(path: &Path) -> KvStore {
+    let options = Options::new()
+        // compact every few writes, so the compaction failpoints are reached.
+        .compaction_policy(Arc::new(SizeThreshold(4 * 1024)))
+        .descriptive_segment_names(true);
+    KvStore::open_with(path, options).expect("open must succeed after a crash")
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+// Kill the store when a random failpoint triggers for a random time, then reopen it: open
+// succeeds and every acknowledged write is there. The write that failed may or may not be.
+#[test]
+fn crash_at_failpoints_keeps_acknowledged_writes() {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().unwrap();
+    let mut acknowledged: BTreeMap<String, String> = BTreeMap::new();
+    let mut random = 0x853c_49e6_748f_ea9b;
+
+    for round in 0..100 {
+        let mut store = open(temp_dir.path());
+        let failpoint = FAILPOINTS[xorshift(&mut random) as usize % FAILPOINTS.len()];
+        let skip = xorshift(&mut random) % 50;
+        fail::cfg(failpoint, &format!("{}*off->return", skip)).unwrap();
+
+        // the write that failed, with the value the key has if it went through.
+        let mut in_doubt = None;
+        for i in 0..200 {
+            let key = format!("key{}", xorshift(&mut random) % 64);
+            if xorshift(&mut random).is_multiple_of(5) {
+                match store.remove_v2(key.clone()) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {
+                        acknowledged.remove(&key);
+                    }
+                    Err(_) => {
+                        in_doubt = Some((key, None));
+                        break;
+                    }
+                }
+            } else {
+                let value = format!("value{}-{}", round, i);
+                match store.set_v2(key.clone(), value.clone()) {
+                    Ok(()) => {
+                        acknowledged.insert(key, value);
+                    }
+                    Err(_) => {
+                        in_doubt = Some((key, Some(value)));
+                        break;
+                    }
+                }
+            }
+        }
+        store.kill().unwrap();
+        fail::remove(failpoint);
+
+        let mut store = open(temp_dir.path());
+        if let Some((key, value)) = in_doubt {
+            if store.get_v2(key.clone()).unwrap() == value {
+                match value {
+                    Some(value) => acknowledged.insert(key, value),
+                    None => acknowledged.remove(&key),
+                };
+            }
+        }
+        let found: BTreeMap<String, String> =
+            store.scan(..).collect::<kvs_project::Result<_>>().unwrap();
+        assert_eq!(
+            found, acknowledged,
+            "round {} lost writes after failpoint {} skipped {} times",
+            round, failpoint, skip
+        );
+    }
+    scenario.teardown();
+}
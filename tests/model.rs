@@ -0,0 +1,108 @@
+//! Model tests: random operation sequences applied to a store and to a `HashMap` must agree.
+
+use kvs_project::{CompactionTrigger, KvStore, KvsError, Options, Result, SimulatedStorage};
+use proptest::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// An operation on both the store and the model, keys are picked from a small set so
+/// operations hit the same keys often.
+#[derive(Clone, Debug)]
+enum Op {
+    Set(u8, String),
+    Remove(u8),
+    Rename(u8, u8),
+    Get(u8),
+    /// `KvStore::compact`.
+    Compact,
+    /// Makes the next write compact.
+    TriggerCompaction,
+    /// Drops the store and opens it again.
+    Reopen,
+    /// Drops the store, crashes the storage and opens it again.
+    Crash,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..16u8, "[a-z]{0,12}").prop_map(|(key, value)| Op::Set(key, value)),
+        2 => (0..16u8).prop_map(Op::Remove),
+        1 => (0..16u8, 0..16u8).prop_map(|(old, new)| Op::Rename(old, new)),
+        2 => (0..16u8).prop_map(Op::Get),
+        1 => Just(Op::Compact),
+        1 => Just(Op::TriggerCompaction),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Crash),
+    ]
+}
+
+fn key(key: u8) -> String {
+    format!("key{}", key)
+}
+
+/// Applies `ops` to a store on simulated storage and to a map, checking they agree after
+/// every operation.
+fn check(seed: u64, ops: Vec<Op>) -> Result<()> {
+    let storage = SimulatedStorage::new(seed).short_writes(true);
+    let trigger = CompactionTrigger::new();
+    let open = || {
+        let options = Options::new()
+            .storage(Arc::new(storage.clone()))
+            .compaction_policy(Arc::new(trigger.clone()))
+            // logs of earlier opens are never synced again, sync them when dropped.
+            .sync_on_drop(true);
+        KvStore::open_with("/model", options)
+    };
+    let mut store = open()?;
+    let mut model: HashMap<String, String> = HashMap::new();
+
+    for op in ops {
+        match op {
+            Op::Set(k, value) => {
+                store.set_v2(key(k), value.clone())?;
+                model.insert(key(k), value);
+            }
+            Op::Remove(k) => match store.remove_v2(key(k)) {
+                Ok(()) => assert!(model.remove(&key(k)).is_some()),
+                Err(KvsError::KeyNotFound) => assert!(!model.contains_key(&key(k))),
+                Err(e) => return Err(e),
+            },
+            Op::Rename(old, new) => match store.rename(key(old), key(new)) {
+                Ok(()) => {
+                    let value = model.remove(&key(old)).expect("renamed a missing key");
+                    model.insert(key(new), value);
+                }
+                Err(KvsError::KeyNotFound) => assert!(!model.contains_key(&key(old))),
+                Err(e) => return Err(e),
+            },
+            Op::Get(k) => assert_eq!(store.get_v2(key(k))?, model.get(&key(k)).cloned()),
+            Op::Compact => store.compact()?,
+            Op::TriggerCompaction => trigger.request(),
+            Op::Reopen => {
+                drop(store);
+                store = open()?;
+            }
+            Op::Crash => {
+                drop(store);
+                storage.crash();
+                store = open()?;
+            }
+        }
+
+        let mut expected: Vec<(String, String)> = model.clone().into_iter().collect();
+        expected.sort();
+        assert_eq!(store.scan(..).collect::<Result<Vec<_>>>()?, expected);
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // The store should hold what the model holds after any sequence of operations, reopens,
+    // compactions and crashes.
+    #[test]
+    fn store_matches_model(seed in any::<u64>(), ops in prop::collection::vec(op(), 1..100)) {
+        check(seed, ops).unwrap();
+    }
+}
@@ -1,8 +1,21 @@
 use assert_cmd::prelude::*;
-use kvs_project::{KvStore, KvsError, Result};
+use kvs_project::{
+    CancellationToken, Change, ChecksumVerification, CompactionHandle, Config, DiffEntry, DirObjectStore, Entry,
+    EventListener, GarbageRatio, KeyEncoding, KvStore, KvsError, LocalStorage, MemoryStorage,
+    NeverCompact, ObjectStore, Operation, Options, PrefixStats, Progress, RecoveryReport, Result,
+    SecondaryIndex, SequenceGap, SimulatedStorage, SizeThreshold, Storage, StorageReader,
+    StorageWriter, TieredStorage, TimeWindow, WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -177,23 +190,35 @@ fn cli_invalid_subcommand() {
         .failure();
 }
 
+/// Stores the basic tests run against: one on the local filesystem in `temp_dir` and one on
+/// `MemoryStorage`, each as the path to open it at and the storage to open it with.
+fn backends(temp_dir: &TempDir) -> [(PathBuf, Arc<dyn Storage>); 2] {
+    [
+        (temp_dir.path().to_path_buf(), Arc::new(LocalStorage)),
+        (PathBuf::from("/kvs"), Arc::new(MemoryStorage::new())),
+    ]
+}
+
 // Should get previously stored value.
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for (path, storage) in backends(&temp_dir) {
+        let options = Options::new().storage(storage);
+        let mut store = KvStore::open_with(&path, options.clone())?;
 
-    store.set_v2("key1".to_owned(), "value1".to_owned())?;
-    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+        store.set_v2("key1".to_owned(), "value1".to_owned())?;
+        store.set_v2("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
 
-    // Open from disk again and check persistent data.
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
-    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = KvStore::open_with(&path, options)?;
+        assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+    }
 
     Ok(())
 }
@@ -202,19 +227,22 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for (path, storage) in backends(&temp_dir) {
+        let options = Options::new().storage(storage);
+        let mut store = KvStore::open_with(&path, options.clone())?;
 
-    store.set_v2("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
-    store.set_v2("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get_v2("key1".to_owned())?, Some("value2".to_owned()));
+        store.set_v2("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+        store.set_v2("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get_v2("key1".to_owned())?, Some("value2".to_owned()));
 
-    // Open from disk again and check persistent data.
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
-    assert_eq!(store.get_v2("key1".to_owned())?, Some("value2".to_owned()));
-    store.set_v2("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get_v2("key1".to_owned())?, Some("value3".to_owned()));
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = KvStore::open_with(&path, options)?;
+        assert_eq!(store.get_v2("key1".to_owned())?, Some("value2".to_owned()));
+        store.set_v2("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get_v2("key1".to_owned())?, Some("value3".to_owned()));
+    }
 
     Ok(())
 }
@@ -223,15 +251,18 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for (path, storage) in backends(&temp_dir) {
+        let options = Options::new().storage(storage);
+        let mut store = KvStore::open_with(&path, options.clone())?;
 
-    store.set_v2("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get_v2("key2".to_owned())?, None);
+        store.set_v2("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get_v2("key2".to_owned())?, None);
 
-    // Open from disk again and check persistent data.
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
-    assert_eq!(store.get_v2("key2".to_owned())?, None);
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = KvStore::open_with(&path, options)?;
+        assert_eq!(store.get_v2("key2".to_owned())?, None);
+    }
 
     Ok(())
 }
@@ -239,65 +270,63 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
-    assert!(store.remove_v2("key1".to_owned()).is_err());
+    for (path, storage) in backends(&temp_dir) {
+        let mut store = KvStore::open_with(&path, Options::new().storage(storage))?;
+        assert!(store.remove_v2("key1".to_owned()).is_err());
+    }
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
-    store.set_v2("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove_v2("key1".to_owned()).is_ok());
-    assert_eq!(store.get_v2("key1".to_owned())?, None);
+    for (path, storage) in backends(&temp_dir) {
+        let mut store = KvStore::open_with(&path, Options::new().storage(storage))?;
+        store.set_v2("key1".to_owned(), "value1".to_owned())?;
+        assert!(store.remove_v2("key1".to_owned()).is_ok());
+        assert_eq!(store.get_v2("key1".to_owned())?, None);
+    }
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
+// Insert data until total size of the log files decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
-
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
-
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set_v2(key, value)?;
-        }
-
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+    'backends: for (path, storage) in backends(&temp_dir) {
+        let options = Options::new().storage(storage);
+        let mut store = KvStore::open_with(&path, options.clone())?;
+
+        let mut current_size = store.stats()?.disk_bytes;
+        for iter in 0..1000 {
+            for key_id in 0..1000 {
+                let key = format!("key{}", key_id);
+                let value = format!("{}", iter);
+                store.set_v2(key, value)?;
+            }
+
+            let new_size = store.stats()?.disk_bytes;
+            if new_size > current_size {
+                current_size = new_size;
+                continue;
+            }
+            // Compaction triggered.
+
+            drop(store);
+            // reopen and check content.
+            let mut store = KvStore::open_with(&path, options)?;
+            for key_id in 0..1000 {
+                let key = format!("key{}", key_id);
+                assert_eq!(store.get_v2(key)?, Some(format!("{}", iter)));
+            }
+            continue 'backends;
         }
-        // Compaction triggered.
 
-        drop(store);
-        // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path(), None, None)?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get_v2(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
+        panic!("No compaction detected on {:?}", path);
     }
 
-    panic!("No compaction detected");
+    Ok(())
 }
 
 #[test]
@@ -399,33 +428,2978 @@ fn test_high_throughput_writes() -> Result<()> {
 #[test]
 fn test_checksum_verification() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for (path, storage) in backends(&temp_dir) {
+        let options = Options::new().storage(storage.clone());
+        let mut store = KvStore::open_with(&path, options.clone())?;
+
+        // Write some data
+        store.set_v2("key1".to_owned(), "value1".to_owned())?;
+
+        // Close the store
+        drop(store);
+
+        // Corrupt the value in the log file, the length prefix stays intact. Writers only
+        // append, so the log is written anew.
+        let log_path = path.join("1.log");
+        let mut content = Vec::new();
+        storage.open_reader(&log_path)?.read_to_end(&mut content)?;
+        let at = content.windows(6).position(|window| window == b"value1").unwrap();
+        content[at + 5] = b'2';
+        storage.remove_file(&log_path)?;
+        storage.open_writer(&log_path)?.write_all(&content)?;
+
+        // Replay verifies every record, so opening should detect the corruption.
+        assert!(matches!(KvStore::open_with(&path, options), Err(KvsError::CorruptedData)));
+    }
+
+    Ok(())
+}
+
+// Gets should verify checksums as configured, returning flipped bits as they are when they
+// skip the check.
+#[test]
+fn checksum_verification_on_gets() -> Result<()> {
+    for (verification, expected) in [
+        (ChecksumVerification::Always, [false, false]),
+        (ChecksumVerification::OnReplay, [true, true]),
+        (ChecksumVerification::Sample(2), [true, false]),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary directory");
+        let options = Options::new().checksum_verification(verification);
+        let mut store = KvStore::open_with(temp_dir.path(), options)?;
+        store.set_v2("key1".to_owned(), "value1".to_owned())?;
+
+        // flip a bit of the value behind the open store's back.
+        let log_path = temp_dir.path().join("1.log");
+        let mut content = std::fs::read(&log_path)?;
+        let at = content.windows(6).position(|window| window == b"value1").unwrap();
+        content[at + 5] = b'2';
+        std::fs::write(&log_path, content)?;
+
+        for unverified in expected {
+            match store.get_v2("key1".to_owned()) {
+                Ok(value) => {
+                    assert!(unverified, "{:?} skipped a check", verification);
+                    assert_eq!(value, Some("value2".to_owned()));
+                }
+                Err(KvsError::CorruptedData) => {
+                    assert!(!unverified, "{:?} made an extra check", verification)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-    // Write some data
+    Ok(())
+}
+
+// A length prefix running past the end of the log should fail open as corruption, without
+// allocating the claimed length.
+#[test]
+fn oversized_length_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
     store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = std::fs::read(&log_path)?;
+    content.extend_from_slice(&(u32::MAX - 1).to_le_bytes());
+    content.extend_from_slice(b"short");
+    std::fs::write(&log_path, content)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::CorruptedData)
+    ));
+
+    Ok(())
+}
 
-    // Close the store
+// With `skip_corrupted` open should skip records that don't decode and a torn tail, and copy
+// them to the `corrupt` directory.
+#[test]
+fn skip_corrupted_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
-    // Corrupt the log file
     let log_path = temp_dir.path().join("1.log");
     let mut content = std::fs::read(&log_path)?;
+    let first_len = 4 + u32::from_le_bytes(content[..4].try_into().unwrap()) as usize;
+    // the last byte of the first value.
+    content[first_len - 1] ^= 0xff;
+    content.extend_from_slice(&[7, 0]);
+    std::fs::write(&log_path, &content)?;
+
+    assert!(KvStore::open(temp_dir.path(), None, None).is_err());
+    let mut store = KvStore::open_with(temp_dir.path(), Options::new().skip_corrupted(true))?;
+    assert_eq!(store.get_v2("key1".to_owned())?, None);
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
 
-    // Modify some bytes in the middle (shouldn't corrupt the length prefix)
-    if content.len() > 20 {
-        content[15] = content[15].wrapping_add(1);
-        std::fs::write(&log_path, content)?;
+    let corrupted = &store.recovery_report().corrupted;
+    assert!(!store.recovery_report().is_clean());
+    assert_eq!(corrupted.len(), 2);
+    assert_eq!((corrupted[0].generation, corrupted[0].offset), (1, 0));
+    assert_eq!(std::fs::read(&corrupted[0].path)?, &content[..first_len]);
+    assert!(corrupted[0].path.with_extension("json").exists());
+    assert_eq!(corrupted[1].offset, content.len() as u64 - 2);
+    assert_eq!(std::fs::read(&corrupted[1].path)?, [7, 0]);
+
+    Ok(())
+}
+
+// Overwrites and removes spread across generations must resolve to the newest operation
+// when the generations are replayed in parallel.
+#[test]
+fn replay_across_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "value1".to_owned())?;
+    store.set_v2("key3".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value2".to_owned())?;
+    store.remove_v2("key2".to_owned())?;
+    store.remove_v2("key3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, None);
+    assert_eq!(store.get_v2("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `open` should report replayed bytes through its progress handle.
+#[test]
+fn open_reports_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..100 {
+        store.set_v2(format!("key{}", i), format!("value{}", i))?;
     }
+    drop(store);
+
+    let progress = Progress::new();
+    KvStore::open_with(temp_dir.path(), Options::new().progress(progress.clone()))?;
+    assert!(progress.total_bytes() > 0);
+    assert_eq!(progress.bytes_replayed(), progress.total_bytes());
+    assert_eq!(progress.current_generation(), Some(1));
+
+    Ok(())
+}
+
+// A cancelled open should fail with `KvsError::Cancelled` and leave no new log behind.
+#[test]
+fn open_cancelled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let progress = Progress::new();
+    progress.cancel();
+    let result = KvStore::open_with(temp_dir.path(), Options::new().progress(progress));
+    assert!(matches!(result, Err(KvsError::Cancelled)));
+    assert!(!temp_dir.path().join("2.log").exists());
 
-    // Try to open and read - should detect corruption
     let mut store = KvStore::open(temp_dir.path(), None, None)?;
-    match store.get_v2("key1".to_owned()) {
-        Err(e) => {
-            assert!(matches!(e, KvsError::CorruptedData));
-            println!("Error: {:?}", e)
-        },
-        Ok(_) => panic!("Should have detected corruption"),
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Storage backend that delegates to the local filesystem while counting created logs.
+#[derive(Debug, Default)]
+struct CountingStorage {
+    writers_opened: AtomicUsize,
+}
+
+impl Storage for CountingStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        LocalStorage.create_dir_all(path)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        LocalStorage.list_files(dir)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        LocalStorage.file_len(path)
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        LocalStorage.open_reader(path)
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        self.writers_opened.fetch_add(1, Ordering::SeqCst);
+        LocalStorage.open_writer(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        LocalStorage.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        LocalStorage.remove_file(path)
+    }
+}
+
+// All file access should go through the configured storage backend.
+#[test]
+fn custom_storage_backend() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let storage = Arc::new(CountingStorage::default());
+
+    let mut store = KvStore::open_with(temp_dir.path(), Options::new().storage(storage.clone()))?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+
+    // one log, the format version and the new metadata on open, then the metadata before
+    // and after compaction, the compaction log and the new active log.
+    assert_eq!(storage.writers_opened.load(Ordering::SeqCst), 7);
+
+    Ok(())
+}
+
+// The in-memory backend should behave like the filesystem across writes, compaction and reopen.
+#[test]
+fn memory_storage() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let open = || KvStore::open_with("/kvs", Options::new().storage(Arc::new(storage.clone())));
+
+    let mut store = open()?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    store.set_v2("key1".to_owned(), "value3".to_owned())?;
+    store.remove_v2("key2".to_owned())?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, None);
+    assert!(matches!(
+        store.remove_v2("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+
+    let mut store = open()?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    drop(store);
+
+    let mut store = open()?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, None);
+    assert!(!Path::new("/kvs").exists());
+
+    Ok(())
+}
+
+// Compacted logs should be archived to the object store and fetched back transparently.
+#[test]
+fn tiered_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let data_dir = temp_dir.path().join("data");
+    let objects = Arc::new(DirObjectStore::new(temp_dir.path().join("bucket"))?);
+    let storage = TieredStorage::new(Arc::new(LocalStorage), objects, &data_dir);
+    let open = || KvStore::open_with(&data_dir, Options::new().storage(Arc::new(storage.clone())));
+
+    let mut store = open()?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    // generation 1 was replaced by the compaction log 2, which is archived.
+    let archived = data_dir.join("2.log");
+    assert!(storage.is_archived(&archived)?);
+    assert!(!archived.exists());
+    assert!(temp_dir.path().join("bucket").join("2.log").exists());
+
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(archived.exists());
+    drop(store);
+
+    storage.evict(&archived)?;
+    assert!(!archived.exists());
+    let mut store = open()?;
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Archived files fetched by a reopen should only stay in the local directory up to the cache
+// budget.
+#[test]
+fn tiered_storage_cache_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let data_dir = temp_dir.path().join("data");
+    let objects = Arc::new(DirObjectStore::new(temp_dir.path().join("bucket"))?);
+    let budget = 16 * 1024;
+    let storage =
+        TieredStorage::new(Arc::new(LocalStorage), objects, &data_dir).cache_budget(budget);
+    let open = || KvStore::open_with(&data_dir, Options::new().storage(Arc::new(storage.clone())));
+
+    // every bulk ingest seals a log of about 12 KiB, which is archived.
+    let mut store = open()?;
+    for batch in 0..5 {
+        store.bulk_ingest((0..100).map(|i| {
+            (format!("key{}-{:03}", batch, i), format!("value{}", i).repeat(16))
+        }))?;
+    }
+    drop(store);
+
+    let mut store = open()?;
+    for batch in 0..5 {
+        for i in 0..100 {
+            let value = store.get_v2(format!("key{}-{:03}", batch, i))?;
+            assert_eq!(value, Some(format!("value{}", i).repeat(16)));
+        }
+    }
+    let local_bytes: u64 = WalkDir::new(&data_dir)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(local_bytes <= budget, "{} bytes of logs kept locally", local_bytes);
+
+    Ok(())
+}
+
+/// Object store counting downloads and recording deletes, downloads take a while so reads
+/// of the same file overlap.
+#[derive(Debug)]
+struct CountingObjects {
+    inner: DirObjectStore,
+    gets: AtomicUsize,
+    deleted: Mutex<Vec<String>>,
+}
+
+impl ObjectStore for CountingObjects {
+    fn put(&self, key: &str, data: &mut dyn Read) -> io::Result<()> {
+        self.inner.put(key, data)
+    }
+
+    fn get(&self, key: &str, dest: &mut dyn Write) -> io::Result<u64> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        self.inner.get(key, dest)
     }
 
+    fn len(&self, key: &str) -> io::Result<u64> {
+        self.inner.len(key)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.deleted.lock().unwrap().push(key.to_owned());
+        self.inner.delete(key)
+    }
+}
+
+// Concurrent reads of an archived file should fetch it once, and only archived files should
+// be deleted from the object store.
+#[test]
+fn tiered_storage_fetch_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let data_dir = temp_dir.path().join("data");
+    let objects = Arc::new(CountingObjects {
+        inner: DirObjectStore::new(temp_dir.path().join("bucket"))?,
+        gets: AtomicUsize::new(0),
+        deleted: Mutex::new(Vec::new()),
+    });
+    let storage = TieredStorage::new(Arc::new(LocalStorage), objects.clone(), &data_dir);
+    let open = || KvStore::open_with(&data_dir, Options::new().storage(Arc::new(storage.clone())));
+
+    let mut store = open()?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    // the replaced active log 1 was never archived.
+    assert!(objects.deleted.lock().unwrap().is_empty());
+
+    let archived = data_dir.join("2.log");
+    let expected = std::fs::read(temp_dir.path().join("bucket").join("2.log"))?;
+    thread::scope(|scope| {
+        for _ in 0..2 {
+            scope.spawn(|| {
+                let mut content = Vec::new();
+                storage.open_reader(&archived).unwrap().read_to_end(&mut content).unwrap();
+                assert_eq!(content, expected);
+            });
+        }
+    });
+    assert_eq!(objects.gets.load(Ordering::SeqCst), 1);
+
+    let mut store = open()?;
+    store.set_v2("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(*objects.deleted.lock().unwrap(), ["2.log"]);
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A temporary store should work like any other and disappear when dropped.
+#[test]
+fn temporary_store() -> Result<()> {
+    let mut store = KvStore::open_temporary()?;
+    let path = store.path().to_owned();
+    assert!(path.exists());
+
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+
+    let other = KvStore::open_temporary()?;
+    assert_ne!(other.path(), path);
+
+    drop(store);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+// `--data-dir` and `KVS_DATA_DIR` should point the CLI at a store outside the working directory.
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("store");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--data-dir", data_dir.to_str().unwrap(), "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(data_dir.join("1.log").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1", "--data-dir", data_dir.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .env("KVS_DATA_DIR", &data_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// `--config` should point the CLI at the store of the file, with flags taking precedence.
+#[test]
+fn cli_config() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = temp_dir.path().join("kvs.toml");
+    std::fs::write(
+        &config,
+        "data_dir = \"store\"\nwriter_buffer_size = 4096\n\n[compaction]\nthreshold = 100\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--config", config.to_str().unwrap(), "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(temp_dir.path().join("store").join("1.log").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .env("KVS_CONFIG", &config)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    let other_dir = temp_dir.path().join("other");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--config", config.to_str().unwrap(), "--data-dir", other_dir.to_str().unwrap()])
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    std::fs::write(&config, "data_dri = \"store\"\n").unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--config", config.to_str().unwrap(), "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid config file"));
+}
+
+// `--data-dir` pointing at a file should fail.
+#[test]
+fn cli_data_dir_not_a_directory() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--data-dir", file.to_str().unwrap(), "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is not a directory"));
+}
+
+// `kvs repl` should run several commands against one open store.
+#[test]
+fn cli_repl() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["repl"])
+        .env("HOME", temp_dir.path())
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 value with spaces\nget key1\nrm key1\nget key1\nrm key1\nbogus\nexit\nget key1\n")
+        .assert()
+        .success()
+        .stdout(contains("value with spaces"))
+        .stdout(contains("Key not found").count(2))
+        .stdout(contains("invalid command"));
+}
+
+// `scan` should iterate a key range in order and `scan_prefix` should stop at the prefix end.
+#[test]
+fn scan_ranges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for key in &["a", "b1", "b2", "b3", "c"] {
+        store.set_v2(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove_v2("b2".to_owned())?;
+
+    let entries = store.scan("b".to_owned().."c".to_owned()).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            ("b1".to_owned(), "value-b1".to_owned()),
+            ("b3".to_owned(), "value-b3".to_owned()),
+        ]
+    );
+
+    let keys: Vec<String> = store.scan_prefix("b").map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["b1".to_owned(), "b3".to_owned()]);
+    assert_eq!(store.keys(..).count(), 4);
+
+    Ok(())
+}
+
+// Reverse scans should walk a range or prefix from its last key, and `first`/`last` should
+// return the ends of the store.
+#[test]
+fn scan_reverse() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.first()?, None);
+    assert_eq!(store.last()?, None);
+    for key in &["a", "b1", "b2", "b3", "c"] {
+        store.set_v2(key.to_string(), format!("value-{}", key))?;
+    }
+
+    let keys: Vec<String> =
+        store.scan_rev("a".to_owned().."b3".to_owned()).map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["b2".to_owned(), "b1".to_owned(), "a".to_owned()]);
+
+    let latest = store.scan_prefix("b").next_back().transpose()?;
+    assert_eq!(latest, Some(("b3".to_owned(), "value-b3".to_owned())));
+
+    assert_eq!(store.first()?, Some(("a".to_owned(), "value-a".to_owned())));
+    assert_eq!(store.last()?, Some(("c".to_owned(), "value-c".to_owned())));
+
+    Ok(())
+}
+
+// Prefix stats should count the live keys of a tenant and the size of their records.
+#[test]
+fn prefix_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for key in &["tenant1/a", "tenant1/b", "tenant10/a", "tenant2/a"] {
+        store.set_v2(key.to_string(), "value".to_owned())?;
+    }
+    store.set_v2("tenant1/a".to_owned(), "overwritten".to_owned())?;
+
+    let stats = store.prefix_stats("tenant1/");
+    assert_eq!(stats.keys, 2);
+    let all = store.prefix_stats("");
+    assert_eq!(all.keys, 4);
+    assert_eq!(all.live_bytes, store.stats()?.live_bytes);
+    assert!(stats.live_bytes > 0 && stats.live_bytes < all.live_bytes);
+    assert_eq!(store.prefix_stats("tenant3/"), PrefixStats::default());
+
+    Ok(())
+}
+
+// Namespaces should keep their keys apart and scan them without the namespace name.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("a".to_owned(), "plain".to_owned())?;
+    store.namespace("app1").set("a".to_owned(), "one".to_owned())?;
+    store.namespace("app1").set("b".to_owned(), "two".to_owned())?;
+    store.namespace("app10").set("a".to_owned(), "ten".to_owned())?;
+
+    let mut app1 = store.namespace("app1");
+    assert_eq!(app1.name(), "app1");
+    assert_eq!(app1.get("a".to_owned())?, Some("one".to_owned()));
+    assert_eq!(
+        app1.scan().collect::<Result<Vec<_>>>()?,
+        vec![("a".to_owned(), "one".to_owned()), ("b".to_owned(), "two".to_owned())]
+    );
+    assert_eq!(app1.stats().keys, 2);
+    app1.remove("a".to_owned())?;
+    assert!(matches!(app1.remove("a".to_owned()), Err(KvsError::KeyNotFound)));
+
+    assert_eq!(store.namespace("app10").get("a".to_owned())?, Some("ten".to_owned()));
+    assert_eq!(store.namespace("app2").get("a".to_owned())?, None);
+    assert_eq!(store.get_v2("a".to_owned())?, Some("plain".to_owned()));
+
+    Ok(())
+}
+
+// A purged key should leave none of its values in any file of the store.
+#[test]
+fn purge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..10 {
+        store.set_v2("secret".to_owned(), format!("hunter{}", i))?;
+        store.set_v2(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.set_v2("secret".to_owned(), "hunter10".to_owned())?;
+
+    assert!(store.purge("secret".to_owned())?);
+    assert!(!store.purge("secret".to_owned())?);
+    assert_eq!(store.get_v2("secret".to_owned())?, None);
+    assert_eq!(store.get_v2("key9".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.expect("unable to walk the store directory");
+        if entry.file_type().is_file() {
+            let bytes = std::fs::read(entry.path())?;
+            assert!(!bytes.windows(6).any(|window| window == b"hunter"), "{:?}", entry.path());
+        }
+    }
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("secret".to_owned())?, None);
+
+    Ok(())
+}
+
+// Pages should follow each other without overlap, using the last key as the cursor.
+#[test]
+fn scan_after_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for key in &["a", "b", "c", "d", "e"] {
+        store.set_v2(key.to_string(), format!("value-{}", key))?;
+    }
+
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store.scan_after(cursor.as_deref(), 2)?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        assert!(page.len() <= 2);
+        cursor = Some(last.clone());
+        keys.extend(page.into_iter().map(|(key, _)| key));
+    }
+    assert_eq!(keys, vec!["a", "b", "c", "d", "e"]);
+    assert_eq!(store.scan_after(Some("b"), 1)?, vec![("c".to_owned(), "value-c".to_owned())]);
+    assert_eq!(store.scan_after(Some("bb"), 10)?.len(), 3);
+
+    Ok(())
+}
+
+// `kvs scan` should support prefixes, pagination and printing values.
+#[test]
+fn cli_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for key in &["a", "b1", "b2", "b3", "c"] {
+        store.set_v2(key.to_string(), format!("value-{}", key))?;
+    }
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "b", "--limit", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("b1\nb2\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["list", "b", "--start-after", "b2", "--values"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("b3\tvalue-b3\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--start-after", "b3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("c\n"));
+
+    Ok(())
+}
+
+// `stats` should account for live and stale bytes.
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.disk_bytes, stats.segments), (0, 0, 1));
+
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.live_bytes, stats.disk_bytes);
+    assert_eq!(stats.garbage_ratio, 0.0);
+    assert_eq!(stats.highest_sequence, 2);
+
+    store.set_v2("key1".to_owned(), "value3".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert!(stats.live_bytes < stats.disk_bytes);
+    assert!(stats.garbage_ratio > 0.0);
+
+    Ok(())
+}
+
+// `kvs stats` should print human-readable and JSON stats.
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys:             1"))
+        .stdout(contains("highest sequence: 1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["stats", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"keys\":1"))
+        .stdout(contains("\"highest_sequence\":1"));
+
+    Ok(())
+}
+
+// `kvs doctor` should pass on a healthy store and fail while another process holds the lock.
+#[test]
+fn cli_doctor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["doctor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("error   lock"));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["doctor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("ok      directory  writable"))
+        .stdout(contains("ok      lock       not locked"))
+        .stdout(contains("ok      sequences"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["doctor", "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"level":"ok","check":"open","message":"logs replayed"}"#));
+
+    Ok(())
+}
+
+/// Returns every file under `dir` but the lock file with its contents.
+fn dir_contents(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file() && entry.file_name() != "LOCK")
+        .map(|entry| (entry.path().to_path_buf(), std::fs::read(entry.path()).unwrap()))
+        .collect()
+}
+
+// `kvs doctor` should report what opening the store would quarantine without changing it.
+#[test]
+fn cli_doctor_leaves_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::write(temp_dir.path().join("7.log.tmp"), b"unfinished")?;
+    let before = dir_contents(temp_dir.path());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["doctor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("disk"))
+        .stdout(contains("7.log.tmp left by an interrupted operation"));
+    assert_eq!(dir_contents(temp_dir.path()), before);
+
+    Ok(())
+}
+
+// `kvs compact` should reclaim stale bytes and print the new layout.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..10 {
+        store.set_v2("key1".to_owned(), format!("value{}", i))?;
+    }
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("reclaimed"))
+        .stdout(contains("generation 3:"))
+        .stdout(contains("generation 4: 0 bytes"));
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value9".to_owned()));
+    let segments = store.segment_stats()?;
+    assert_eq!(segments[0].generation, 3);
+    // only the footer follows the live records.
+    let footer_len = segments[0].disk_bytes - segments[0].live_bytes;
+    assert!(footer_len > 0 && footer_len < 512);
+
+    Ok(())
+}
+
+// Segment stats should count the records and live keys of each log, and the bytes not
+// holding live values.
+#[test]
+fn segment_stats() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = Options::new().storage(Arc::new(storage.clone()));
+    let mut store = KvStore::open_with("/db", options)?;
+    for i in 0..3 {
+        store.set_v2("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set_v2("key2".to_owned(), "value".to_owned())?;
+    store.remove_v2("key2".to_owned())?;
+
+    let segments = store.segment_stats()?;
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].records, 5);
+    assert_eq!(segments[0].live_keys, 1);
+    assert_eq!(segments[0].garbage_bytes, segments[0].disk_bytes - segments[0].live_bytes);
+
+    // a torn record at the end isn't counted.
+    let mut log = storage.open_writer(Path::new("/db/1.log"))?;
+    log.write_all(&100u32.to_le_bytes())?;
+    log.write_all(b"torn")?;
+    assert_eq!(store.segment_stats()?[0].records, 5);
+
+    store.compact()?;
+    let segments = store.segment_stats()?;
+    // the compaction log and the new active log.
+    assert_eq!(segments.len(), 2);
+    assert_eq!((segments[0].records, segments[0].live_keys), (1, 1));
+    assert_eq!((segments[1].records, segments[1].disk_bytes), (0, 0));
+
+    Ok(())
+}
+
+// Open should compact a store whose garbage ratio is above `compact_on_open`, and leave one
+// below it alone.
+#[test]
+fn compact_on_open() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = Options::new()
+        .storage(Arc::new(storage.clone()))
+        .compaction_policy(Arc::new(NeverCompact));
+    let mut store = KvStore::open_with("/db", options.clone())?;
+    for i in 0..10 {
+        store.set_v2("key".to_owned(), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with("/db", options.clone().compact_on_open(0.95))?;
+    let records: u64 = store.segment_stats()?.iter().map(|segment| segment.records).sum();
+    assert_eq!(records, 10);
+    drop(store);
+
+    let mut store = KvStore::open_with("/db", options.compact_on_open(0.5))?;
+    let records: u64 = store.segment_stats()?.iter().map(|segment| segment.records).sum();
+    assert_eq!(records, 1);
+    assert_eq!(store.get_v2("key".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+// Compaction through io_uring should write every batch in order, so every value survives
+// a reopen.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[test]
+fn io_uring_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let value = |key_id: usize, iter: usize| format!("{}-{}", iter, key_id).repeat(key_id % 50 + 1);
+    for iter in 0..2 {
+        for key_id in 0..5000 {
+            store.set_v2(format!("key{}", key_id), value(key_id, iter))?;
+        }
+    }
+    for key_id in (0..5000).step_by(7) {
+        store.remove_v2(format!("key{}", key_id))?;
+    }
+    store.compact()?;
+    store.set_v2("after".to_owned(), "compaction".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for key_id in 0..5000 {
+        let expected = if key_id % 7 == 0 { None } else { Some(value(key_id, 1)) };
+        assert_eq!(store.get_v2(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(store.get_v2("after".to_owned())?, Some("compaction".to_owned()));
+
+    Ok(())
+}
+
+// A batch rolled back through io_uring should be cut from the log, also the writes queued on
+// the ring already.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[test]
+fn io_uring_roll_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // records go past the buffer straight to the ring.
+    let options = Options::new().writer_buffer_size(16);
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let value = "v".repeat(100);
+    store.set_v2("x0".to_owned(), value.clone())?;
+    let before = store.stats()?.disk_bytes;
+    store.set_v2("k0".to_owned(), value.clone())?;
+    let set_len = store.stats()?.disk_bytes - before;
+    store.remove_v2("k0".to_owned())?;
+    let remove_len = store.stats()?.disk_bytes - before - set_len;
+
+    let disk_bytes = store.stats()?.disk_bytes;
+    let max_disk_bytes = disk_bytes + 2 * set_len + remove_len - 1;
+    store.set_config(Config { max_disk_bytes: Some(max_disk_bytes), ..store.config() })?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("k1".to_owned(), value.clone())
+        .remove("x0".to_owned())
+        .set("k2".to_owned(), value.clone());
+    assert!(matches!(store.apply_batch(batch), Err(KvsError::QuotaExceeded)));
+    assert_eq!(store.stats()?.disk_bytes, disk_bytes);
+    store.set_v2("k3".to_owned(), "v".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_v2("k1".to_owned())?, None);
+    assert_eq!(store.get_v2("x0".to_owned())?, Some(value));
+    assert_eq!(store.get_v2("k3".to_owned())?, Some("v".to_owned()));
+
+    Ok(())
+}
+
+// A cancelled compaction should leave the store readable and reopenable, a finished one
+// should report every live byte copied.
+#[test]
+fn compaction_handle() -> Result<()> {
+    let options = Options::new()
+        .storage(Arc::new(MemoryStorage::new()))
+        .compaction_policy(Arc::new(NeverCompact));
+    let mut store = KvStore::open_with("/db", options.clone())?;
+    for i in 0..100 {
+        store.set_v2(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+
+    let handle = CompactionHandle::new();
+    handle.cancel();
+    assert!(matches!(store.compact_with(&handle), Err(KvsError::Cancelled)));
+    assert_eq!(handle.bytes_copied(), 0);
+    assert_eq!(store.get_v2("key3".to_owned())?, Some("value93".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with("/db", options)?;
+    assert!(store.recovery_report().is_clean());
+    assert!(store.recovery_report().orphans.is_empty());
+    assert_eq!(store.scan(..).count(), 10);
+
+    let handle = CompactionHandle::new();
+    store.compact_with(&handle)?;
+    assert!(handle.total_bytes() > 0);
+    assert_eq!(handle.bytes_copied(), handle.total_bytes());
+    assert_eq!(store.get_v2("key3".to_owned())?, Some("value93".to_owned()));
+
+    Ok(())
+}
+
+// Batches should apply sets and removes in order and skip removes of missing keys.
+#[test]
+fn apply_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned())
+        .remove("missing".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key3".to_owned());
+    assert_eq!(batch.len(), 5);
+    store.apply_batch(batch)?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, None);
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_v2("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs batch` should apply commands from stdin and stop at an invalid line.
+#[test]
+fn cli_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("# seed\nset key1 value one\nset key2 value2\n\nrm key2\n")
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key3 value3\nbogus\n")
+        .assert()
+        .failure()
+        .stderr(contains("line 2: invalid command `bogus`"));
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value one".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, None);
+    assert_eq!(store.get_v2("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// `--output json` should print get, scan and stats results as JSON.
+#[test]
+fn cli_output_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--output", "json", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key1","value":"value1"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "missing", "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"missing","value":null}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--values", "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            eq(r#"[{"key":"key1","value":"value1"},{"key":"key2","value":"value2"}]"#).trim(),
+        );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--output", "json", "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"keys\":2"));
+
+    Ok(())
+}
+
+// Failures should exit with a distinct code per failure type.
+#[test]
+fn cli_exit_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("locked"));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1", "--output", "yaml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1);
+
+    Ok(())
+}
+
+// Synced writes should survive a simulated crash whatever the seed does to the others, and
+// the same seed should lose the same writes.
+#[test]
+fn simulated_storage_crash() -> Result<()> {
+    let run = |seed| -> Result<(Vec<u8>, Duration)> {
+        let storage = SimulatedStorage::new(seed)
+            .short_writes(true)
+            .max_latency(Duration::from_millis(1));
+        storage.create_dir_all(Path::new("/sim"))?;
+        let mut writer = storage.open_writer(Path::new("/sim/file"))?;
+        writer.write_all(b"synced")?;
+        writer.sync_data()?;
+        writer.write_all(b" maybe")?;
+        writer.write_all(b" lost")?;
+        storage.crash();
+
+        let mut contents = Vec::new();
+        storage.open_reader(Path::new("/sim/file"))?.read_to_end(&mut contents)?;
+        Ok((contents, storage.elapsed()))
+    };
+
+    for seed in 0..20 {
+        let (contents, elapsed) = run(seed)?;
+        assert!(contents.starts_with(b"synced"), "seed {}", seed);
+        assert!(contents.len() <= 17, "seed {}", seed);
+        assert!(elapsed > Duration::ZERO);
+        assert_eq!(run(seed)?, (contents, elapsed), "seed {}", seed);
+    }
+    Ok(())
+}
+
+// A store on simulated storage should keep synced writes through a crash.
+#[test]
+fn simulated_storage_store() -> Result<()> {
+    for seed in 0..5 {
+        let storage = SimulatedStorage::new(seed).short_writes(true);
+        let open = || KvStore::open_with("/sim", Options::new().storage(Arc::new(storage.clone())));
+
+        let mut store = open()?;
+        for i in 0..100 {
+            store.set_v2(format!("key{}", i % 30), format!("value{}", i))?;
+        }
+        store.remove_v2("key3".to_owned())?;
+        store.sync()?;
+        drop(store);
+        storage.crash();
+
+        let mut store = open()?;
+        assert_eq!(store.scan(..).count(), 29, "seed {}", seed);
+        assert_eq!(store.get_v2("key0".to_owned())?, Some("value90".to_owned()));
+        assert_eq!(store.get_v2("key3".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// `sync_up_to` should sync only when a write up to the sequence may not be synced, and
+// those writes should survive a crash.
+#[test]
+fn sync_up_to_sequence() -> Result<()> {
+    for seed in 0..5 {
+        let storage = SimulatedStorage::new(seed);
+        // the unsynced writes may be torn by the crash.
+        let options = Options::new().storage(Arc::new(storage.clone())).skip_corrupted(true);
+
+        let mut store = KvStore::open_with("/sim", options.clone())?;
+        for i in 0..10 {
+            store.set_v2(format!("key{}", i), format!("value{}", i))?;
+        }
+        assert_eq!(store.synced_sequence(), 0);
+        store.sync_up_to(5)?;
+        assert_eq!(store.synced_sequence(), 10);
+
+        for i in 10..20 {
+            store.set_v2(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.sync_up_to(10)?;
+        assert_eq!(store.synced_sequence(), 10);
+        assert_eq!(store.last_sequence(), 20);
+        drop(store);
+        storage.crash();
+
+        let mut store = KvStore::open_with("/sim", options)?;
+        for i in 0..10 {
+            let value = store.get_v2(format!("key{}", i))?;
+            assert_eq!(value, Some(format!("value{}", i)), "seed {}", seed);
+        }
+    }
+    Ok(())
+}
+
+/// Listener keeping the last durable sequence number.
+#[derive(Debug, Default)]
+struct DurableListener(AtomicU64);
+
+impl EventListener for DurableListener {
+    fn on_durable(&self, sequence: u64) {
+        self.0.store(sequence, Ordering::SeqCst);
+    }
+}
+
+// The background syncer should make flushed writes durable without a sync call, wake
+// waiters and notify listeners.
+#[test]
+fn background_sync() -> Result<()> {
+    let storage = SimulatedStorage::new(7);
+    let listener = Arc::new(DurableListener::default());
+    let options = Options::new()
+        .storage(Arc::new(storage.clone()))
+        .background_sync(Duration::from_millis(5))
+        .listener(listener.clone());
+
+    let mut store = KvStore::open_with("/sim", options.clone())?;
+    let watcher = store.durability_watcher().unwrap();
+    for i in 0..10 {
+        store.set_v2(format!("key{}", i), format!("value{}", i))?;
+    }
+    let waiter = thread::spawn(move || watcher.wait_for(10));
+    waiter.join().unwrap()?;
+    assert_eq!(store.synced_sequence(), 10);
+
+    // nothing is written after the crash, the store can be dropped after it.
+    storage.crash();
+    drop(store);
+    assert_eq!(listener.0.load(Ordering::SeqCst), 10);
+    let mut store = KvStore::open_with("/sim", options)?;
+    for i in 0..10 {
+        assert_eq!(store.get_v2(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A second store on a locked directory should fail until the first is dropped.
+#[test]
+fn store_lock() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = Options::new().storage(Arc::new(storage.clone()));
+    let store = KvStore::open_with("/db", options.clone())?;
+    assert!(matches!(
+        KvStore::open_with("/db", options.clone()),
+        Err(KvsError::StoreLocked)
+    ));
+    drop(store);
+    KvStore::open_with("/db", options)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::StoreLocked)
+    ));
+    drop(store);
+    KvStore::open(temp_dir.path(), None, None)?;
+
+    Ok(())
+}
+
+/// Listener recording every event as a string.
+#[derive(Debug, Default)]
+struct RecordingListener {
+    events: Mutex<Vec<String>>,
+}
+
+impl EventListener for RecordingListener {
+    fn on_set(&self, key: &str, sequence: u64) {
+        self.events.lock().unwrap().push(format!("set {} {}", key, sequence));
+    }
+
+    fn on_remove(&self, key: &str, sequence: u64) {
+        self.events.lock().unwrap().push(format!("rm {} {}", key, sequence));
+    }
+
+    fn on_compaction_start(&self) {
+        self.events.lock().unwrap().push("compaction start".to_owned());
+    }
+
+    fn on_compaction_end(&self) {
+        self.events.lock().unwrap().push("compaction end".to_owned());
+    }
+
+    fn on_corruption_detected(&self, generation: u64, pos: u64) {
+        self.events.lock().unwrap().push(format!("corruption {} {}", generation, pos));
+    }
+
+    fn on_slow_operation(&self, operation: Operation, key: Option<&str>, _duration: Duration) {
+        self.events.lock().unwrap().push(format!("slow {:?} {:?}", operation, key));
+    }
+}
+
+// Registered listeners should see writes, compactions and corrupted records.
+#[test]
+fn event_listener() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = Arc::new(RecordingListener::default());
+    let options = Options::new().listener(listener.clone());
+
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned()).remove("key1".to_owned());
+    store.apply_batch(batch)?;
+    store.compact()?;
+    drop(store);
+    assert_eq!(
+        *listener.events.lock().unwrap(),
+        ["set key1 1", "set key2 2", "rm key1 3", "compaction start", "compaction end"]
+    );
+    listener.events.lock().unwrap().clear();
+
+    // corrupt the first record of the compacted log.
+    let log_path = temp_dir.path().join("2.log");
+    let mut content = std::fs::read(&log_path)?;
+    content[15] = content[15].wrapping_add(1);
+    std::fs::write(&log_path, content)?;
+
+    assert!(KvStore::open_with(temp_dir.path(), options).is_err());
+    assert_eq!(*listener.events.lock().unwrap(), ["corruption 2 0"]);
+
+    Ok(())
+}
+
+// Sets past `max_disk_bytes` should compact first and fail once only live data is left.
+#[test]
+fn disk_quota() -> Result<()> {
+    let options = Options::new()
+        .storage(Arc::new(MemoryStorage::new()))
+        .max_disk_bytes(1024);
+    let mut store = KvStore::open_with("/db", options)?;
+
+    // overwrites stay under the cap by compacting the stale values away.
+    for i in 0..100 {
+        store.set_v2("key".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats()?.disk_bytes <= 1024);
+
+    let mut i = 0;
+    let err = loop {
+        match store.set_v2(format!("key{}", i), "value".to_owned()) {
+            Ok(()) => i += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(err, KvsError::QuotaExceeded));
+    assert!(store.stats()?.disk_bytes <= 1024);
+    assert_eq!(store.get_v2(format!("key{}", i))?, None);
+
+    // removes are still accepted and free space for new sets.
+    store.remove_v2("key0".to_owned())?;
+    store.remove_v2("key1".to_owned())?;
+    store.set_v2(format!("key{}", i), "value".to_owned())?;
+
+    Ok(())
+}
+
+// A batch running into `max_disk_bytes` partway should leave none of its writes behind.
+#[test]
+fn disk_quota_batch() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || Options::new().storage(Arc::new(storage.clone()));
+    let mut store = KvStore::open_with("/db", options())?;
+    store.set_v2("x0".to_owned(), "value".to_owned())?;
+
+    // the sizes of a set and a remove record, measured on the store itself.
+    let before = store.stats()?.disk_bytes;
+    store.set_v2("k0".to_owned(), "v".to_owned())?;
+    let set_len = store.stats()?.disk_bytes - before;
+    store.remove_v2("k0".to_owned())?;
+    let remove_len = store.stats()?.disk_bytes - before - set_len;
+
+    // both sets fit, but not along with the remove between them.
+    let disk_bytes = store.stats()?.disk_bytes;
+    let max_disk_bytes = disk_bytes + 2 * set_len + remove_len - 1;
+    store.set_config(Config { max_disk_bytes: Some(max_disk_bytes), ..store.config() })?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("k1".to_owned(), "v".to_owned())
+        .remove("x0".to_owned())
+        .set("k2".to_owned(), "v".to_owned());
+    assert!(matches!(store.apply_batch(batch), Err(KvsError::QuotaExceeded)));
+    assert_eq!(store.get_v2("k1".to_owned())?, None);
+    assert_eq!(store.get_v2("x0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.disk_bytes, disk_bytes);
+
+    // the log was cut back, later writes land cleanly.
+    store.set_v2("k3".to_owned(), "v".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with("/db", options())?;
+    assert_eq!(store.get_v2("k1".to_owned())?, None);
+    assert_eq!(store.get_v2("k2".to_owned())?, None);
+    assert_eq!(store.get_v2("x0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get_v2("k3".to_owned())?, Some("v".to_owned()));
+
+    Ok(())
+}
+
+// In cache mode the least recently used keys should be evicted once over budget.
+#[test]
+fn cache_eviction() -> Result<()> {
+    let value = "v".repeat(100);
+    let mut probe = KvStore::open_with("/probe", Options::new().storage(Arc::new(MemoryStorage::new())))?;
+    probe.set_v2("a".to_owned(), value.clone())?;
+    // room for three records, with slack for varint-encoded fields.
+    let budget = 3 * probe.stats()?.live_bytes + 20;
+
+    let storage = MemoryStorage::new();
+    let options = Options::new()
+        .storage(Arc::new(storage.clone()))
+        .cache_budget(budget);
+    let mut store = KvStore::open_with("/db", options.clone())?;
+    store.set_v2("a".to_owned(), value.clone())?;
+    store.set_v2("b".to_owned(), value.clone())?;
+    store.set_v2("c".to_owned(), value.clone())?;
+    assert_eq!(store.get_v2("a".to_owned())?, Some(value.clone()));
+    store.set_v2("d".to_owned(), value.clone())?;
+
+    assert_eq!(store.get_v2("b".to_owned())?, None);
+    assert_eq!(store.keys(..).collect::<Vec<_>>(), ["a", "c", "d"]);
+    assert!(store.stats()?.live_bytes <= budget);
+    drop(store);
+
+    // evictions are persisted as removes.
+    let mut store = KvStore::open_with("/db", options)?;
+    assert_eq!(store.get_v2("b".to_owned())?, None);
+    assert_eq!(store.keys(..).count(), 3);
+
+    Ok(())
+}
+
+/// `MemoryStorage` whose writers fail with `StorageFull` once `capacity` bytes are written.
+#[derive(Debug, Default)]
+struct FullStorage {
+    inner: MemoryStorage,
+    capacity: Arc<AtomicUsize>,
+}
+
+struct FullWriter {
+    inner: Box<dyn StorageWriter>,
+    capacity: Arc<AtomicUsize>,
+}
+
+impl io::Write for FullWriter {
+    // writes as much as fits, like a disk filling up mid-record.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.capacity.load(Ordering::SeqCst));
+        if len == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::StorageFull.into());
+        }
+        self.capacity.fetch_sub(len, Ordering::SeqCst);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl io::Seek for FullWriter {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageWriter for FullWriter {
+    fn sync_data(&self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+impl Storage for FullStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list_files(dir)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        self.inner.file_len(path)
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        self.inner.open_reader(path)
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        Ok(Box::new(FullWriter {
+            inner: self.inner.open_writer(path)?,
+            capacity: self.capacity.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+}
+
+// A write hitting a full disk should be rolled back without a torn record or an event.
+#[test]
+fn disk_full() -> Result<()> {
+    let storage = Arc::new(FullStorage::default());
+    storage.capacity.store(usize::MAX, Ordering::SeqCst);
+    let listener = Arc::new(RecordingListener::default());
+    let options = Options::new().storage(storage.clone()).listener(listener.clone());
+
+    let mut store = KvStore::open_with("/db", options.clone())?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    storage.capacity.store(10, Ordering::SeqCst);
+
+    assert!(matches!(
+        store.set_v2("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::DiskFull)
+    ));
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned()).remove("key1".to_owned());
+    assert!(matches!(store.apply_batch(batch), Err(KvsError::DiskFull)));
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, None);
+    assert_eq!(store.stats()?.highest_sequence, 1);
+    assert_eq!(*listener.events.lock().unwrap(), ["set key1 1"]);
+
+    storage.capacity.store(usize::MAX, Ordering::SeqCst);
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert_eq!(*listener.events.lock().unwrap(), ["set key1 1", "set key2 2"]);
+
+    let mut store = KvStore::open_with("/db", options)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats()?.highest_sequence, 2);
+
+    Ok(())
+}
+
+// A compaction rate limit should slow compaction down, and can be lifted at runtime.
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let options = Options::new()
+        .storage(Arc::new(MemoryStorage::new()))
+        .compaction_rate_limit(1000);
+    let mut store = KvStore::open_with("/db", options)?;
+    for i in 0..10 {
+        store.set_v2(format!("key{}", i), "v".repeat(50))?;
+    }
+    let live_bytes = store.stats()?.live_bytes;
+    assert!(live_bytes >= 500);
+
+    let start = std::time::Instant::now();
+    store.compact()?;
+    assert!(start.elapsed() >= std::time::Duration::from_millis(live_bytes - 100));
+
+    store.set_compaction_rate_limit(None);
+    let start = std::time::Instant::now();
+    store.compact()?;
+    assert!(start.elapsed() < std::time::Duration::from_millis(400));
+    assert_eq!(store.get_v2("key9".to_owned())?, Some("v".repeat(50)));
+
+    Ok(())
+}
+
+// `flush` and `sync` should make writes durable, dropping with `sync_on_drop` as well.
+#[test]
+fn flush_and_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().sync_on_drop(true);
+
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    store.sync()?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Replay should report records lost from the middle of a log and sequences reused by
+// another store's log, and strict recovery should refuse to open.
+#[test]
+fn sequence_anomalies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for key in ["b", "a", "d", "c"] {
+        store.set_v2(key.to_owned(), "value".to_owned())?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert!(store.recovery_report().is_clean());
+    drop(store);
+
+    // drop the second record of the first log.
+    let log_path = temp_dir.path().join("1.log");
+    let content = std::fs::read(&log_path)?;
+    let record_len = |pos: usize| 4 + u32::from_le_bytes(content[pos..pos + 4].try_into().unwrap()) as usize;
+    let second = record_len(0);
+    let third = second + record_len(second);
+    std::fs::write(&log_path, [&content[..second], &content[third..]].concat())?;
+
+    // a log of another store reuses sequence 1 for a different key.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path(), None, None)?;
+    other.set_v2("z".to_owned(), "value".to_owned())?;
+    drop(other);
+    std::fs::copy(other_dir.path().join("1.log"), temp_dir.path().join("10.log"))?;
+
+    let expected = RecoveryReport {
+        gaps: vec![SequenceGap { generation: 1, missing: 2..=2 }],
+        duplicates: vec![1],
+        ..RecoveryReport::default()
+    };
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(*store.recovery_report(), expected);
+    drop(store);
+
+    match KvStore::open_with(temp_dir.path(), Options::new().strict_recovery(true)) {
+        Err(KvsError::InconsistentLog(report)) => assert_eq!(report, expected),
+        _ => panic!("strict recovery should fail"),
+    }
+
+    Ok(())
+}
+
+// Replay should report the lost tail of a log followed by the next one, but not the
+// sequences a bulk ingest or a compaction in between accounts for.
+#[test]
+fn sequence_gap_between_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("a".to_owned(), "value".to_owned())?;
+    store.bulk_ingest(vec![("b".to_owned(), "value".to_owned()), ("c".to_owned(), "value".to_owned())])?;
+    store.set_v2("d".to_owned(), "value".to_owned())?;
+    store.set_v2("e".to_owned(), "value".to_owned())?;
+    drop(store);
+    // 1.log, the bulk log 2.log and 3.log, reopening appends to 4.log.
+    let mut store = KvStore::open_with(temp_dir.path(), Options::new().strict_recovery(true))?;
+    store.set_v2("f".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), Options::new().strict_recovery(true))?;
+    drop(store);
+
+    // lose the last record of 3.log, sequence 5.
+    let log_path = temp_dir.path().join("3.log");
+    let content = std::fs::read(&log_path)?;
+    let first_len = 4 + u32::from_le_bytes(content[..4].try_into().unwrap()) as usize;
+    std::fs::write(&log_path, &content[..first_len])?;
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(
+        store.recovery_report().gaps,
+        vec![SequenceGap { generation: 4, missing: 5..=5 }]
+    );
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.compact()?;
+    store.set_v2("g".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), Options::new().strict_recovery(true))?;
+    assert!(store.recovery_report().is_clean());
+
+    Ok(())
+}
+
+// Compaction output is in key order with gaps, which is not an anomaly.
+#[test]
+fn sequence_gaps_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("b".to_owned(), "value".to_owned())?;
+    store.set_v2("a".to_owned(), "value".to_owned())?;
+    store.set_v2("b".to_owned(), "value2".to_owned())?;
+    store.remove_v2("a".to_owned())?;
+    store.set_v2("c".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().strict_recovery(true))?;
+    assert!(store.recovery_report().is_clean());
+
+    Ok(())
+}
+
+// The store metadata should keep sequence numbers from going backwards after compaction
+// dropped the records that held the highest ones.
+#[test]
+fn store_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.remove_v2("key1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let meta: serde_json::Value = serde_json::from_slice(&std::fs::read(temp_dir.path().join("META"))?)?;
+    assert_eq!(meta["sequence"], 2);
+    assert_eq!(meta["engine"], "kvs");
+    assert_eq!(meta["id"].as_str().map(str::len), Some(36));
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.stats()?.highest_sequence, 2);
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.highest_sequence, 3);
+
+    Ok(())
+}
+
+// The store id should persist across reopens and guard against opening the wrong store.
+#[test]
+fn store_id() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    let id = store.id().to_owned();
+    assert_eq!(store.stats()?.id, id);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().expected_id(id.clone()))?;
+    assert_eq!(store.id(), id);
+    drop(store);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path(), None, None)?;
+    assert_ne!(other.id(), id);
+    drop(other);
+    assert!(matches!(
+        KvStore::open_with(other_dir.path(), Options::new().expected_id(id)),
+        Err(KvsError::StoreMismatch(_))
+    ));
+
+    Ok(())
+}
+
+// Leftovers of a crash during compaction should be quarantined, or deleted, on open.
+#[test]
+fn orphan_cleanup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // a compaction into generation 5 died halfway, next to a torn metadata save.
+    let meta_path = temp_dir.path().join("META");
+    let mut meta: serde_json::Value = serde_json::from_slice(&std::fs::read(&meta_path)?)?;
+    meta["compacting"] = 5.into();
+    std::fs::write(&meta_path, serde_json::to_vec(&meta)?)?;
+    std::fs::write(temp_dir.path().join("5.log"), [7, 0, 0, 0, 1])?;
+    std::fs::write(temp_dir.path().join("META.tmp"), "{")?;
+    std::fs::write(temp_dir.path().join("notes.txt"), "not ours")?;
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(
+        store.recovery_report().orphans,
+        [temp_dir.path().join("5.log"), temp_dir.path().join("META.tmp")]
+    );
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(temp_dir.path().join("quarantine").join("5.log").exists());
+    assert!(temp_dir.path().join("notes.txt").exists());
+    drop(store);
+
+    std::fs::write(temp_dir.path().join("3.log.fetch"), "partial")?;
+    let store = KvStore::open_with(temp_dir.path(), Options::new().delete_orphans(true))?;
+    assert_eq!(store.recovery_report().orphans, [temp_dir.path().join("3.log.fetch")]);
+    assert!(!temp_dir.path().join("3.log.fetch").exists());
+    assert!(!temp_dir.path().join("quarantine").join("3.log.fetch").exists());
+
+    Ok(())
+}
+
+// A rename should move the value in one record, and survive compaction when the old key
+// is set again afterwards.
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+
+    store.set_v2("a".to_owned(), "value1".to_owned())?;
+    store.set_v2("b".to_owned(), "old".to_owned())?;
+    store.rename("a".to_owned(), "b".to_owned())?;
+    assert_eq!(store.get_v2("a".to_owned())?, None);
+    assert_eq!(store.get_v2("b".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.rename("a".to_owned(), "c".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("a".to_owned())?, None);
+    assert_eq!(store.get_v2("b".to_owned())?, Some("value1".to_owned()));
+
+    // compaction writes the rename after the newer set of "a", in key order.
+    store.set_v2("a".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("a".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_v2("b".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A conditional remove should only delete a key holding the expected value.
+#[test]
+fn remove_if() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+
+    store.set_v2("lock".to_owned(), "owner1".to_owned())?;
+    assert!(!store.remove_if("lock".to_owned(), "owner2")?);
+    assert_eq!(store.get_v2("lock".to_owned())?, Some("owner1".to_owned()));
+    assert!(store.remove_if("lock".to_owned(), "owner1")?);
+    assert_eq!(store.get_v2("lock".to_owned())?, None);
+    assert!(!store.remove_if("lock".to_owned(), "owner1")?);
+
+    Ok(())
+}
+
+// The default should only be computed and written for a missing key.
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+
+    assert_eq!(store.get_or_insert_with("key1".to_owned(), || "value1".to_owned())?, "value1");
+    assert_eq!(
+        store.get_or_insert_with("key1".to_owned(), || panic!("default computed for existing key"))?,
+        "value1"
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Entries should insert missing keys, update existing ones in place and remove them.
+#[test]
+fn entry_api() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+
+    let bump = |count: &mut String| *count = (count.parse::<u32>().unwrap() + 1).to_string();
+    assert_eq!(store.entry("count".to_owned())?.and_modify(bump)?.or_insert("1".to_owned())?, "1");
+    assert_eq!(store.entry("count".to_owned())?.and_modify(bump)?.or_insert("1".to_owned())?, "2");
+
+    match store.entry("count".to_owned())? {
+        Entry::Occupied(mut entry) => {
+            assert_eq!(entry.get(), "2");
+            assert_eq!(entry.insert("5".to_owned())?, "2");
+        }
+        Entry::Vacant(_) => panic!("count should exist"),
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("count".to_owned())?, Some("5".to_owned()));
+    assert_eq!(store.entry("count".to_owned())?.remove()?, Some("5".to_owned()));
+    assert_eq!(store.entry("count".to_owned())?.remove()?, None);
+    assert_eq!(store.get_v2("count".to_owned())?, None);
+
+    Ok(())
+}
+
+// Sorts decimal keys by value by zero-padding them, other keys come after.
+#[derive(Debug)]
+struct NumericKeys;
+
+impl KeyEncoding for NumericKeys {
+    fn name(&self) -> &str {
+        "numeric"
+    }
+
+    fn encode(&self, key: &str) -> String {
+        match key.parse::<u64>() {
+            Ok(n) if n.to_string() == key => format!("n{:020}", n),
+            _ => format!("s{}", key),
+        }
+    }
+
+    fn decode(&self, encoded: &str) -> String {
+        match encoded.split_at(1) {
+            ("n", n) => n.parse::<u64>().unwrap().to_string(),
+            (_, key) => key.to_owned(),
+        }
+    }
+}
+
+// A key encoding should set the scan order, see decoded keys everywhere and be enforced on
+// reopen.
+#[test]
+fn key_encoding() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().key_encoding(Arc::new(NumericKeys));
+
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key in &["100", "9", "abc", "10"] {
+        store.set_v2(key.to_string(), format!("value-{}", key))?;
+    }
+    store.rename("abc".to_owned(), "11".to_owned())?;
+    let keys: Vec<String> = store.keys(..).collect();
+    assert_eq!(keys, vec!["9", "10", "11", "100"]);
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::KeyEncodingMismatch(Some(name))) if name == "numeric"
+    ));
+
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    let entries = store.scan("10".to_owned().."100".to_owned()).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            ("10".to_owned(), "value-10".to_owned()),
+            ("11".to_owned(), "value-abc".to_owned()),
+        ]
+    );
+    assert_eq!(store.last()?, Some(("100".to_owned(), "value-100".to_owned())));
+    assert_eq!(store.get_v2("9".to_owned())?, Some("value-9".to_owned()));
+    store.remove_v2("9".to_owned())?;
+    assert_eq!(store.first()?, Some(("10".to_owned(), "value-10".to_owned())));
+
+    Ok(())
+}
+
+// Files users by the city after the `|` in their value.
+#[derive(Debug)]
+struct CityIndex;
+
+impl SecondaryIndex for CityIndex {
+    fn name(&self) -> &str {
+        "city"
+    }
+
+    fn index_key(&self, _key: &str, value: &str) -> Option<String> {
+        value.split_once('|').map(|(_, city)| city.to_owned())
+    }
+}
+
+// A secondary index should follow sets, overwrites, removes and renames, and be rebuilt on
+// reopen.
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().secondary_index(Arc::new(CityIndex));
+    let city_keys = |store: &mut KvStore, city: &str| -> Result<Vec<String>> {
+        Ok(store.get_by_index("city", city)?.into_iter().map(|(key, _)| key).collect())
+    };
+
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_v2("alice".to_owned(), "Alice|Paris".to_owned())?;
+    store.set_v2("bob".to_owned(), "Bob|Oslo".to_owned())?;
+    store.set_v2("carol".to_owned(), "Carol|Paris".to_owned())?;
+    store.set_v2("dave".to_owned(), "Dave".to_owned())?;
+    assert_eq!(city_keys(&mut store, "Paris")?, vec!["alice", "carol"]);
+    assert_eq!(
+        store.get_by_index("city", "Oslo")?,
+        vec![("bob".to_owned(), "Bob|Oslo".to_owned())]
+    );
+
+    store.set_v2("alice".to_owned(), "Alice|Oslo".to_owned())?;
+    store.remove_v2("carol".to_owned())?;
+    store.rename("bob".to_owned(), "robert".to_owned())?;
+    assert_eq!(city_keys(&mut store, "Paris")?, Vec::<String>::new());
+    assert_eq!(city_keys(&mut store, "Oslo")?, vec!["alice", "robert"]);
+    assert!(matches!(store.get_by_index("age", "42"), Err(KvsError::UnknownIndex(_))));
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(city_keys(&mut store, "Oslo")?, vec!["alice", "robert"]);
+
+    Ok(())
+}
+
+// Changes should come back in sequence order, and collapse to the live records after
+// compaction.
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let change = |sequence, key: &str, value: Option<&str>| Change {
+        sequence,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    };
+
+    store.set_v2("a".to_owned(), "1".to_owned())?;
+    store.set_v2("b".to_owned(), "2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.remove_v2("a".to_owned())?;
+    store.rename("b".to_owned(), "c".to_owned())?;
+
+    let changes = store.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        changes,
+        vec![
+            change(1, "a", Some("1")),
+            change(2, "b", Some("2")),
+            change(3, "a", None),
+            change(4, "b", None),
+            change(4, "c", Some("2")),
+        ]
+    );
+    assert_eq!(store.changes_since(3)?.count(), 2);
+    assert_eq!(store.changes_since(4)?.count(), 0);
+
+    store.compact()?;
+    let changes = store.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(changes, vec![change(4, "b", None), change(4, "c", Some("2"))]);
+
+    Ok(())
+}
+
+// A follower applying the changes of a leader should end up with the same data and sequence
+// numbers, and skip changes it applied before.
+#[test]
+fn apply_if_newer() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut leader = KvStore::open(leader_dir.path(), None, None)?;
+    let mut follower = KvStore::open(follower_dir.path(), None, None)?;
+
+    leader.set_v2("a".to_owned(), "1".to_owned())?;
+    leader.set_v2("b".to_owned(), "2".to_owned())?;
+    let changes = leader.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(follower.apply_if_newer(changes.clone())?, 2);
+    // a retry applies nothing twice.
+    assert_eq!(follower.apply_if_newer(changes)?, 0);
+
+    leader.remove_v2("a".to_owned())?;
+    leader.rename("b".to_owned(), "c".to_owned())?;
+    leader.set_v2("d".to_owned(), "4".to_owned())?;
+    let changes = leader.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(follower.apply_if_newer(changes)?, 3);
+    assert_eq!(follower.last_sequence(), 5);
+    drop(follower);
+
+    let mut follower = KvStore::open(follower_dir.path(), None, None)?;
+    let expected = leader.scan(..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(follower.scan(..).collect::<Result<Vec<_>>>()?, expected);
+    let expected = leader.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(follower.changes_since(0)?.collect::<Result<Vec<_>>>()?, expected);
+    assert!(follower.recovery_report().is_clean());
+
+    Ok(())
+}
+
+// A diff should tell added, modified and removed keys apart between two sequence points.
+#[test]
+fn diff() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+
+    store.set_v2("kept".to_owned(), "1".to_owned())?;
+    store.set_v2("modified".to_owned(), "1".to_owned())?;
+    store.set_v2("removed".to_owned(), "1".to_owned())?;
+    store.set_v2("renamed".to_owned(), "1".to_owned())?;
+    let from = store.stats()?.highest_sequence;
+    store.set_v2("modified".to_owned(), "2".to_owned())?;
+    store.remove_v2("removed".to_owned())?;
+    store.rename("renamed".to_owned(), "added".to_owned())?;
+    store.set_v2("transient".to_owned(), "1".to_owned())?;
+    store.remove_v2("transient".to_owned())?;
+    let to = store.stats()?.highest_sequence;
+    store.set_v2("later".to_owned(), "1".to_owned())?;
+
+    let entries = store.diff(from, to)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            DiffEntry::Added { key: "added".to_owned(), value: "1".to_owned() },
+            DiffEntry::Modified { key: "modified".to_owned(), value: "2".to_owned() },
+            DiffEntry::Removed { key: "removed".to_owned() },
+            DiffEntry::Removed { key: "renamed".to_owned() },
+        ]
+    );
+    assert_eq!(store.diff(to, to)?.count(), 0);
+
+    Ok(())
+}
+
+// A bulk ingest should land in one new log that survives a reopen, and unsorted input
+// should leave the store untouched.
+#[test]
+fn bulk_ingest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key0005".to_owned(), "old".to_owned())?;
+
+    let pairs = (0..1000).map(|i| (format!("key{:04}", i), format!("value{}", i)));
+    assert_eq!(store.bulk_ingest(pairs)?, 1000);
+    assert_eq!(store.get_v2("key0005".to_owned())?, Some("value5".to_owned()));
+    store.set_v2("key0006".to_owned(), "new".to_owned())?;
+    let sequence = store.stats()?.highest_sequence;
+    assert_eq!(sequence, 1002);
+
+    let unsorted = vec![("b".to_owned(), "1".to_owned()), ("a".to_owned(), "1".to_owned())];
+    assert!(matches!(store.bulk_ingest(unsorted), Err(KvsError::UnsortedInput(key)) if key == "a"));
+    assert_eq!(store.get_v2("b".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(store.stats()?.keys, 1000);
+    assert_eq!(store.stats()?.highest_sequence, sequence);
+    assert_eq!(store.get_v2("key0999".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(store.get_v2("key0006".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// A reopen should index a bulk ingest log from its hint file without reading the records,
+// replay the log once the hint is gone, and compaction should drop the hint with the log.
+#[test]
+fn bulk_ingest_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.bulk_ingest((0..100).map(|i| (format!("key{:03}", i), format!("value{:03}", i))))?;
+    drop(store);
+    let hint_path = temp_dir.path().join("2.hint");
+    let hint = std::fs::read(&hint_path)?;
+
+    // damage a value in place, replay rejects the record but the hint doesn't read it.
+    let log_path = temp_dir.path().join("2.log");
+    let mut content = std::fs::read(&log_path)?;
+    let at = content.windows(8).position(|window| window == b"value050").unwrap();
+    content[at] = b'V';
+    std::fs::write(&log_path, content)?;
+    std::fs::remove_file(&hint_path)?;
+    assert!(matches!(KvStore::open(temp_dir.path(), None, None), Err(KvsError::CorruptedData)));
+    std::fs::write(&hint_path, hint)?;
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(store.stats()?.keys, 100);
+    assert_eq!(store.get_v2("key049".to_owned())?, Some("value049".to_owned()));
+    assert!(matches!(store.get_v2("key050".to_owned()), Err(KvsError::CorruptedData)));
+
+    store.remove_v2("key050".to_owned())?;
+    store.compact()?;
+    assert!(!log_path.exists());
+    assert!(!hint_path.exists());
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key099".to_owned())?, Some("value099".to_owned()));
+    assert_eq!(store.get_v2("key050".to_owned())?, None);
+
+    Ok(())
+}
+
+// A cancelled scan should end after yielding the cancellation, a bulk ingest past its
+// deadline should apply none of its pairs.
+#[test]
+fn cancellation_token() -> Result<()> {
+    let options = Options::new().storage(Arc::new(MemoryStorage::new()));
+    let mut store = KvStore::open_with("/db", options.clone())?;
+    for i in 0..10 {
+        store.set_v2(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let token = CancellationToken::new();
+    let mut scan = store.scan(..).cancel_on(&token);
+    assert_eq!(scan.next().transpose()?, Some(("key0".to_owned(), "value0".to_owned())));
+    token.clone().cancel();
+    assert!(matches!(scan.next(), Some(Err(KvsError::Cancelled))));
+    assert!(scan.next().is_none());
+    assert_eq!(store.scan(..).cancel_on(&CancellationToken::new()).count(), 10);
+
+    let expired = CancellationToken::with_deadline(Instant::now());
+    assert!(expired.is_cancelled());
+    let pairs = (0..100).map(|i| (format!("new{:03}", i), "x".to_owned()));
+    assert!(matches!(store.bulk_ingest_with(pairs, &expired), Err(KvsError::Cancelled)));
+    assert_eq!(store.get_v2("new000".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::open_with("/db", options)?;
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(store.scan(..).count(), 10);
+    let pairs = (0..100).map(|i| (format!("new{:03}", i), "x".to_owned()));
+    let token = CancellationToken::with_timeout(Duration::from_secs(60));
+    assert_eq!(store.bulk_ingest_with(pairs, &token)?, 100);
+
+    Ok(())
+}
+
+// An export should hold a record of every live key followed by a footer, a cancelled one
+// should stop before the footer.
+#[test]
+fn export_stream() -> Result<()> {
+    let options = Options::new().storage(Arc::new(MemoryStorage::new()));
+    let mut store = KvStore::open_with("/db", options)?;
+    for i in 0..100 {
+        store.set_v2(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.remove_v2("key3".to_owned())?;
+
+    let mut stream = Vec::new();
+    assert_eq!(store.export(&mut stream)?, 9);
+    assert!(stream.ends_with(b"KVSFOOT1"));
+    let holds = |value: &[u8]| stream.windows(value.len()).any(|window| window == value);
+    assert!(holds(b"value95"));
+    assert!(!holds(b"value93") && !holds(b"value85"));
+
+    let token = CancellationToken::new();
+    token.cancel();
+    let mut cancelled = Vec::new();
+    assert!(matches!(store.export_with(&mut cancelled, &token), Err(KvsError::Cancelled)));
+    assert!(!cancelled.ends_with(b"KVSFOOT1"));
+
+    Ok(())
+}
+
+// A restore should bring back every pair of an export, and apply none of a stream that
+// fails verification.
+#[test]
+fn restore_from_stream() -> Result<()> {
+    let options = Options::new().storage(Arc::new(MemoryStorage::new()));
+    let mut store = KvStore::open_with("/db", options.clone())?;
+    for i in 0..100 {
+        store.set_v2(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    let mut stream = Vec::new();
+    store.export(&mut stream)?;
+    let expected = store.scan(..).collect::<Result<Vec<_>>>()?;
+
+    let mut corrupted = stream.clone();
+    let at = corrupted.windows(7).position(|window| window == b"value42").unwrap();
+    corrupted[at + 6] = b'X';
+    let restored = KvStore::restore_from_stream("/restored", options.clone(), &corrupted[..]);
+    assert!(matches!(restored, Err(KvsError::CorruptedData)));
+    let truncated = &stream[..stream.len() / 2];
+    let restored = KvStore::restore_from_stream("/restored", options.clone(), truncated);
+    let eof = |e: &io::Error| e.kind() == io::ErrorKind::UnexpectedEof;
+    assert!(matches!(restored, Err(KvsError::IoError(e)) if eof(&e)));
+
+    let mut restored = KvStore::restore_from_stream("/restored", options.clone(), &stream[..])?;
+    assert_eq!(restored.scan(..).collect::<Result<Vec<_>>>()?, expected);
+    drop(restored);
+    let mut restored = KvStore::open_with("/restored", options.clone())?;
+    assert_eq!(restored.scan(..).count(), 100);
+    drop(restored);
+
+    let again = KvStore::restore_from_stream("/restored", options, &stream[..]);
+    assert!(matches!(again, Err(KvsError::StoreNotEmpty)));
+
+    Ok(())
+}
+
+// A length prefix far beyond the end of an export stream should fail the restore without
+// allocating the length it claims, and so should a footer that doesn't end.
+#[test]
+fn restore_oversized_length_prefix() -> Result<()> {
+    let options = Options::new().storage(Arc::new(MemoryStorage::new()));
+    let mut stream = (u32::MAX - 1).to_le_bytes().to_vec();
+    stream.extend_from_slice(b"short");
+    let restored = KvStore::restore_from_stream("/oversized", options.clone(), &stream[..]);
+    let eof = |e: &io::Error| e.kind() == io::ErrorKind::UnexpectedEof;
+    assert!(matches!(restored, Err(KvsError::IoError(e)) if eof(&e)));
+
+    let mut stream = u32::MAX.to_le_bytes().to_vec();
+    stream.extend_from_slice(&vec![b'x'; 1024 * 1024]);
+    let restored = KvStore::restore_from_stream("/endless", options, &stream[..]);
+    assert!(matches!(restored, Err(KvsError::CorruptedData)));
+
+    Ok(())
+}
+
+// Partial exports should hold only the pairs under the prefix or of the keys given, and
+// restore on their own.
+#[test]
+fn partial_export() -> Result<()> {
+    let options = Options::new().storage(Arc::new(MemoryStorage::new()));
+    let mut store = KvStore::open_with("/db", options.clone())?;
+    for key in &["tenant1/a", "tenant1/b", "tenant10/a", "tenant2/a"] {
+        store.set_v2(key.to_string(), format!("value-{}", key))?;
+    }
+
+    let mut stream = Vec::new();
+    assert_eq!(store.export_prefix(&mut stream, "tenant1/")?, 2);
+    let restored = KvStore::restore_from_stream("/tenant1", options.clone(), &stream[..])?;
+    assert_eq!(restored.keys(..).collect::<Vec<_>>(), vec!["tenant1/a", "tenant1/b"]);
+    drop(restored);
+
+    let keys = vec!["tenant2/a".to_owned(), "missing".to_owned(), "tenant1/b".to_owned()];
+    let mut stream = Vec::new();
+    assert_eq!(store.export_keys(&mut stream, keys)?, 2);
+    let mut restored = KvStore::restore_from_stream("/keys", options, &stream[..])?;
+    assert_eq!(restored.keys(..).collect::<Vec<_>>(), vec!["tenant1/b", "tenant2/a"]);
+    assert_eq!(restored.get_v2("tenant2/a".to_owned())?, Some("value-tenant2/a".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot archive should import into a store holding the same pairs, and a damaged one
+// should be refused before anything is applied.
+#[test]
+fn snapshot_archive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path().join("db"), None, None)?;
+    for i in 0..100 {
+        store.set_v2(format!("key{:02}", i), format!("value{}", i).repeat(100))?;
+    }
+    let archive = temp_dir.path().join("db.snapshot");
+    assert_eq!(store.export_snapshot(&archive)?, 100);
+    // the records are compressed.
+    assert!(std::fs::metadata(&archive)?.len() < store.stats()?.live_bytes / 10);
+    assert!(!temp_dir.path().join("db.snapshot.tmp").exists());
+    assert!(!temp_dir.path().join("db.tmp").exists());
+    // a failed export leaves nothing behind.
+    let occupied = temp_dir.path().join("occupied");
+    std::fs::create_dir(&occupied)?;
+    assert!(store.export_snapshot(&occupied).is_err());
+    assert!(!temp_dir.path().join("occupied.tmp").exists());
+    let expected = store.scan(..).collect::<Result<Vec<_>>>()?;
+
+    let options = Options::new().storage(Arc::new(MemoryStorage::new()));
+    let mut imported = KvStore::import_snapshot("/imported", options.clone(), &archive)?;
+    assert_eq!(imported.scan(..).collect::<Result<Vec<_>>>()?, expected);
+    drop(imported);
+
+    let mut bytes = std::fs::read(&archive)?;
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 1;
+    let damaged = temp_dir.path().join("damaged.snapshot");
+    std::fs::write(&damaged, &bytes)?;
+    let imported = KvStore::import_snapshot("/damaged", options.clone(), &damaged);
+    assert!(matches!(imported, Err(KvsError::CorruptedData)));
+    std::fs::write(&damaged, b"not a snapshot")?;
+    let imported = KvStore::import_snapshot("/damaged", options, &damaged);
+    assert!(matches!(imported, Err(KvsError::CorruptedData)));
+
+    Ok(())
+}
+
+// Compaction should seal its log with a footer that replay skips.
+#[test]
+fn segment_footer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..100 {
+        store.set_v2(format!("key{}", i), "x".repeat(100))?;
+    }
+    store.compact()?;
+    let compacted = store.segment_stats()?[0].generation;
+    drop(store);
+
+    let bytes = std::fs::read(temp_dir.path().join(format!("{}.log", compacted)))?;
+    assert!(bytes.ends_with(b"KVSFOOT1"));
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(store.stats()?.keys, 100);
+    assert_eq!(store.get_v2("key42".to_owned())?, Some("x".repeat(100)));
+
+    Ok(())
+}
+
+// Stores in a newer or unknown format should fail to open with a typed error, older ones
+// should be upgraded.
+#[test]
+fn format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let format_file = temp_dir.path().join("FORMAT");
+    assert_eq!(std::fs::read_to_string(&format_file)?, "3\n");
+
+    std::fs::write(&format_file, "99\n")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::IncompatibleFormat(_))
+    ));
+    std::fs::write(&format_file, "garbage")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::IncompatibleFormat(_))
+    ));
+
+    std::fs::write(&format_file, "1\n")?;
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(std::fs::read_to_string(&format_file)?, "3\n");
+
+    let meta_file = temp_dir.path().join("META");
+    let meta = std::fs::read_to_string(&meta_file)?.replace("\"protobuf\"", "\"json\"");
+    std::fs::write(&meta_file, meta)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::IncompatibleFormat(_))
+    ));
+
+    Ok(())
+}
+
+// Logs should be spread over every data directory, which later opens keep using.
+#[test]
+fn data_dirs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let extra_dir = temp_dir.path().join("disk2");
+    let logs_in = |dir: &Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    let mut store = KvStore::open_with(&store_dir, Options::new().data_dir(&extra_dir))?;
+    for i in 0..3 {
+        store.set_v2(format!("key{}", i), format!("value{}", i))?;
+        store.compact()?;
+    }
+    drop(store);
+    assert!(logs_in(&store_dir) > 0);
+    assert!(logs_in(&extra_dir) > 0);
+
+    let mut store = KvStore::open(&store_dir, None, None)?;
+    for i in 0..3 {
+        assert_eq!(store.get_v2(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open(&store_dir, None, None)?;
+    assert_eq!(store.stats()?.keys, 3);
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Logs should go to shard subdirectories under descriptive names once asked to, next to the
+// plain logs written before.
+#[test]
+fn segment_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_names = |dir: &Path| -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        names.sort();
+        names
+    };
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(log_names(temp_dir.path()), vec!["1.log"]);
+
+    let options = Options::new().segment_shards(2).descriptive_segment_names(true);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert_eq!(log_names(temp_dir.path()), vec!["1.log"]);
+    let shard0 = temp_dir.path().join("segments").join("00");
+    let shard1 = temp_dir.path().join("segments").join("01");
+    assert_eq!(log_names(&shard0).len(), 1);
+    assert!(log_names(&shard0)[0].starts_with("2-"));
+
+    // the layout is remembered without the options.
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+    store.compact()?;
+    drop(store);
+    assert!(log_names(temp_dir.path()).is_empty());
+    let sealed: Vec<String> = log_names(&shard0)
+        .into_iter()
+        .chain(log_names(&shard1))
+        .filter(|name| name.ends_with(".sealed.log"))
+        .collect();
+    assert_eq!(sealed.len(), 1);
+    assert!(sealed[0].starts_with("4-"));
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get_v2("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_v2("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Writes should compact when the compaction policy says so, which can change at runtime.
+#[test]
+fn compaction_policy() -> Result<()> {
+    let options = Options::new()
+        .storage(Arc::new(MemoryStorage::new()))
+        .compaction_policy(Arc::new(NeverCompact));
+    let mut store = KvStore::open_with("/db", options)?;
+    // every compaction moves the store to new generations.
+    let generations = |store: &KvStore| -> Result<Vec<u64>> {
+        Ok(store.segment_stats()?.into_iter().map(|segment| segment.generation).collect())
+    };
+    for i in 0..100 {
+        store.set_v2("key".to_owned(), "v".repeat(i))?;
+    }
+    assert_eq!(generations(&store)?, vec![1]);
+
+    store.set_compaction_policy(Arc::new(SizeThreshold(100)));
+    store.set_v2("key".to_owned(), "value".to_owned())?;
+    assert_eq!(generations(&store)?, vec![2, 3]);
+
+    store.set_compaction_policy(Arc::new(GarbageRatio { ratio: 0.5, min_stale_bytes: 0 }));
+    store.set_v2("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(generations(&store)?, vec![2, 3]);
+    for _ in 0..20 {
+        store.set_v2("key".to_owned(), "value3".to_owned())?;
+    }
+    assert_ne!(generations(&store)?, vec![2, 3]);
+    assert_eq!(store.get_v2("key".to_owned())?, Some("value3".to_owned()));
+
+    // a window around the current hour defers to its policy, one away from it never compacts.
+    let hour = (std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 3600
+        % 24) as u32;
+    let always = Arc::new(SizeThreshold(0));
+    let outside = TimeWindow::new((hour + 2) % 24, (hour + 23) % 24, always.clone());
+    store.set_compaction_policy(Arc::new(outside));
+    let before = generations(&store)?;
+    store.set_v2("key".to_owned(), "value4".to_owned())?;
+    store.set_v2("key".to_owned(), "value5".to_owned())?;
+    assert_eq!(generations(&store)?, before);
+    let around = TimeWindow::new((hour + 23) % 24, (hour + 2) % 24, always);
+    store.set_compaction_policy(Arc::new(around));
+    store.set_v2("key".to_owned(), "value6".to_owned())?;
+    assert_ne!(generations(&store)?, before);
+    assert_eq!(store.get_v2("key".to_owned())?, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+// Small sealed logs should merge into one, keeping removes that shadow older logs.
+#[test]
+fn merge_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every open starts a new log.
+    let write = |f: &dyn Fn(&mut KvStore) -> Result<()>| -> Result<()> {
+        let mut store = KvStore::open(temp_dir.path(), None, None)?;
+        f(&mut store)
+    };
+    write(&|store| {
+        store.set_v2("d".to_owned(), "x".repeat(2000))?;
+        store.set_v2("x".to_owned(), "1".to_owned())
+    })?;
+    write(&|store| {
+        store.set_v2("a".to_owned(), "1".to_owned())?;
+        store.set_v2("b".to_owned(), "1".to_owned())
+    })?;
+    write(&|store| {
+        store.set_v2("a".to_owned(), "2".to_owned())?;
+        store.remove_v2("b".to_owned())?;
+        store.remove_v2("x".to_owned())
+    })?;
+    write(&|store| store.rename("a".to_owned(), "c".to_owned()))?;
+    write(&|store| store.set_v2("e".to_owned(), "1".to_owned()))?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for (key, value) in [("a", None), ("b", None), ("c", Some("2")), ("e", Some("1")), ("x", None)] {
+            assert_eq!(store.get_v2(key.to_owned())?, value.map(str::to_owned));
+        }
+        assert_eq!(store.get_v2("d".to_owned())?, Some("x".repeat(2000)));
+        Ok(())
+    };
+    let generations = |store: &KvStore| -> Result<Vec<u64>> {
+        Ok(store.segment_stats()?.into_iter().map(|segment| segment.generation).collect())
+    };
+
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(generations(&store)?, vec![1, 2, 3, 4, 5, 6]);
+    // the first log is too big, the empty active one is replaced.
+    assert_eq!(store.merge_segments(1000)?, 4);
+    assert_eq!(generations(&store)?, vec![1, 7, 8]);
+    check(&mut store)?;
+    assert_eq!(store.merge_segments(1000)?, 0);
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), Options::new().strict_recovery(true))?;
+    assert!(store.recovery_report().is_clean());
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// Compaction should keep removes within the tombstone retention for readers of the logs.
+#[test]
+fn tombstone_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let removed = |store: &mut KvStore| -> Result<Vec<String>> {
+        Ok(store
+            .changes_since(0)?
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|change| change.value.is_none())
+            .map(|change| change.key)
+            .collect())
+    };
+
+    let options = Options::new().tombstone_retention(std::time::Duration::from_secs(1));
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key in ["a", "b", "c"] {
+        store.set_v2(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove_v2("b".to_owned())?;
+    store.rename("c".to_owned(), "d".to_owned())?;
+    store.compact()?;
+    assert_eq!(removed(&mut store)?, vec!["b", "c"]);
+    drop(store);
+
+    let options = Options::new()
+        .tombstone_retention(std::time::Duration::from_secs(1))
+        .strict_recovery(true);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(removed(&mut store)?, vec!["b", "c"]);
+    assert_eq!(store.get_v2("b".to_owned())?, None);
+    assert_eq!(store.get_v2("d".to_owned())?, Some("value".to_owned()));
+
+    // past the retention they go, but for the rename that still holds the value of d.
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    store.compact()?;
+    assert_eq!(removed(&mut store)?, vec!["c"]);
+    assert_eq!(store.get_v2("a".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Settings changed through `set_config` should apply to the open store right away.
+#[test]
+fn set_config() -> Result<()> {
+    let mut store = KvStore::open_with("/db", Options::new().storage(Arc::new(MemoryStorage::new())))?;
+    let config = store.config();
+    assert_eq!(config.cache_budget, None);
+    assert_eq!(config.compaction_rate_limit, None);
+    assert_eq!(config.max_disk_bytes, None);
+    assert!(!config.sync_on_drop);
+
+    for i in 0..10 {
+        store.set_v2(format!("key{}", i), "v".repeat(50))?;
+    }
+    // turning cache mode on evicts the oldest keys.
+    store.set_config(Config { cache_budget: Some(300), ..store.config() })?;
+    assert!(store.stats()?.live_bytes <= 300);
+    assert_eq!(store.get_v2("key0".to_owned())?, None);
+    assert_eq!(store.get_v2("key9".to_owned())?, Some("v".repeat(50)));
+    assert_eq!(store.config().cache_budget, Some(300));
+
+    store.set_config(Config { cache_budget: None, ..store.config() })?;
+    for i in 0..10 {
+        store.set_v2(format!("key{}", i), "v".repeat(50))?;
+    }
+    assert_eq!(store.stats()?.keys, 10);
+
+    let disk_bytes = store.stats()?.disk_bytes;
+    store.set_config(Config {
+        max_disk_bytes: Some(disk_bytes + 10),
+        compaction_policy: Arc::new(NeverCompact),
+        compaction_rate_limit: Some(1_000_000),
+        sync_on_drop: true,
+        ..store.config()
+    })?;
+    assert!(matches!(
+        store.set_v2("big".to_owned(), "v".repeat(1000)),
+        Err(KvsError::QuotaExceeded)
+    ));
+    let config = store.config();
+    assert_eq!(config.compaction_rate_limit, Some(1_000_000));
+    assert!(config.sync_on_drop);
+
+    Ok(())
+}
+
+// Gets, sets, removes and compactions should land in their histograms, and ones over the
+// threshold should be reported with their key.
+#[test]
+fn latency_stats() -> Result<()> {
+    let listener = Arc::new(RecordingListener::default());
+    let options = Options::new()
+        .storage(Arc::new(MemoryStorage::new()))
+        .listener(listener.clone())
+        .slow_op_threshold(Duration::ZERO);
+    let mut store = KvStore::open_with("/db", options)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "value2".to_owned())?;
+    store.get_v2("key1".to_owned())?;
+    store.get_v2("missing".to_owned())?;
+    store.remove_v2("key2".to_owned())?;
+    store.compact()?;
+
+    let latencies = store.stats()?.latencies;
+    assert_eq!(latencies.get.count(), 2);
+    assert_eq!(latencies.set.count(), 2);
+    assert_eq!(latencies.remove.count(), 1);
+    assert_eq!(latencies.compaction.count(), 1);
+    assert!(latencies.set.quantile(0.5) <= latencies.set.max());
+    assert!(latencies.set.max() * 2 >= latencies.set.quantile(1.0));
+
+    let slow: Vec<String> = listener
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|event| event.starts_with("slow"))
+        .cloned()
+        .collect();
+    assert_eq!(
+        slow,
+        [
+            "slow Set Some(\"key1\")",
+            "slow Set Some(\"key2\")",
+            "slow Get Some(\"key1\")",
+            "slow Get Some(\"missing\")",
+            "slow Remove Some(\"key2\")",
+            "slow Compaction None",
+        ]
+    );
+
+    // nothing is reported without a threshold.
+    let listener = Arc::new(RecordingListener::default());
+    let options = Options::new().storage(Arc::new(MemoryStorage::new())).listener(listener.clone());
+    let mut store = KvStore::open_with("/db", options)?;
+    store.get_v2("key".to_owned())?;
+    assert_eq!(store.stats()?.latencies.get.count(), 1);
+    assert!(listener.events.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+// Storage backend keeping logs in memory while counting seeks of the readers.
+#[derive(Debug, Default)]
+struct SeekCountingStorage {
+    inner: MemoryStorage,
+    seeks: Arc<AtomicUsize>,
+}
+
+struct SeekCountingReader {
+    inner: Box<dyn StorageReader>,
+    seeks: Arc<AtomicUsize>,
+}
+
+impl io::Read for SeekCountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl io::Seek for SeekCountingReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.seeks.fetch_add(1, Ordering::SeqCst);
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageReader for SeekCountingReader {}
+
+impl Storage for SeekCountingStorage {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list_files(dir)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        self.inner.file_len(path)
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn StorageReader>> {
+        let inner = self.inner.open_reader(path)?;
+        Ok(Box::new(SeekCountingReader { inner, seeks: self.seeks.clone() }))
+    }
+
+    fn open_writer(&self, path: &Path) -> io::Result<Box<dyn StorageWriter>> {
+        self.inner.open_writer(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+}
+
+// Scans over consecutive records should read them in chunks rather than seeking to each,
+// and still return the latest values of keys overwritten since.
+#[test]
+fn scan_read_ahead() -> Result<()> {
+    let storage = Arc::new(SeekCountingStorage::default());
+    let mut store = KvStore::open_with("/db", Options::new().storage(storage.clone()))?;
+    let mut expected = BTreeMap::new();
+    for i in 0..5000 {
+        let (key, value) = (format!("key{:05}", i), format!("value{}", i).repeat(10));
+        store.set_v2(key.clone(), value.clone())?;
+        expected.insert(key, value);
+    }
+    store.compact()?;
+    for i in (0..5000).step_by(100) {
+        let key = format!("key{:05}", i);
+        store.set_v2(key.clone(), "new".to_owned())?;
+        expected.insert(key, "new".to_owned());
+    }
+
+    storage.seeks.store(0, Ordering::SeqCst);
+    let scanned: BTreeMap<String, String> = store.scan(..).collect::<Result<_>>()?;
+    assert_eq!(scanned, expected);
+    // one seek per overwritten key and the records after it, plus one per chunk.
+    assert!(storage.seeks.load(Ordering::SeqCst) < 200);
+
+    let reversed: Vec<(String, String)> = store.scan_rev(..).collect::<Result<_>>()?;
+    assert!(reversed.into_iter().eq(expected.into_iter().rev()));
+
+    Ok(())
+}
+
+// `get_bytes` should return the same values as `get_v2`, including renamed ones.
+#[test]
+fn get_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_v2("key1".to_owned(), "value1".to_owned())?;
+    store.set_v2("key2".to_owned(), "välue2".to_owned())?;
+    store.rename("key2".to_owned(), "key3".to_owned())?;
+    store.remove_v2("key1".to_owned())?;
+
+    assert_eq!(store.get_bytes("key1".to_owned())?, None);
+    assert_eq!(store.get_bytes("key2".to_owned())?, None);
+    assert_eq!(store.get_bytes("key3".to_owned())?.as_deref(), Some("välue2".as_bytes()));
+    store.set_v2("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?.as_deref(), Some(&b"value3"[..]));
+    assert_eq!(store.stats()?.latencies.get.count(), 4);
+
+    store.compact()?;
+    assert_eq!(store.get_bytes("key3".to_owned())?.as_deref(), Some("välue2".as_bytes()));
+
     Ok(())
 }